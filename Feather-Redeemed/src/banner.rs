@@ -0,0 +1,137 @@
+use std::io::{BufWriter, Write};
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::results::csv_field;
+
+//How long to wait for the connect and for the service to say something.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+//Longest banner we keep once it has been cleaned up.
+const MAX_BANNER_LEN: usize = 256;
+const MAX_BANNER_GRABS: usize = 256;
+
+//What an open port said when we connected to it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Banner {
+    pub host: IpAddr,
+    pub port: u32,
+    #[serde(skip)]
+    pub raw: Vec<u8>,
+    pub text: String,
+    pub probed: bool, //True if the service stayed quiet and we had to send it something first.
+}
+
+//DESCRIPTION: Grabs a banner off every open port and writes them to banners.txt as
+//             ip,port,banner. Banners with a comma or quote in them are quoted like CSV.
+//TAKES: The open (ip, port) pairs, the run folder to write to and the stop flag.
+//RETURNS: Every banner that came back. Ports that stayed silent are left out.
+pub async fn grab_banners(open_ports: &[(IpAddr, u32)], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<Banner> {
+    let mut probes = Vec::with_capacity(open_ports.len());
+    for (host, port) in open_ports {
+        let host = *host;
+        let port = *port;
+        probes.push(async move {
+            grab_banner(host, port).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_BANNER_GRABS, stop).await;
+
+    let banner_rfp = out_dir.join("banners.txt");
+    let banner_rf = match File::create(&banner_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", banner_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut banner_buff = BufWriter::new(banner_rf);
+
+    let mut banners = Vec::new();
+    for answer in answers {
+        if let Some(banner) = answer.flatten() {
+            eprintln!("{}:{} -> {}", banner.host, banner.port, banner.text);
+            banner_buff.write_all(format!("{},{},{}\n", banner.host, banner.port, csv_field(&banner.text)).as_bytes()).expect("Unable to write banner to banners.txt");
+            banners.push(banner);
+        }
+    }
+    banners
+}
+
+//DESCRIPTION: Connects and waits for the service to talk first (SSH, FTP, SMTP, MySQL...). If it
+//             stays quiet we send it a small probe for its port and read whatever comes back.
+//TAKES: Host and port.
+//RETURNS: The banner, or None if we could not connect or nothing came back.
+pub async fn grab_banner(host: IpAddr, port: u32) -> Option<Banner> {
+    let socket = SocketAddr::new(host, port as u16);
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    let mut raw = read_some(&mut stream).await;
+    let probed = raw.is_empty();
+    if probed {
+        stream.write_all(banner_probe(port)).await.ok()?;
+        raw = read_some(&mut stream).await;
+    }
+    if raw.is_empty() {
+        return None;
+    }
+    let text = sanitize_banner(&raw);
+    Some(Banner { host, port, raw, text, probed })
+}
+
+//DESCRIPTION: Reads whatever the service sends before READ_TIMEOUT runs out.
+//TAKES: The connected stream.
+//RETURNS: The bytes read, empty if nothing came.
+async fn read_some(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = vec![0u8; 1024];
+    match timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(Ok(len)) => {
+            buf.truncate(len);
+            buf
+        },
+        _ => Vec::new(),
+    }
+}
+
+//DESCRIPTION: The smallest thing that gets a reply out of a service that waits for the client.
+//TAKES: The port.
+//RETURNS: Bytes to send.
+fn banner_probe(port: u32) -> &'static [u8] {
+    match port {
+        80 | 81 | 591 | 8000 | 8008 | 8080 | 8081 | 8888 => b"HEAD / HTTP/1.0\r\n\r\n",
+        6379 => b"PING\r\n",
+        11211 => b"version\r\n",
+        _ => b"\r\n\r\n",
+    }
+}
+
+//DESCRIPTION: Makes a banner safe to drop in a text file. Printable ASCII stays, line endings
+//             become \r \n and everything else becomes \xNN. Trailing line endings are dropped.
+//TAKES: The raw bytes.
+//RETURNS: One line of text no longer than MAX_BANNER_LEN.
+pub fn sanitize_banner(raw: &[u8]) -> String {
+    let trimmed = match raw.iter().rposition(|b| !matches!(b, b'\r' | b'\n' | 0)) {
+        Some(end) => &raw[..=end],
+        None => &raw[..0],
+    };
+    let mut text = String::new();
+    for byte in trimmed {
+        let escaped = match byte {
+            b'\r' => "\\r".to_string(),
+            b'\n' => "\\n".to_string(),
+            b'\\' => "\\\\".to_string(),
+            0x20..=0x7e => (*byte as char).to_string(),
+            _ => format!("\\x{:02x}", byte),
+        };
+        if text.len() + escaped.len() > MAX_BANNER_LEN {
+            break;
+        }
+        text.push_str(&escaped);
+    }
+    text
+}
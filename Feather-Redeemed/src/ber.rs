@@ -0,0 +1,125 @@
+//Just enough BER/DER to build the requests we send and walk the replies we get back. NTLM
+//wrappers, SNMP and LDAP all speak it.
+
+//DESCRIPTION: Wraps content in a tag and length. Short and two byte lengths only, plenty for
+//             anything we send.
+//TAKES: The tag byte and the content.
+//RETURNS: The encoded element.
+pub fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    match content.len() {
+        len if len < 0x80 => element.push(len as u8),
+        len if len < 0x100 => element.extend_from_slice(&[0x81, len as u8]),
+        len => element.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+//DESCRIPTION: Reads one BER tag/length/value and moves past it.
+//TAKES: The buffer and the offset to read from, which gets moved along.
+//RETURNS: The tag and the value bytes, or None if it runs off the end.
+pub fn read_tlv<'a>(buf: &'a [u8], at: &mut usize) -> Option<(u8, &'a [u8])> {
+    let tag = *buf.get(*at)?;
+    let first = *buf.get(*at + 1)? as usize;
+    let mut start = *at + 2;
+    let length = if first & 0x80 == 0 {
+        first
+    } else {
+        //Long form, the low bits say how many length bytes follow.
+        let count = first & 0x7F;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = buf.get(start..start + count)?.iter().fold(0usize, |length, byte| length << 8 | *byte as usize);
+        start += count;
+        length
+    };
+    let value = buf.get(start..start + length)?;
+    *at = start + length;
+    Some((tag, value))
+}
+
+//DESCRIPTION: Encodes an unsigned number as the shortest BER INTEGER content that stays positive.
+//TAKES: The number.
+//RETURNS: The content bytes.
+pub fn encode_integer(value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value.to_be_bytes().to_vec();
+    while bytes.len() > 1 && bytes[0] == 0 && bytes[1] & 0x80 == 0 {
+        bytes.remove(0);
+    }
+    if bytes[0] & 0x80 != 0 {
+        bytes.insert(0, 0);
+    }
+    bytes
+}
+
+//DESCRIPTION: Decodes BER INTEGER (and Counter/Gauge/TimeTicks) content.
+//TAKES: The content bytes.
+//RETURNS: The number.
+pub fn decode_integer(value: &[u8]) -> i64 {
+    let negative = value.first().is_some_and(|byte| byte & 0x80 != 0);
+    value.iter().take(8).fold(if negative {-1} else {0}, |number, byte| number << 8 | *byte as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A successful anonymous LDAP bind answered by Active Directory: message 1, bindResponse, success.
+    const BIND_RESPONSE: &[u8] = &[0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x00, 0x04, 0x00, 0x04, 0x00];
+
+    #[test]
+    fn walks_an_ldap_bind_response() {
+        let mut at = 0;
+        let (tag, message) = read_tlv(BIND_RESPONSE, &mut at).unwrap();
+        assert_eq!((tag, at), (0x30, BIND_RESPONSE.len()));
+        let mut at = 0;
+        let (tag, message_id) = read_tlv(message, &mut at).unwrap();
+        assert_eq!((tag, decode_integer(message_id)), (0x02, 1));
+        let (tag, bind_response) = read_tlv(message, &mut at).unwrap();
+        assert_eq!(tag, 0x61);
+        assert_eq!(at, message.len());
+        let mut at = 0;
+        let (tag, result_code) = read_tlv(bind_response, &mut at).unwrap();
+        assert_eq!((tag, decode_integer(result_code)), (0x0a, 0));
+        assert_eq!(read_tlv(bind_response, &mut at), Some((0x04, &[][..])));
+    }
+
+    #[test]
+    fn long_form_lengths() {
+        let content = vec![0x41; 300];
+        let element = der(0x04, &content);
+        assert_eq!(&element[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        let mut at = 0;
+        assert_eq!(read_tlv(&element, &mut at), Some((0x04, &content[..])));
+        assert_eq!(at, element.len());
+        assert_eq!(read_tlv(&[0x04, 0x81, 0x02, 0x41, 0x42], &mut 0), Some((0x04, &b"AB"[..])));
+    }
+
+    #[test]
+    fn truncated_or_malformed_elements() {
+        let mut at = 0;
+        assert_eq!(read_tlv(&BIND_RESPONSE[..6], &mut at), None);
+        assert_eq!(at, 0, "Offset moved on a failed read");
+        assert_eq!(read_tlv(&[0x30], &mut 0), None);
+        assert_eq!(read_tlv(&[], &mut 0), None);
+        assert_eq!(read_tlv(&[0x04, 0x82, 0x01], &mut 0), None, "Length bytes cut off");
+        assert_eq!(read_tlv(&[0x04, 0x80, 0x00, 0x00], &mut 0), None, "Indefinite length");
+        assert_eq!(read_tlv(&[0x04, 0x85, 0, 0, 0, 0, 1, 0x41], &mut 0), None, "Five length bytes");
+        assert_eq!(read_tlv(&BIND_RESPONSE[..2], &mut 5), None, "Offset past the end");
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        for value in [0, 1, 127, 128, 255, 256, 0x7FFF, 0x8000, 0x564B_0003, u32::MAX] {
+            let encoded = encode_integer(value);
+            assert_eq!(encoded[0] & 0x80, 0, "{} encoded negative", value);
+            assert_eq!(decode_integer(&encoded), value as i64);
+        }
+        assert_eq!(encode_integer(128), vec![0x00, 0x80]);
+        assert_eq!(decode_integer(&[0xff]), -1);
+        assert_eq!(decode_integer(&[0x80, 0x00]), -32768);
+        assert_eq!(decode_integer(&[]), 0);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Db, PortResult, PortState};
+
+//Kept in the run folder next to the results so --resume only needs the one path.
+pub const STATE_FILE: &str = "state.json";
+
+//How often the sweep saves its progress. Each save writes every host found so far.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+//Where a run got to, saved as it goes so a run that gets killed can pick up from here.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: String,
+    pub args: Vec<String>, //The command line the run was started with, a resumed run uses the same one.
+    pub cwd: PathBuf,      //Where the run was started from, relative paths in args are from here.
+    pub started: u64,      //Unix time the run was first started.
+    pub output_dir: PathBuf,
+    pub completed_subnets: BTreeSet<String>, //Every /24 swept so far, "10.1.2.0" with no mask.
+    pub subnets_with_hosts: Vec<String>,
+    pub hosts: BTreeMap<String, String>,     //ip -> hostname, same as the host list.
+    pub ports: Option<Vec<SavedPort>>,       //Every port result, set once the portscan has finished.
+    pub swept_hosts: Option<BTreeMap<String, String>>, //The host list before the portscan added to it, set with ports.
+    #[serde(skip)]
+    last_saved: Option<Instant>,
+}
+
+//A port result from a finished portscan, closed and filtered ones too so the outputs match a run
+//that wasn't stopped.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedPort {
+    pub host: IpAddr,
+    pub port: u32,
+    pub protocol: String,
+    pub state: String, //PortState::name()
+    pub latency_ms: u64,
+}
+
+impl Checkpoint {
+    //A fresh run, nothing done yet. The run folder is kept as a full path so the state file works from anywhere.
+    pub fn new(started: SystemTime, output_dir: &Path) -> Checkpoint {
+        Checkpoint {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            args: std::env::args().collect(),
+            cwd: std::env::current_dir().expect("Getting Current Directory Errored."),
+            started: started.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0),
+            output_dir: fs::canonicalize(output_dir).unwrap_or(output_dir.to_path_buf()),
+            completed_subnets: BTreeSet::new(),
+            subnets_with_hosts: Vec::new(),
+            hosts: BTreeMap::new(),
+            ports: None,
+            swept_hosts: None,
+            last_saved: None,
+        }
+    }
+
+    //When the run was first started.
+    pub fn started(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.started)
+    }
+
+    //Where the state file for this run goes.
+    pub fn path(&self) -> PathBuf {
+        self.output_dir.join(STATE_FILE)
+    }
+
+    //DESCRIPTION: Marks a /24 as swept and saves if the last save was long enough ago.
+    //TAKES: The subnet ("10.1.2.0"), the subnets with hosts so far and the host list.
+    //RETURNS: Nothing.
+    pub fn subnet_swept(&mut self, subnet: &str, subnets_with_hosts: &[String], host_list: &Db) {
+        self.completed_subnets.insert(subnet.to_string());
+        let due = self.last_saved.is_none_or(|saved| saved.elapsed() >= CHECKPOINT_INTERVAL);
+        if due {
+            self.save(subnets_with_hosts, host_list);
+        }
+    }
+
+    //DESCRIPTION: Keeps the port results from a finished portscan so a resumed run doesn't scan again,
+    //             and the hosts found before it so a resumed run still knows which ones the portscan found.
+    //TAKES: The port results and the host list from before the portscan.
+    //RETURNS: Nothing.
+    pub fn portscan_done(&mut self, results: &[PortResult], swept_hosts: &HashMap<String, String>) {
+        let ports = results.iter()
+            .map(|result| SavedPort { host: result.host, port: result.port, protocol: result.proto.to_string(), state: result.state.name().to_string(), latency_ms: result.latency.as_millis() as u64 })
+            .collect();
+        self.ports = Some(ports);
+        self.swept_hosts = Some(swept_hosts.iter().map(|(ip, hostname)| (ip.clone(), hostname.clone())).collect());
+    }
+
+    //DESCRIPTION: Turns the saved ports back into port results for the stages after the portscan.
+    //TAKES: Nothing.
+    //RETURNS: The port results, None if the portscan hadn't finished.
+    pub fn port_results(&self) -> Option<Vec<PortResult>> {
+        let ports = self.ports.as_ref()?;
+        let results = ports.iter().map(|saved| PortResult {
+            host: saved.host,
+            port: saved.port,
+            proto: if saved.protocol == "udp" { "udp" } else { "tcp" },
+            state: PortState::from_name(&saved.state).unwrap_or(PortState::Error),
+            latency: Duration::from_millis(saved.latency_ms),
+        }).collect();
+        Some(results)
+    }
+
+    //DESCRIPTION: Writes the state file. Written to a temporary file first and renamed over the old
+    //             one so getting killed mid write doesn't lose the last good checkpoint.
+    //TAKES: The subnets with hosts so far and the host list.
+    //RETURNS: Nothing. A failed save is only reported, the scan carries on.
+    pub fn save(&mut self, subnets_with_hosts: &[String], host_list: &Db) {
+        self.subnets_with_hosts = subnets_with_hosts.to_vec();
+        self.hosts = host_list.lock().unwrap().iter().map(|(ip, hostname)| (ip.clone(), hostname.clone())).collect();
+        self.last_saved = Some(Instant::now());
+        let state_rfp = self.path();
+        let temp_rfp = state_rfp.with_extension("json.tmp");
+        let state = serde_json::to_string(self).expect("Unable to serialize the checkpoint");
+        if let Err(e) = fs::write(&temp_rfp, state).and_then(|_| fs::rename(&temp_rfp, &state_rfp)) {
+            eprintln!("Couldn't save the checkpoint to {}: {}", state_rfp.display(), e);
+        }
+    }
+
+    //DESCRIPTION: Removes the state file once the run has finished, there's nothing left to resume.
+    //TAKES: Nothing.
+    //RETURNS: Nothing.
+    pub fn remove(&self) {
+        let state_rfp = self.path();
+        if state_rfp.exists() {
+            if let Err(e) = fs::remove_file(&state_rfp) {
+                eprintln!("Couldn't remove {}: {}", state_rfp.display(), e);
+            }
+        }
+    }
+}
+
+//DESCRIPTION: Reads a state file back to resume the run it came from.
+//TAKES: Path to the state file.
+//RETURNS: The checkpoint. Panics if it can't be read or isn't a valk2 state file.
+pub fn load_checkpoint(path: &str) -> Checkpoint {
+    let contents = match fs::read_to_string(path) {
+        Err(e) => panic!("Couldn't Read {}: {}", path, e),
+        Ok(contents) => contents,
+    };
+    match serde_json::from_str(&contents) {
+        Err(e) => panic!("{} isn't a valk2 state file: {}", path, e),
+        Ok(checkpoint) => checkpoint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    //DESCRIPTION: Makes an empty run folder under the temp dir for one test.
+    //TAKES: A name for the folder.
+    //RETURNS: Its path.
+    fn run_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("valk2-checkpoint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).expect("Couldn't create the test run folder");
+        folder
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let folder = run_folder("round-trip");
+        let mut checkpoint = Checkpoint::new(UNIX_EPOCH + Duration::from_secs(1706708700), &folder);
+        let host_list: Db = Arc::new(Mutex::new(HashMap::from([
+            ("10.0.1.5".to_string(), "dc01".to_string()),
+            ("10.0.1.20".to_string(), "no_hostname".to_string()),
+        ])));
+        let subnets = vec!["10.0.1.0/24".to_string()];
+        checkpoint.subnet_swept("10.0.1.0", &subnets, &host_list);
+        checkpoint.subnet_swept("10.0.2.0", &subnets, &host_list);
+        let swept_hosts = host_list.lock().unwrap().clone();
+        let results = [
+            PortResult { host: "10.0.1.5".parse().unwrap(), port: 445, proto: "tcp", state: PortState::Open, latency: Duration::from_millis(3) },
+            PortResult { host: "10.0.1.5".parse().unwrap(), port: 3389, proto: "tcp", state: PortState::Filtered, latency: Duration::from_secs(1) },
+            PortResult { host: "10.0.1.30".parse().unwrap(), port: 161, proto: "udp", state: PortState::Open, latency: Duration::from_millis(12) },
+        ];
+        host_list.lock().unwrap().insert("10.0.1.30".to_string(), "no_hostname".to_string());
+        checkpoint.portscan_done(&results, &swept_hosts);
+        checkpoint.save(&subnets, &host_list);
+
+        let state_rfp = checkpoint.path();
+        assert_eq!(state_rfp, fs::canonicalize(&folder).unwrap().join(STATE_FILE));
+        let loaded = load_checkpoint(state_rfp.to_str().unwrap());
+        assert_eq!(loaded.version, checkpoint.version);
+        assert_eq!(loaded.args, checkpoint.args);
+        assert_eq!(loaded.cwd, checkpoint.cwd);
+        assert_eq!(loaded.started(), UNIX_EPOCH + Duration::from_secs(1706708700));
+        assert_eq!(loaded.output_dir, checkpoint.output_dir);
+        assert_eq!(loaded.completed_subnets, BTreeSet::from(["10.0.1.0".to_string(), "10.0.2.0".to_string()]));
+        assert_eq!(loaded.subnets_with_hosts, subnets);
+        assert_eq!(loaded.hosts.len(), 3);
+        //The portscan found 10.0.1.30, the sweep didn't.
+        let swept = loaded.swept_hosts.as_ref().expect("swept_hosts wasn't saved");
+        assert_eq!(swept.keys().collect::<Vec<_>>(), vec!["10.0.1.20", "10.0.1.5"]);
+        //Every state is kept, not only the open ports.
+        let port_results = loaded.port_results().expect("ports weren't saved");
+        let ports: Vec<(String, u32, &str, PortState, Duration)> = port_results.iter().map(|result| (result.host.to_string(), result.port, result.proto, result.state, result.latency)).collect();
+        assert_eq!(ports, vec![
+            ("10.0.1.5".to_string(), 445, "tcp", PortState::Open, Duration::from_millis(3)),
+            ("10.0.1.5".to_string(), 3389, "tcp", PortState::Filtered, Duration::from_secs(1)),
+            ("10.0.1.30".to_string(), 161, "udp", PortState::Open, Duration::from_millis(12)),
+        ]);
+
+        loaded.remove();
+        assert!(!state_rfp.exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn sweeps_saved_before_the_portscan_have_no_ports() {
+        let folder = run_folder("pending");
+        let mut checkpoint = Checkpoint::new(SystemTime::now(), &folder);
+        let host_list: Db = Arc::new(Mutex::new(HashMap::from([("10.0.1.5".to_string(), "dc01".to_string())])));
+        checkpoint.save(&["10.0.1.0/24".to_string()], &host_list);
+        let loaded = load_checkpoint(checkpoint.path().to_str().unwrap());
+        assert_eq!(loaded.subnets_with_hosts, vec!["10.0.1.0/24"]);
+        assert!(loaded.port_results().is_none());
+        assert!(loaded.swept_hosts.is_none());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a valk2 state file")]
+    fn truncated_state_files_are_rejected() {
+        let folder = run_folder("truncated");
+        let mut checkpoint = Checkpoint::new(SystemTime::now(), &folder);
+        checkpoint.save(&[], &Arc::new(Mutex::new(HashMap::new())));
+        let state = fs::read_to_string(checkpoint.path()).unwrap();
+        fs::write(checkpoint.path(), &state[..state.len() / 2]).unwrap();
+        load_checkpoint(checkpoint.path().to_str().unwrap());
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::banner::Banner;
+use crate::fingerprint::ServiceInfo;
+use crate::http::HttpInfo;
+use crate::osfingerprint::OsGuess;
+use crate::snmp::SnmpInfo;
+use crate::tls::TlsInfo;
+use crate::{PortResult, PortState};
+
+const DEFAULT_CLASSIFICATION_RULES: &str = include_str!("../classification-rules.txt");
+
+//Where the kernel keeps the MACs of everything on our own subnets. Linux only.
+const ARP_CACHE: &str = "/proc/net/arp";
+
+//Everything the rules can look at for one host, filled in by each stage as it finishes.
+#[derive(Clone, Debug, Default)]
+pub struct HostEvidence {
+    pub tcp_ports: Vec<u32>,
+    pub udp_ports: Vec<u32>,
+    pub banners: Vec<String>,
+    pub services: Vec<String>,
+    pub http_titles: Vec<String>,
+    pub http_servers: Vec<String>,
+    pub tls_names: Vec<String>,
+    pub snmp_object_id: String,
+    pub snmp_descr: String,
+    pub mac: String,
+    pub os: String,
+}
+
+pub type Evidence = HashMap<IpAddr, HostEvidence>;
+
+//What one condition looks at.
+enum Test {
+    TcpPort(u32),
+    UdpPort(u32),
+    Banner(Regex),
+    Service(Regex),
+    HttpTitle(Regex),
+    HttpServer(Regex),
+    Tls(Regex),
+    SnmpOid(String),
+    SnmpDescr(Regex),
+    Mac(String),
+    Os(Regex),
+}
+
+struct Condition {
+    negated: bool,
+    test: Test,
+}
+
+//One line of the rule file.
+pub struct ClassificationRule {
+    pub category: String,
+    pub text: String, //The conditions as written, to say why a host got its category.
+    conditions: Vec<Condition>,
+}
+
+//The category a host ended up with.
+#[derive(Clone, Debug, Serialize)]
+pub struct Classification {
+    pub host: IpAddr,
+    pub category: String,
+    pub matched: String,
+}
+
+//DESCRIPTION: Loads the rule file at path, or the built in one if path is None.
+//TAKES: Optional path to a rule file.
+//RETURNS: The rules in file order. Panics on a file that can't be read or a faulty line.
+pub fn load_classification_rules(path: Option<&str>) -> Vec<ClassificationRule> {
+    match path {
+        Some(path) => {
+            let contents = match fs::read_to_string(path) {
+                Err(e) => panic!("Couldn't Read {}: {}", path, e),
+                Ok(contents) => contents,
+            };
+            parse_classification_rules(&contents, path)
+        },
+        None => parse_classification_rules(DEFAULT_CLASSIFICATION_RULES, "built in classification rules"),
+    }
+}
+
+//DESCRIPTION: Parses rule file text, <category>|<condition> & <condition>... per line.
+//TAKES: The file contents and a name to put in error messages.
+//RETURNS: The rules in file order.
+fn parse_classification_rules(contents: &str, source: &str) -> Vec<ClassificationRule> {
+    let mut rules = Vec::new();
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let faulty = || -> ! { panic!("Something is wrong with the formatting of {}: Faulty Line {} >{}<", source, line_no + 1, line) };
+        let Some((category, text)) = line.split_once('|') else { faulty() };
+        let mut conditions = Vec::new();
+        for condition in text.split(" & ") {
+            let condition = condition.trim();
+            let (negated, condition) = match condition.strip_prefix('!') {
+                Some(condition) => (true, condition),
+                None => (false, condition),
+            };
+            let Some((kind, value)) = condition.split_once(':') else { faulty() };
+            let regex = || RegexBuilder::new(value).case_insensitive(true).build().unwrap_or_else(|_| faulty());
+            let test = match kind {
+                "port" => Test::TcpPort(value.parse().unwrap_or_else(|_| faulty())),
+                "udp" => Test::UdpPort(value.parse().unwrap_or_else(|_| faulty())),
+                "banner" => Test::Banner(regex()),
+                "service" => Test::Service(regex()),
+                "http-title" => Test::HttpTitle(regex()),
+                "http-server" => Test::HttpServer(regex()),
+                "tls" => Test::Tls(regex()),
+                "snmp-oid" => Test::SnmpOid(value.to_string()),
+                "snmp-descr" => Test::SnmpDescr(regex()),
+                "mac" => Test::Mac(value.to_ascii_lowercase()),
+                "os" => Test::Os(regex()),
+                _ => faulty(),
+            };
+            conditions.push(Condition { negated, test });
+        }
+        rules.push(ClassificationRule { category: category.trim().to_string(), text: text.trim().to_string(), conditions });
+    }
+    rules
+}
+
+//DESCRIPTION: Adds the open ports from a scan.
+//TAKES: The evidence and the port results.
+//RETURNS: Nothing.
+pub fn add_port_results(evidence: &mut Evidence, results: &[PortResult]) {
+    for result in results.iter().filter(|result| result.state == PortState::Open) {
+        let host = evidence.entry(result.host).or_default();
+        match result.proto {
+            "udp" => host.udp_ports.push(result.port),
+            _ => host.tcp_ports.push(result.port),
+        }
+    }
+}
+
+//DESCRIPTION: Adds the banners.
+//TAKES: The evidence and the banners.
+//RETURNS: Nothing.
+pub fn add_banners(evidence: &mut Evidence, banners: &[Banner]) {
+    for banner in banners {
+        evidence.entry(banner.host).or_default().banners.push(banner.text.clone());
+    }
+}
+
+//DESCRIPTION: Adds the fingerprinted services as one line each.
+//TAKES: The evidence and the services.
+//RETURNS: Nothing.
+pub fn add_services(evidence: &mut Evidence, services: &[ServiceInfo]) {
+    for service in services {
+        let line = format!("{} {} {} {}", service.service, service.summary(), service.os, service.device_type);
+        evidence.entry(service.host).or_default().services.push(line);
+    }
+}
+
+//DESCRIPTION: Adds page titles and Server headers.
+//TAKES: The evidence and the HTTP results.
+//RETURNS: Nothing.
+pub fn add_http(evidence: &mut Evidence, pages: &[HttpInfo]) {
+    for page in pages {
+        let host = evidence.entry(page.host).or_default();
+        host.http_titles.push(page.title.clone());
+        host.http_servers.push(page.server.clone());
+    }
+}
+
+//DESCRIPTION: Adds the subject, issuer and every name off each certificate.
+//TAKES: The evidence and the certificates.
+//RETURNS: Nothing.
+pub fn add_certificates(evidence: &mut Evidence, certificates: &[TlsInfo]) {
+    for cert in certificates {
+        let host = evidence.entry(cert.host).or_default();
+        host.tls_names.push(cert.subject.clone());
+        host.tls_names.push(cert.issuer.clone());
+        host.tls_names.extend(cert.sans.iter().cloned());
+    }
+}
+
+//DESCRIPTION: Adds sysObjectID and sysDescr.
+//TAKES: The evidence and the SNMP agents.
+//RETURNS: Nothing.
+pub fn add_snmp(evidence: &mut Evidence, agents: &[SnmpInfo]) {
+    for agent in agents {
+        let host = evidence.entry(agent.host).or_default();
+        host.snmp_object_id = agent.sys_object_id.clone();
+        host.snmp_descr = agent.sys_descr.clone();
+    }
+}
+
+//DESCRIPTION: Adds the OS family guesses.
+//TAKES: The evidence and the guesses.
+//RETURNS: Nothing.
+pub fn add_os_guesses(evidence: &mut Evidence, guesses: &[OsGuess]) {
+    for guess in guesses {
+        evidence.entry(guess.host).or_default().os = guess.family.clone();
+    }
+}
+
+//DESCRIPTION: Adds MACs from the kernel's ARP cache for hosts we already have evidence for.
+//             Scanning a host on our own subnet puts it in the cache so this is done last.
+//TAKES: The evidence.
+//RETURNS: Nothing. No cache (not Linux) just means no MACs.
+pub fn add_arp_cache(evidence: &mut Evidence) {
+    let Ok(contents) = fs::read_to_string(ARP_CACHE) else { return };
+    //IP address, HW type, Flags, HW address, Mask, Device. Flags 0x2 is a completed entry.
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[2] != "0x2" {
+            continue;
+        }
+        let Ok(ip) = fields[0].parse::<IpAddr>() else { continue };
+        if let Some(host) = evidence.get_mut(&ip) {
+            host.mac = fields[3].to_ascii_lowercase();
+        }
+    }
+}
+
+//DESCRIPTION: Gives every host the category of the first rule it matches and writes
+//             classification.txt. Hosts that match nothing are listed as unknown.
+//TAKES: The evidence, the rules, the hostnames and the run folder to write to.
+//RETURNS: The category of every host, sorted by address.
+pub fn classify_hosts(evidence: &Evidence, rules: &[ClassificationRule], hostnames: &HashMap<String, String>, out_dir: &Path) -> Vec<Classification> {
+    let mut hosts: Vec<&IpAddr> = evidence.keys().collect();
+    hosts.sort();
+
+    let classification_rfp = out_dir.join("classification.txt");
+    let classification_rf = match File::create(&classification_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", classification_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut classification_buff = BufWriter::new(classification_rf);
+
+    let mut classifications = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let host_evidence = &evidence[host];
+        let classification = match rules.iter().find(|rule| rule.conditions.iter().all(|condition| condition_holds(condition, host_evidence))) {
+            Some(rule) => Classification { host: *host, category: rule.category.clone(), matched: rule.text.clone() },
+            None => Classification { host: *host, category: "unknown".to_string(), matched: String::new() },
+        };
+        let hostname = hostnames.get(&host.to_string()).map(|hostname| hostname.as_str()).unwrap_or("no_hostname");
+        eprintln!("{} {} {}", classification.host, hostname, classification.category);
+        let block = format!(
+            "{} {}\n    Category: {}\n    Matched: {}\n    MAC: {}\n",
+            classification.host, hostname, classification.category, classification.matched, host_evidence.mac,
+        );
+        classification_buff.write_all(block.as_bytes()).expect("Unable to write to classification.txt");
+        classifications.push(classification);
+    }
+    classifications
+}
+
+//DESCRIPTION: Checks one condition against a host.
+//TAKES: The condition and the host's evidence.
+//RETURNS: True if it holds, after the ! if it has one.
+fn condition_holds(condition: &Condition, host: &HostEvidence) -> bool {
+    let any = |values: &[String], regex: &Regex| values.iter().any(|value| regex.is_match(value));
+    let holds = match &condition.test {
+        Test::TcpPort(port) => host.tcp_ports.contains(port),
+        Test::UdpPort(port) => host.udp_ports.contains(port),
+        Test::Banner(regex) => any(&host.banners, regex),
+        Test::Service(regex) => any(&host.services, regex),
+        Test::HttpTitle(regex) => any(&host.http_titles, regex),
+        Test::HttpServer(regex) => any(&host.http_servers, regex),
+        Test::Tls(regex) => any(&host.tls_names, regex),
+        Test::SnmpOid(prefix) => !host.snmp_object_id.is_empty() && host.snmp_object_id.starts_with(prefix.as_str()),
+        Test::SnmpDescr(regex) => regex.is_match(&host.snmp_descr),
+        Test::Mac(prefix) => !host.mac.is_empty() && host.mac.starts_with(prefix.as_str()),
+        Test::Os(regex) => regex.is_match(&host.os),
+    };
+    holds != condition.negated
+}
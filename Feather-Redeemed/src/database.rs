@@ -0,0 +1,551 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::banner::sanitize_banner;
+use crate::udpscan::udp_exchange;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+//Redis INFO and a MongoDB isMaster reply are a few KB, nothing else comes close.
+const MAX_DATABASE_REPLY: usize = 65536;
+const MAX_DATABASE_PROBES: usize = 64;
+
+pub const MSSQL_PORT: u32 = 1433;
+pub const SQL_BROWSER_PORT: u32 = 1434;
+pub const MYSQL_PORT: u32 = 3306;
+pub const POSTGRES_PORT: u32 = 5432;
+pub const REDIS_PORT: u32 = 6379;
+pub const MONGODB_PORT: u32 = 27017;
+
+//TCP ports we have a probe for.
+pub const DATABASE_PORTS: &[u32] = &[MSSQL_PORT, MYSQL_PORT, POSTGRES_PORT, REDIS_PORT, MONGODB_PORT];
+
+//What a database server told us before we logged in. Each one gives up different things so
+//anything past the version goes in details as (name, value).
+#[derive(Clone, Debug, Serialize)]
+pub struct DatabaseInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub service: &'static str,
+    pub version: String,
+    pub details: Vec<(&'static str, String)>,
+}
+
+//DESCRIPTION: Runs the matching probe against every open database port, asks SQL Browser on every
+//             host for its instances, and writes databases.txt.
+//TAKES: The open database ports as (host, port) and the hosts to ask SQL Browser,
+//       the run folder to write to and the stop flag.
+//RETURNS: What every database server said.
+pub async fn database_enrich(open_databases: &[(IpAddr, u32)], browser_hosts: &[IpAddr], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<DatabaseInfo> {
+    //SQL Browser asks go in with no port, it's the host that gets asked not one of its ports.
+    let targets = open_databases.iter().map(|(host, port)| (*host, Some(*port))).chain(browser_hosts.iter().map(|host| (*host, None)));
+    let mut probes = Vec::with_capacity(open_databases.len() + browser_hosts.len());
+    for (host, port) in targets {
+        probes.push(async move {
+            match port {
+                Some(MSSQL_PORT) => mssql_prelogin(host).await,
+                Some(MYSQL_PORT) => mysql_handshake(host).await,
+                Some(POSTGRES_PORT) => postgres_ssl(host).await,
+                Some(REDIS_PORT) => redis_info(host).await,
+                Some(MONGODB_PORT) => mongodb_is_master(host).await,
+                Some(_) => None,
+                None => sql_browser(host).await,
+            }
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_DATABASE_PROBES, stop).await;
+
+    let database_rfp = out_dir.join("databases.txt");
+    let database_rf = match File::create(&database_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", database_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut database_buff = BufWriter::new(database_rf);
+
+    let mut results = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        eprintln!("{}:{} {} {}", info.host, info.port, info.service, info.version);
+        let mut block = format!("{}:{} {} {}\n", info.host, info.port, info.service, info.version);
+        for (name, value) in &info.details {
+            block.push_str(&format!("    {}: {}\n", name, value));
+        }
+        database_buff.write_all(block.as_bytes()).expect("Unable to write to databases.txt");
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: Sends a TDS PRELOGIN, the first thing any SQL Server client sends.
+//TAKES: The host.
+//RETURNS: The version and what the server wants for encryption, or None if it didn't answer like SQL Server.
+async fn mssql_prelogin(host: IpAddr) -> Option<DatabaseInfo> {
+    let mut stream = database_connect(host, MSSQL_PORT).await?;
+    //Option headers (token, offset, length) then their data: VERSION, ENCRYPTION (off),
+    //INSTOPT (default instance), THREADID and MARS (off).
+    let options: [(u8, &[u8]); 5] = [(0x00, &[0; 6]), (0x01, &[0x00]), (0x02, &[0x00]), (0x03, &[0; 4]), (0x04, &[0x00])];
+    let mut headers = Vec::new();
+    let mut data = Vec::new();
+    let mut offset = options.len() * 5 + 1;
+    for (token, value) in options {
+        headers.push(token);
+        headers.extend_from_slice(&(offset as u16).to_be_bytes());
+        headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value);
+        offset += value.len();
+    }
+    headers.push(0xFF);
+    //TDS header: PRELOGIN, end of message, length, SPID, packet id, window.
+    let mut request = vec![0x12, 0x01];
+    request.extend_from_slice(&((8 + headers.len() + data.len()) as u16).to_be_bytes());
+    request.extend_from_slice(&[0x00, 0x00, 0x01, 0x00]);
+    request.extend_from_slice(&headers);
+    request.extend_from_slice(&data);
+    stream.write_all(&request).await.ok()?;
+
+    let header = read_exact_timeout(&mut stream, 8).await?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if header[0] != 0x04 || length < 8 {
+        return None;
+    }
+    let reply = read_exact_timeout(&mut stream, length - 8).await?;
+    let mut info = DatabaseInfo { host, port: MSSQL_PORT, service: "Microsoft SQL Server", version: String::new(), details: Vec::new() };
+    let mut at = 0;
+    while let Some(&token) = reply.get(at) {
+        if token == 0xFF {
+            break;
+        }
+        let header = reply.get(at..at + 5)?;
+        let offset = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let value = reply.get(offset..offset + length)?;
+        match token {
+            0x00 if length >= 4 => {
+                let build = u16::from_be_bytes([value[2], value[3]]);
+                info.version = format!("{}.{}.{}", value[0], value[1], build);
+                info.details.push(("Release", sql_server_release(value[0], value[1]).to_string()));
+            },
+            0x01 if length >= 1 => {
+                let encryption = match value[0] {
+                    0x00 => "login only",
+                    0x01 => "on",
+                    0x02 => "not supported",
+                    0x03 => "required",
+                    _ => "unknown",
+                };
+                info.details.push(("Encryption", encryption.to_string()));
+            },
+            _ => {},
+        }
+        at += 5;
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Names the SQL Server release for a major and minor version.
+//TAKES: Major and minor version.
+//RETURNS: The release, or "unknown".
+fn sql_server_release(major: u8, minor: u8) -> &'static str {
+    match (major, minor) {
+        (17, _) => "SQL Server 2025",
+        (16, _) => "SQL Server 2022",
+        (15, _) => "SQL Server 2019",
+        (14, _) => "SQL Server 2017",
+        (13, _) => "SQL Server 2016",
+        (12, _) => "SQL Server 2014",
+        (11, _) => "SQL Server 2012",
+        (10, 50) => "SQL Server 2008 R2",
+        (10, _) => "SQL Server 2008",
+        (9, _) => "SQL Server 2005",
+        (8, _) => "SQL Server 2000",
+        _ => "unknown",
+    }
+}
+
+//DESCRIPTION: Asks SQL Browser for every instance on the host. Named instances listen on a port of
+//             their own choosing so this is the only way to find them.
+//TAKES: The host.
+//RETURNS: The server name and each instance, or None if nothing answered.
+async fn sql_browser(host: IpAddr) -> Option<DatabaseInfo> {
+    //CLNT_UCAST_EX
+    let reply = udp_exchange(host, SQL_BROWSER_PORT, &[0x02]).await.ok()??;
+    //SVR_RESP, length, then ;-separated key;value pairs with ;; between instances.
+    if reply.len() < 3 || reply[0] != 0x05 {
+        return None;
+    }
+    //Named pipe paths are full of backslashes so just drop control characters rather than escaping.
+    let text: String = String::from_utf8_lossy(&reply[3..]).chars().filter(|c| !c.is_control()).collect();
+    let mut info = DatabaseInfo { host, port: SQL_BROWSER_PORT, service: "SQL Browser", version: String::new(), details: Vec::new() };
+    for instance in text.split(";;").filter(|instance| !instance.is_empty()) {
+        let fields: Vec<&str> = instance.split(';').collect();
+        let field = |key: &str| fields.chunks(2).find(|pair| pair[0].eq_ignore_ascii_case(key)).and_then(|pair| pair.get(1)).copied().unwrap_or("");
+        if info.version.is_empty() {
+            info.version = field("ServerName").to_string();
+        }
+        let mut line = format!("{} version {}", field("InstanceName"), field("Version"));
+        if !field("tcp").is_empty() {
+            line.push_str(&format!(" tcp {}", field("tcp")));
+        }
+        if !field("np").is_empty() {
+            line.push_str(&format!(" np {}", field("np")));
+        }
+        info.details.push(("Instance", line));
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Reads the handshake a MySQL or MariaDB server sends as soon as you connect. Servers
+//             that don't want to talk to us send an error packet instead, which says why.
+//TAKES: The host.
+//RETURNS: The version, auth plugin and TLS support, or the error. None if it wasn't MySQL.
+async fn mysql_handshake(host: IpAddr) -> Option<DatabaseInfo> {
+    let mut stream = database_connect(host, MYSQL_PORT).await?;
+    let header = read_exact_timeout(&mut stream, 4).await?;
+    let length = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    if length == 0 || length > MAX_DATABASE_REPLY {
+        return None;
+    }
+    let packet = read_exact_timeout(&mut stream, length).await?;
+    let mut info = DatabaseInfo { host, port: MYSQL_PORT, service: "MySQL", version: String::new(), details: Vec::new() };
+    match packet[0] {
+        //Protocol 10 handshake.
+        0x0A => {
+            let version_end = packet.iter().skip(1).position(|byte| *byte == 0)? + 1;
+            info.version = sanitize_banner(&packet[1..version_end]);
+            if info.version.contains("MariaDB") {
+                info.service = "MariaDB";
+            }
+            //Connection id, first 8 bytes of the scramble and a filler, then the capability flags.
+            let at = version_end + 1 + 4 + 8 + 1;
+            let capabilities = packet.get(at..at + 2).map(|flags| u16::from_le_bytes([flags[0], flags[1]]))?;
+            info.details.push(("TLS", if capabilities & 0x0800 != 0 {"supported"} else {"not supported"}.to_string()));
+            //Charset, status, upper capabilities, scramble length, 10 reserved, rest of the scramble, then the plugin.
+            let scramble_len = *packet.get(at + 7)? as usize;
+            let plugin_at = at + 18 + scramble_len.saturating_sub(8).max(13);
+            if let Some(plugin) = packet.get(plugin_at..) {
+                let plugin_end = plugin.iter().position(|byte| *byte == 0).unwrap_or(plugin.len());
+                info.details.push(("Auth Plugin", sanitize_banner(&plugin[..plugin_end])));
+            }
+        },
+        //ERR packet: code, then the message (with a # and SQL state first on newer servers).
+        0xFF if packet.len() > 3 => {
+            let code = u16::from_le_bytes([packet[1], packet[2]]);
+            let message = if packet[3] == b'#' {packet.get(9..).unwrap_or(&[])} else {&packet[3..]};
+            info.details.push(("Error", format!("{} {}", code, sanitize_banner(message))));
+        },
+        _ => return None,
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Sends a PostgreSQL SSLRequest. The server answers with a single S or N.
+//TAKES: The host.
+//RETURNS: Whether it does TLS, or None if the answer wasn't S or N.
+async fn postgres_ssl(host: IpAddr) -> Option<DatabaseInfo> {
+    let mut stream = database_connect(host, POSTGRES_PORT).await?;
+    let mut request = 8u32.to_be_bytes().to_vec();
+    request.extend_from_slice(&80877103u32.to_be_bytes());
+    stream.write_all(&request).await.ok()?;
+    let reply = read_exact_timeout(&mut stream, 1).await?;
+    let ssl = match reply[0] {
+        b'S' => "supported",
+        b'N' => "not supported",
+        _ => return None,
+    };
+    Some(DatabaseInfo { host, port: POSTGRES_PORT, service: "PostgreSQL", version: String::new(), details: vec![("SSL", ssl.to_string())] })
+}
+
+//DESCRIPTION: Sends Redis INFO. Without a password set it answers with everything about itself,
+//             otherwise it sends back an error saying it wants one.
+//TAKES: The host.
+//RETURNS: The version, OS, mode and role if it answered, or the error. None if it wasn't Redis.
+async fn redis_info(host: IpAddr) -> Option<DatabaseInfo> {
+    let mut stream = database_connect(host, REDIS_PORT).await?;
+    stream.write_all(b"*1\r\n$4\r\nINFO\r\n").await.ok()?;
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 4096];
+    //Bulk string: $length\r\n then that many bytes and a \r\n. Errors are one - line.
+    let complete = |reply: &[u8]| {
+        let Some(line_end) = reply.windows(2).position(|pair| pair == b"\r\n") else { return false };
+        match reply[0] {
+            b'$' => std::str::from_utf8(&reply[1..line_end]).ok().and_then(|len| len.parse::<usize>().ok()).is_none_or(|len| reply.len() >= line_end + 2 + len),
+            _ => true,
+        }
+    };
+    while reply.len() < MAX_DATABASE_REPLY && (reply.is_empty() || !complete(&reply)) {
+        let len = timeout(READ_TIMEOUT, stream.read(&mut buf)).await.ok()?.ok()?;
+        if len == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..len]);
+    }
+
+    let mut info = DatabaseInfo { host, port: REDIS_PORT, service: "Redis", version: String::new(), details: Vec::new() };
+    match reply.first() {
+        Some(b'$') => {
+            info.details.push(("Auth", "not required".to_string()));
+            let text = String::from_utf8_lossy(&reply);
+            for line in text.lines() {
+                let Some((key, value)) = line.split_once(':') else { continue };
+                match key {
+                    "redis_version" => info.version = value.to_string(),
+                    "os" => info.details.push(("OS", value.to_string())),
+                    "redis_mode" => info.details.push(("Mode", value.to_string())),
+                    "role" => info.details.push(("Role", value.to_string())),
+                    _ => {},
+                }
+            }
+        },
+        Some(b'-') => {
+            let line_end = reply.windows(2).position(|pair| pair == b"\r\n").unwrap_or(reply.len());
+            info.details.push(("Auth", "required".to_string()));
+            info.details.push(("Error", sanitize_banner(&reply[1..line_end])));
+        },
+        _ => return None,
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Sends isMaster as a legacy OP_QUERY, which every MongoDB still answers before login.
+//TAKES: The host.
+//RETURNS: The version implied by the wire protocol range, its replica set and role. None if it wasn't MongoDB.
+async fn mongodb_is_master(host: IpAddr) -> Option<DatabaseInfo> {
+    let mut stream = database_connect(host, MONGODB_PORT).await?;
+    //{ isMaster: 1 }
+    let mut query = Vec::new();
+    query.push(0x10);
+    query.extend_from_slice(b"isMaster\x00");
+    query.extend_from_slice(&1i32.to_le_bytes());
+    query.push(0x00);
+    let query = [((query.len() + 4) as i32).to_le_bytes().to_vec(), query].concat();
+    //OP_QUERY: flags, admin.$cmd, skip 0, return 1, the query.
+    let mut body = 0i32.to_le_bytes().to_vec();
+    body.extend_from_slice(b"admin.$cmd\x00");
+    body.extend_from_slice(&0i32.to_le_bytes());
+    body.extend_from_slice(&1i32.to_le_bytes());
+    body.extend_from_slice(&query);
+    //Header: length, request id, response to, opcode 2004.
+    let mut request = ((16 + body.len()) as i32).to_le_bytes().to_vec();
+    request.extend_from_slice(&0x564B_3200i32.to_le_bytes());
+    request.extend_from_slice(&0i32.to_le_bytes());
+    request.extend_from_slice(&2004i32.to_le_bytes());
+    request.extend_from_slice(&body);
+    stream.write_all(&request).await.ok()?;
+
+    let header = read_exact_timeout(&mut stream, 16).await?;
+    let length = i32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let opcode = i32::from_le_bytes(header[12..16].try_into().unwrap());
+    //OP_REPLY: flags, cursor id, starting from and count come before the document.
+    if opcode != 1 || !(16 + 20 + 5..=MAX_DATABASE_REPLY).contains(&length) {
+        return None;
+    }
+    let reply = read_exact_timeout(&mut stream, length - 16).await?;
+    let fields = bson_fields(&reply[20..]);
+
+    let mut info = DatabaseInfo { host, port: MONGODB_PORT, service: "MongoDB", version: String::new(), details: Vec::new() };
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+    if let Some(wire_version) = field("maxWireVersion") {
+        info.version = mongodb_release(&wire_version).to_string();
+        info.details.push(("Wire Version", wire_version));
+    }
+    let role = if field("msg").as_deref() == Some("isdbgrid") {
+        "mongos"
+    }
+    else if field("ismaster").as_deref() == Some("true") {
+        "primary"
+    }
+    else if field("secondary").as_deref() == Some("true") {
+        "secondary"
+    }
+    else {
+        "other"
+    };
+    info.details.push(("Role", role.to_string()));
+    if let Some(set_name) = field("setName") {
+        info.details.push(("Replica Set", set_name));
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Names the newest MongoDB release that speaks a wire version.
+//TAKES: maxWireVersion as text.
+//RETURNS: The release, or "unknown".
+fn mongodb_release(wire_version: &str) -> &'static str {
+    match wire_version.parse::<i64>().unwrap_or(-1) {
+        25.. => "8.0",
+        21..=24 => "7.0",
+        17..=20 => "6.0",
+        13..=16 => "5.0",
+        9..=12 => "4.4",
+        8 => "4.2",
+        7 => "4.0",
+        6 => "3.6",
+        5 => "3.4",
+        4 => "3.2",
+        3 => "3.0",
+        2 => "2.6",
+        0..=1 => "2.4 or older",
+        _ => "unknown",
+    }
+}
+
+//DESCRIPTION: Walks the top level of a BSON document. Only the scalar types isMaster sends come out
+//             as text, everything else is skipped.
+//TAKES: The document.
+//RETURNS: (name, value) for each field we could read, stopping at anything we can't step over.
+fn bson_fields(document: &[u8]) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut at = 4; //Document length
+    while let Some(&kind) = document.get(at) {
+        if kind == 0x00 {
+            break;
+        }
+        let Some(name_len) = document.get(at + 1..).and_then(|rest| rest.iter().position(|byte| *byte == 0)) else { break };
+        let name = String::from_utf8_lossy(&document[at + 1..at + 1 + name_len]).to_string();
+        at += 2 + name_len;
+        let int32 = |at: usize| document.get(at..at + 4).map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()));
+        let (value, size) = match kind {
+            0x01 => (document.get(at..at + 8).map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()).to_string()), 8),
+            0x02 => {
+                //The length counts the trailing zero, anything under 1 isn't a string.
+                let Some(len) = int32(at).filter(|len| *len >= 1) else { break };
+                let text = document.get(at + 4..at + 4 + len as usize - 1).map(sanitize_banner);
+                (text, 4 + len as usize)
+            },
+            0x03 | 0x04 => (None, int32(at).unwrap_or(0).max(0) as usize),
+            0x05 => (None, 5 + int32(at).unwrap_or(0).max(0) as usize),
+            0x07 => (None, 12),
+            0x08 => (document.get(at).map(|byte| (*byte != 0).to_string()), 1),
+            0x09 | 0x11 | 0x12 => (document.get(at..at + 8).map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap()).to_string()), 8),
+            0x0A => (None, 0),
+            0x10 => (int32(at).map(|number| number.to_string()), 4),
+            _ => break,
+        };
+        if size == 0 && kind != 0x0A {
+            break;
+        }
+        if let Some(value) = value {
+            fields.push((name, value));
+        }
+        at += size;
+    }
+    fields
+}
+
+//DESCRIPTION: Connects to a database port.
+//TAKES: Host and port.
+//RETURNS: The stream, or None if it didn't connect in time.
+async fn database_connect(host: IpAddr, port: u32) -> Option<TcpStream> {
+    let socket = SocketAddr::new(host, port as u16);
+    timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()
+}
+
+//DESCRIPTION: Reads exactly len bytes.
+//TAKES: The stream and how many bytes.
+//RETURNS: The bytes, or None if the connection closed or it took too long.
+async fn read_exact_timeout(stream: &mut TcpStream, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    timeout(READ_TIMEOUT, stream.read_exact(&mut buf)).await.ok()?.ok()?;
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The isMaster document a MongoDB 6.0 replica set primary sends back, laid out field for field.
+    const IS_MASTER_REPLY: &[u8] = &[
+        0xd3, 0x01, 0x00, 0x00, 0x03, 0x74, 0x6f, 0x70, 0x6f, 0x6c, 0x6f, 0x67, 0x79, 0x56, 0x65, 0x72,
+        0x73, 0x69, 0x6f, 0x6e, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x07, 0x70, 0x72, 0x6f, 0x63, 0x65, 0x73,
+        0x73, 0x49, 0x64, 0x00, 0x65, 0x30, 0xf1, 0xc2, 0xa8, 0xd4, 0xe5, 0xf6, 0xa7, 0xb8, 0xc9, 0xd0,
+        0x12, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x73, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x02, 0x30, 0x00,
+        0x16, 0x00, 0x00, 0x00, 0x64, 0x62, 0x31, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+        0x63, 0x6f, 0x6d, 0x3a, 0x32, 0x37, 0x30, 0x31, 0x37, 0x00, 0x02, 0x31, 0x00, 0x16, 0x00, 0x00,
+        0x00, 0x64, 0x62, 0x32, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d,
+        0x3a, 0x32, 0x37, 0x30, 0x31, 0x37, 0x00, 0x00, 0x02, 0x73, 0x65, 0x74, 0x4e, 0x61, 0x6d, 0x65,
+        0x00, 0x04, 0x00, 0x00, 0x00, 0x72, 0x73, 0x30, 0x00, 0x10, 0x73, 0x65, 0x74, 0x56, 0x65, 0x72,
+        0x73, 0x69, 0x6f, 0x6e, 0x00, 0x03, 0x00, 0x00, 0x00, 0x08, 0x69, 0x73, 0x6d, 0x61, 0x73, 0x74,
+        0x65, 0x72, 0x00, 0x01, 0x08, 0x73, 0x65, 0x63, 0x6f, 0x6e, 0x64, 0x61, 0x72, 0x79, 0x00, 0x00,
+        0x02, 0x70, 0x72, 0x69, 0x6d, 0x61, 0x72, 0x79, 0x00, 0x16, 0x00, 0x00, 0x00, 0x64, 0x62, 0x31,
+        0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x3a, 0x32, 0x37, 0x30,
+        0x31, 0x37, 0x00, 0x02, 0x6d, 0x65, 0x00, 0x16, 0x00, 0x00, 0x00, 0x64, 0x62, 0x31, 0x2e, 0x65,
+        0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x3a, 0x32, 0x37, 0x30, 0x31, 0x37,
+        0x00, 0x10, 0x6d, 0x61, 0x78, 0x42, 0x73, 0x6f, 0x6e, 0x4f, 0x62, 0x6a, 0x65, 0x63, 0x74, 0x53,
+        0x69, 0x7a, 0x65, 0x00, 0x00, 0x00, 0x00, 0x01, 0x10, 0x6d, 0x61, 0x78, 0x4d, 0x65, 0x73, 0x73,
+        0x61, 0x67, 0x65, 0x53, 0x69, 0x7a, 0x65, 0x42, 0x79, 0x74, 0x65, 0x73, 0x00, 0x00, 0x6c, 0xdc,
+        0x02, 0x10, 0x6d, 0x61, 0x78, 0x57, 0x72, 0x69, 0x74, 0x65, 0x42, 0x61, 0x74, 0x63, 0x68, 0x53,
+        0x69, 0x7a, 0x65, 0x00, 0xa0, 0x86, 0x01, 0x00, 0x09, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x54, 0x69,
+        0x6d, 0x65, 0x00, 0xdb, 0x0b, 0xc4, 0x5f, 0x8d, 0x01, 0x00, 0x00, 0x10, 0x6c, 0x6f, 0x67, 0x69,
+        0x63, 0x61, 0x6c, 0x53, 0x65, 0x73, 0x73, 0x69, 0x6f, 0x6e, 0x54, 0x69, 0x6d, 0x65, 0x6f, 0x75,
+        0x74, 0x4d, 0x69, 0x6e, 0x75, 0x74, 0x65, 0x73, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x12, 0x63, 0x6f,
+        0x6e, 0x6e, 0x65, 0x63, 0x74, 0x69, 0x6f, 0x6e, 0x49, 0x64, 0x00, 0x12, 0x04, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x10, 0x6d, 0x69, 0x6e, 0x57, 0x69, 0x72, 0x65, 0x56, 0x65, 0x72, 0x73, 0x69,
+        0x6f, 0x6e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x6d, 0x61, 0x78, 0x57, 0x69, 0x72, 0x65, 0x56,
+        0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x00, 0x11, 0x00, 0x00, 0x00, 0x08, 0x72, 0x65, 0x61, 0x64,
+        0x4f, 0x6e, 0x6c, 0x79, 0x00, 0x00, 0x01, 0x6f, 0x6b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xf0, 0x3f, 0x00,
+    ];
+
+    #[test]
+    fn reads_an_is_master_reply() {
+        let fields = bson_fields(IS_MASTER_REPLY);
+        let expected = [
+            ("setName", "rs0"),
+            ("setVersion", "3"),
+            ("ismaster", "true"),
+            ("secondary", "false"),
+            ("primary", "db1.example.com:27017"),
+            ("me", "db1.example.com:27017"),
+            ("maxBsonObjectSize", "16777216"),
+            ("maxMessageSizeBytes", "48000000"),
+            ("maxWriteBatchSize", "100000"),
+            ("localTime", "1706708700123"),
+            ("logicalSessionTimeoutMinutes", "30"),
+            ("connectionId", "1042"),
+            ("minWireVersion", "0"),
+            ("maxWireVersion", "17"),
+            ("readOnly", "false"),
+            ("ok", "1"),
+        ];
+        let expected: Vec<(String, String)> = expected.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        assert_eq!(fields, expected);
+        assert_eq!(mongodb_release("17"), "6.0");
+    }
+
+    #[test]
+    fn truncated_replies_give_the_fields_before_the_cut() {
+        let full = bson_fields(IS_MASTER_REPLY);
+        for len in 0..IS_MASTER_REPLY.len() {
+            let fields = bson_fields(&IS_MASTER_REPLY[..len]);
+            assert!(full.starts_with(&fields), "Cut off at {} gave {:?}", len, fields);
+        }
+    }
+
+    #[test]
+    fn malformed_documents_stop_the_walk() {
+        //A string claiming to be longer than the document.
+        let mut long_string = vec![0x00, 0x00, 0x00, 0x00, 0x02, b'a', 0x00, 0xff, 0xff, 0xff, 0x7f, b'x', 0x00];
+        long_string.extend_from_slice(&[0x10, b'b', 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(bson_fields(&long_string), Vec::new());
+        //A zero or negative string length after a good field. Only the good field comes out.
+        for len in [[0x00, 0x00, 0x00, 0x00], [0xfb, 0xff, 0xff, 0xff]] {
+            let mut bad_length = vec![0x00, 0x00, 0x00, 0x00, 0x10, b'b', 0x00, 0x07, 0x00, 0x00, 0x00, 0x02, b'a', 0x00];
+            bad_length.extend_from_slice(&len);
+            bad_length.extend_from_slice(&[0x10, b'c', 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+            assert_eq!(bson_fields(&bad_length), vec![("b".to_string(), "7".to_string())]);
+        }
+        //A name that never ends.
+        assert_eq!(bson_fields(&[0x00, 0x00, 0x00, 0x00, 0x10, b'a', b'b']), Vec::new());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::results::{HostReport, PortReport, ScanReport};
+
+//What changed between two runs. Ports on new and gone hosts are listed with the host rather than
+//as opened or closed.
+#[derive(Serialize)]
+pub struct ScanDiff {
+    pub old: DiffSide,
+    pub new: DiffSide,
+    pub warnings: Vec<String>, //Why some of the changes below might not be real.
+    pub new_hosts: Vec<DiffHost>,
+    pub gone_hosts: Vec<DiffHost>,
+    pub unchecked_hosts: Vec<DiffHost>, //Hosts the new run didn't sweep, so it can't say they're gone.
+    pub hostname_changes: Vec<HostnameChange>,
+    pub opened_ports: Vec<PortChange>,
+    pub closed_ports: Vec<PortChange>,
+    pub service_changes: Vec<ServiceChange>,
+}
+
+//Which run one side of the diff is.
+#[derive(Serialize)]
+pub struct DiffSide {
+    pub source: String, //The results.json path or "<database> run <id>".
+    pub started: String,
+    pub hosts: usize,
+    pub incomplete: bool,
+}
+
+#[derive(Serialize)]
+pub struct DiffHost {
+    pub address: IpAddr,
+    pub hostname: Option<String>,
+    pub ports: Vec<String>, //"445/tcp"
+}
+
+#[derive(Serialize)]
+pub struct HostnameChange {
+    pub address: IpAddr,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PortChange {
+    pub address: IpAddr,
+    pub port: u32,
+    pub protocol: String,
+    pub service: String,
+    pub version: String,
+}
+
+#[derive(Serialize)]
+pub struct ServiceChange {
+    pub address: IpAddr,
+    pub port: u32,
+    pub protocol: String,
+    pub old_service: String,
+    pub old_version: String,
+    pub new_service: String,
+    pub new_version: String,
+}
+
+impl ScanDiff {
+    //True when the two runs found the same thing.
+    pub fn is_empty(&self) -> bool {
+        self.new_hosts.is_empty() && self.gone_hosts.is_empty() && self.hostname_changes.is_empty()
+            && self.opened_ports.is_empty() && self.closed_ports.is_empty() && self.service_changes.is_empty()
+    }
+}
+
+//DESCRIPTION: Compares two runs host by host and port by port.
+//TAKES: The older report, the newer one and what to call each of them.
+//RETURNS: Everything that changed, sorted by address then port. Ports the new run didn't scan
+//         aren't counted as closed, and hosts outside what a partial new run swept aren't counted as gone.
+pub fn diff_reports(old: &ScanReport, new: &ScanReport, old_source: &str, new_source: &str) -> ScanDiff {
+    let old_hosts: HashMap<IpAddr, &HostReport> = old.hosts.iter().map(|host| (host.address, host)).collect();
+    let new_hosts: HashMap<IpAddr, &HostReport> = new.hosts.iter().map(|host| (host.address, host)).collect();
+    //A stopped run or one limited to --known-subnets didn't sweep everything. Only the /24s it found
+    //hosts in are known to have been swept.
+    let known_subnets = new.run.command_line.iter().any(|arg| arg == "--known-subnets");
+    let partial_sweep = new.run.incomplete || known_subnets;
+    //run.ports is filled in from -P even when nothing was portscanned, the stages say what actually ran.
+    let new_tcp = new.run.stages.iter().any(|stage| stage == "portscan" || stage == "syn_scan");
+    let new_udp = new.run.stages.iter().any(|stage| stage == "udp_scan");
+    let mut diff = ScanDiff {
+        old: DiffSide { source: old_source.to_string(), started: old.run.started.clone(), hosts: old.hosts.len(), incomplete: old.run.incomplete },
+        new: DiffSide { source: new_source.to_string(), started: new.run.started.clone(), hosts: new.hosts.len(), incomplete: new.run.incomplete },
+        warnings: Vec::new(),
+        new_hosts: Vec::new(),
+        gone_hosts: Vec::new(),
+        unchecked_hosts: Vec::new(),
+        hostname_changes: Vec::new(),
+        opened_ports: Vec::new(),
+        closed_ports: Vec::new(),
+        service_changes: Vec::new(),
+    };
+
+    if old.run.incomplete {
+        diff.warnings.push(format!("The old run ({}) was stopped before it finished, hosts and ports it missed show up as new.", old_source));
+    }
+    if new.run.incomplete {
+        diff.warnings.push(format!("The new run ({}) was stopped before it finished, hosts it didn't get to aren't counted as gone.", new_source));
+    }
+    if known_subnets {
+        diff.warnings.push(format!("The new run ({}) only swept --known-subnets, hosts outside them aren't counted as gone.", new_source));
+    }
+
+    for host in old.hosts.iter().filter(|host| !new_hosts.contains_key(&host.address)) {
+        if partial_sweep && !new.subnets.contains(&subnet_of(&host.address)) {
+            diff.unchecked_hosts.push(diff_host(host));
+        }
+        else {
+            diff.gone_hosts.push(diff_host(host));
+        }
+    }
+    for new_host in new.hosts.iter() {
+        let Some(old_host) = old_hosts.get(&new_host.address) else {
+            diff.new_hosts.push(diff_host(new_host));
+            continue;
+        };
+        if old_host.hostname != new_host.hostname {
+            diff.hostname_changes.push(HostnameChange { address: new_host.address, old: old_host.hostname.clone(), new: new_host.hostname.clone() });
+        }
+        let old_ports: HashMap<(&str, u32), &PortReport> = old_host.ports.iter().map(|port| ((port.protocol.as_str(), port.port), port)).collect();
+        let new_ports: HashMap<(&str, u32), &PortReport> = new_host.ports.iter().map(|port| ((port.protocol.as_str(), port.port), port)).collect();
+        //A port the new run never scanned isn't closed, the new run just didn't look. A stopped run
+        //may not have got to a host with no ports at all.
+        let reached = !(new.run.incomplete && new_host.ports.is_empty());
+        let scanned = |port: &PortReport| reached && match port.protocol.as_str() {
+            "udp" => new_udp,
+            _ => new_tcp && new.run.ports.contains(&port.port),
+        };
+        for port in old_host.ports.iter().filter(|port| scanned(port) && !new_ports.contains_key(&(port.protocol.as_str(), port.port))) {
+            diff.closed_ports.push(port_change(new_host.address, port));
+        }
+        for new_port in new_host.ports.iter() {
+            match old_ports.get(&(new_port.protocol.as_str(), new_port.port)) {
+                None => diff.opened_ports.push(port_change(new_host.address, new_port)),
+                //A run without fingerprinting leaves these empty, that isn't the service changing.
+                Some(old_port) if !old_port.version.is_empty() && !new_port.version.is_empty()
+                    && (old_port.service != new_port.service || old_port.version != new_port.version) => {
+                    diff.service_changes.push(ServiceChange {
+                        address: new_host.address,
+                        port: new_port.port,
+                        protocol: new_port.protocol.clone(),
+                        old_service: old_port.service.clone(),
+                        old_version: old_port.version.clone(),
+                        new_service: new_port.service.clone(),
+                        new_version: new_port.version.clone(),
+                    });
+                },
+                Some(_) => {},
+            }
+        }
+    }
+
+    diff.new_hosts.sort_by_key(|host| host.address);
+    diff.gone_hosts.sort_by_key(|host| host.address);
+    diff.unchecked_hosts.sort_by_key(|host| host.address);
+    diff.hostname_changes.sort_by_key(|change| change.address);
+    diff.opened_ports.sort_by(|a, b| (a.address, a.port, &a.protocol).cmp(&(b.address, b.port, &b.protocol)));
+    diff.closed_ports.sort_by(|a, b| (a.address, a.port, &a.protocol).cmp(&(b.address, b.port, &b.protocol)));
+    diff.service_changes.sort_by(|a, b| (a.address, a.port, &a.protocol).cmp(&(b.address, b.port, &b.protocol)));
+    diff
+}
+
+//DESCRIPTION: Lays the diff out for reading in a terminal.
+//TAKES: The diff.
+//RETURNS: The text, one section per kind of change. Empty sections are left out.
+pub fn diff_text(diff: &ScanDiff) -> String {
+    let name = |hostname: &Option<String>| hostname.clone().unwrap_or("no_hostname".to_string());
+    let mut text = format!(
+        "Old: {} started {} ({} hosts)\nNew: {} started {} ({} hosts)\n",
+        diff.old.source, diff.old.started, diff.old.hosts, diff.new.source, diff.new.started, diff.new.hosts,
+    );
+    for warning in diff.warnings.iter() {
+        text.push_str(&format!("Warning: {}\n", warning));
+    }
+    if diff.is_empty() {
+        text.push_str("\nNo changes.\n");
+    }
+    let mut section = |title: &str, lines: Vec<String>| {
+        if !lines.is_empty() {
+            text.push_str(&format!("\n{} ({}):\n", title, lines.len()));
+            for line in lines {
+                text.push_str(&format!("    {}\n", line));
+            }
+        }
+    };
+    section("New hosts", diff.new_hosts.iter().map(|host| format!("{} {} {}", host.address, name(&host.hostname), host.ports.join(",")).trim_end().to_string()).collect());
+    section("Gone hosts", diff.gone_hosts.iter().map(|host| format!("{} {} {}", host.address, name(&host.hostname), host.ports.join(",")).trim_end().to_string()).collect());
+    section("Not swept by the new run", diff.unchecked_hosts.iter().map(|host| format!("{} {} {}", host.address, name(&host.hostname), host.ports.join(",")).trim_end().to_string()).collect());
+    section("Hostname changes", diff.hostname_changes.iter().map(|change| format!("{} {} -> {}", change.address, name(&change.old), name(&change.new))).collect());
+    section("Opened ports", diff.opened_ports.iter().map(port_line).collect());
+    section("Closed ports", diff.closed_ports.iter().map(port_line).collect());
+    section("Service changes", diff.service_changes.iter().map(|change| format!(
+        "{} {}/{} {} {} -> {} {}",
+        change.address, change.port, change.protocol, change.old_service, change.old_version, change.new_service, change.new_version,
+    )).collect());
+    text
+}
+
+//DESCRIPTION: A host that's only in one of the runs.
+//TAKES: The host.
+//RETURNS: The host with its ports as "445/tcp".
+fn diff_host(host: &HostReport) -> DiffHost {
+    let ports = host.ports.iter().map(|port| format!("{}/{}", port.port, port.protocol)).collect();
+    DiffHost { address: host.address, hostname: host.hostname.clone(), ports }
+}
+
+//DESCRIPTION: The /24 an address is in, written the way the report lists subnets.
+//TAKES: The address.
+//RETURNS: "10.1.2.0/24", or the address itself if it isn't IPv4.
+fn subnet_of(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])
+        },
+        IpAddr::V6(_) => address.to_string(),
+    }
+}
+
+//DESCRIPTION: A port that's only open in one of the runs.
+//TAKES: The host's address and the port.
+//RETURNS: The change.
+fn port_change(address: IpAddr, port: &PortReport) -> PortChange {
+    PortChange { address, port: port.port, protocol: port.protocol.clone(), service: port.service.clone(), version: port.version.clone() }
+}
+
+//DESCRIPTION: One opened or closed port for diff_text.
+//TAKES: The change.
+//RETURNS: "10.0.0.1 445/tcp microsoft-ds"
+fn port_line(change: &PortChange) -> String {
+    format!("{} {}/{} {} {}", change.address, change.port, change.protocol, change.service, change.version).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const PORTS: [u32; 5] = [22, 80, 443, 445, 3389];
+
+    //DESCRIPTION: Builds a report the way results.json lays it out.
+    //TAKES: The command line, whether it was stopped, its stages, the /24s with hosts and the hosts.
+    //RETURNS: The report.
+    fn report(command_line: &[&str], incomplete: bool, stages: &[&str], subnets: &[&str], hosts: serde_json::Value) -> ScanReport {
+        serde_json::from_value(json!({
+            "run": {
+                "version": "2.0.0",
+                "command_line": command_line,
+                "started": "2024-01-31 13:45:00",
+                "finished": "2024-01-31 14:02:11",
+                "duration_secs": 1031,
+                "stages": stages,
+                "ports": PORTS,
+                "incomplete": incomplete,
+            },
+            "targets": ["10.0.0.0/16"],
+            "exclusions": { "hosts": [], "subnets": [] },
+            "subnets": subnets,
+            "hosts": hosts,
+        })).expect("Test report didn't deserialize")
+    }
+
+    fn port(port: u32, protocol: &str, service: &str, version: &str) -> serde_json::Value {
+        json!({ "port": port, "protocol": protocol, "state": "open", "service": service, "version": version, "latency_ms": 2 })
+    }
+
+    fn old_report() -> ScanReport {
+        report(&["valk2", "10.0.0.0/16"], false, &["pingsweep", "portscan", "fingerprint", "udp_scan"], &["10.0.1.0/24", "10.0.2.0/24"], json!([
+            { "address": "10.0.1.5", "hostname": "dc01", "discovery": "rdns", "ports": [
+                port(445, "tcp", "microsoft-ds", "Microsoft Windows Server 2019"),
+                port(3389, "tcp", "ms-wbt-server", ""),
+                port(161, "udp", "snmp", ""),
+            ] },
+            { "address": "10.0.1.20", "hostname": "web", "discovery": "ping", "ports": [
+                port(22, "tcp", "ssh", "OpenSSH 8.2p1 Ubuntu 4ubuntu0.5"),
+                port(80, "tcp", "http", "nginx 1.18.0"),
+                port(8080, "tcp", "http-proxy", ""),
+            ] },
+            { "address": "10.0.1.40", "hostname": null, "discovery": "ping", "ports": [] },
+            { "address": "10.0.2.7", "hostname": "printer", "discovery": "ping", "ports": [port(80, "tcp", "http", "")] },
+        ]))
+    }
+
+    //Port 8080 and UDP weren't scanned, 3389 was and is gone. 10.0.1.40 and 10.0.2.7 didn't answer.
+    fn new_report(command_line: &[&str], incomplete: bool) -> ScanReport {
+        report(command_line, incomplete, &["pingsweep", "portscan", "fingerprint"], &["10.0.1.0/24"], json!([
+            { "address": "10.0.1.5", "hostname": "dc01.corp", "discovery": "rdns", "ports": [
+                port(445, "tcp", "microsoft-ds", "Microsoft Windows Server 2019"),
+            ] },
+            { "address": "10.0.1.20", "hostname": "web", "discovery": "ping", "ports": [
+                port(22, "tcp", "ssh", "OpenSSH 9.6p1 Ubuntu 3ubuntu13"),
+                port(80, "tcp", "http", "nginx 1.18.0"),
+                port(443, "tcp", "https", ""),
+            ] },
+            { "address": "10.0.1.30", "hostname": null, "discovery": "portscan", "ports": [port(22, "tcp", "ssh", "")] },
+        ]))
+    }
+
+    fn addresses(hosts: &[DiffHost]) -> Vec<String> {
+        hosts.iter().map(|host| host.address.to_string()).collect()
+    }
+
+    fn ports(changes: &[PortChange]) -> Vec<String> {
+        changes.iter().map(|change| format!("{} {}/{}", change.address, change.port, change.protocol)).collect()
+    }
+
+    #[test]
+    fn full_runs_report_every_change() {
+        let diff = diff_reports(&old_report(), &new_report(&["valk2", "10.0.0.0/16"], false), "old.json", "new.json");
+        assert!(diff.warnings.is_empty());
+        assert_eq!(addresses(&diff.new_hosts), vec!["10.0.1.30"]);
+        assert_eq!(addresses(&diff.gone_hosts), vec!["10.0.1.40", "10.0.2.7"]);
+        assert!(diff.unchecked_hosts.is_empty());
+        assert_eq!(diff.hostname_changes.len(), 1);
+        assert_eq!(diff.hostname_changes[0].new.as_deref(), Some("dc01.corp"));
+        assert_eq!(ports(&diff.opened_ports), vec!["10.0.1.20 443/tcp"]);
+        //8080/tcp and 161/udp weren't scanned by the new run, so they aren't closed.
+        assert_eq!(ports(&diff.closed_ports), vec!["10.0.1.5 3389/tcp"]);
+        assert_eq!(diff.service_changes.len(), 1);
+        assert_eq!(diff.service_changes[0].new_version, "OpenSSH 9.6p1 Ubuntu 3ubuntu13");
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn known_subnets_runs_leave_unswept_hosts_unchecked() {
+        let diff = diff_reports(&old_report(), &new_report(&["valk2", "--known-subnets", "10.0.0.0/16"], false), "old.json", "new.json");
+        assert_eq!(diff.warnings.len(), 1);
+        assert!(diff.warnings[0].contains("--known-subnets"));
+        //10.0.1.0/24 was swept, so 10.0.1.40 really is gone.
+        assert_eq!(addresses(&diff.gone_hosts), vec!["10.0.1.40"]);
+        assert_eq!(addresses(&diff.unchecked_hosts), vec!["10.0.2.7"]);
+    }
+
+    #[test]
+    fn incomplete_runs_are_warned_about() {
+        let mut old = old_report();
+        old.run.incomplete = true;
+        let diff = diff_reports(&old, &new_report(&["valk2", "10.0.0.0/16"], true), "old.json", "new.json");
+        assert_eq!(diff.warnings.len(), 2);
+        assert_eq!(addresses(&diff.gone_hosts), vec!["10.0.1.40"]);
+        assert_eq!(addresses(&diff.unchecked_hosts), vec!["10.0.2.7"]);
+        let text = diff_text(&diff);
+        assert!(text.contains("Warning: The old run (old.json) was stopped"));
+        assert!(text.contains("Warning: The new run (new.json) was stopped"));
+        assert!(text.contains("\nNot swept by the new run (1):\n    10.0.2.7 printer 80/tcp\n"));
+    }
+
+    #[test]
+    fn runs_without_a_portscan_close_nothing() {
+        //rDNS and pingsweep only, run.ports still has the -P default in it.
+        let new = report(&["valk2", "-r", "-w"], false, &["pingsweep"], &["10.0.1.0/24", "10.0.2.0/24"], json!([
+            { "address": "10.0.1.5", "hostname": "dc01", "discovery": "rdns", "ports": [] },
+            { "address": "10.0.1.20", "hostname": "web", "discovery": "ping", "ports": [] },
+        ]));
+        let diff = diff_reports(&old_report(), &new, "old.json", "new.json");
+        assert!(diff.closed_ports.is_empty(), "{:?}", ports(&diff.closed_ports));
+        assert_eq!(addresses(&diff.gone_hosts), vec!["10.0.1.40", "10.0.2.7"]);
+    }
+
+    #[test]
+    fn stopped_runs_close_nothing_on_hosts_they_didnt_reach() {
+        let new = report(&["valk2", "-p"], true, &["pingsweep", "portscan"], &["10.0.1.0/24"], json!([
+            { "address": "10.0.1.5", "hostname": "dc01", "discovery": "rdns", "ports": [] },
+            { "address": "10.0.1.20", "hostname": "web", "discovery": "ping", "ports": [port(22, "tcp", "ssh", "")] },
+        ]));
+        let diff = diff_reports(&old_report(), &new, "old.json", "new.json");
+        //10.0.1.5 has nothing so the scan may not have got to it, 10.0.1.20 was scanned and lost port 80.
+        assert_eq!(ports(&diff.closed_ports), vec!["10.0.1.20 80/tcp"]);
+    }
+
+    #[test]
+    fn identical_runs_have_no_changes() {
+        let diff = diff_reports(&old_report(), &old_report(), "old.json", "old.json");
+        assert!(diff.is_empty());
+        assert!(diff_text(&diff).ends_with("\nNo changes.\n"));
+    }
+
+    #[test]
+    fn truncated_reports_dont_load() {
+        let truncated = &serde_json::to_string(&old_report()).unwrap()[..200];
+        assert!(serde_json::from_str::<ScanReport>(truncated).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::format_timestamp;
+
+//Events come from every rDNS and portscan task, so the stream lives here instead of being handed
+//down through all of them like the host list is. Nothing is written until it's opened.
+static EVENT_STREAM: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+//Something the scan found or finished, one JSON object per line.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    HostFound { host: IpAddr, via: &'a str },                              //"rdns", "ping" or "portscan"
+    HostnameResolved { host: IpAddr, hostname: &'a str, source: &'a str }, //"rdns", "certificate" or "snmp"
+    PortOpen { host: IpAddr, port: u32, protocol: &'a str, latency_ms: u64 },
+    SubnetComplete { subnet: &'a str, has_hosts: bool },
+    PhaseComplete { phase: &'a str, secs: u64 },
+}
+
+#[derive(Serialize)]
+struct StampedEvent<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+//DESCRIPTION: Starts the event stream. Everything emitted after this is written straight away.
+//TAKES: The file to write to, or - for stdout, and whether to add to the end of it (a resumed run
+//       carries on the stream it stopped) instead of starting it over.
+//RETURNS: Nothing. Panics if the file can't be created.
+pub fn open_event_stream(path: &str, append: bool) {
+    let out: Box<dyn Write + Send> = match path {
+        "-" => Box::new(io::stdout()),
+        path => match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path) {
+            Err(e) => panic!("Couldn't Create {}: {}", path, e),
+            Ok(file) => Box::new(file),
+        },
+    };
+    if EVENT_STREAM.set(Mutex::new(out)).is_err() {
+        panic!("Event stream opened twice");
+    }
+}
+
+//DESCRIPTION: Writes one event to the stream and flushes it so anything tailing it sees it now.
+//TAKES: The event.
+//RETURNS: Nothing. Does nothing if the stream was never opened.
+pub fn emit(event: Event) {
+    let Some(out) = EVENT_STREAM.get() else { return };
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0);
+    let mut line = serde_json::to_string(&StampedEvent { time: format_timestamp(secs), event }).expect("Unable to serialize event");
+    line.push('\n');
+    let mut out = out.lock().unwrap();
+    out.write_all(line.as_bytes()).and_then(|_| out.flush()).expect("Unable to write to the event stream");
+}
@@ -0,0 +1,497 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use regex::bytes::{Regex, RegexBuilder};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::banner::{sanitize_banner, Banner};
+use crate::results::csv_field;
+
+//Probe database compiled into the binary. --service-probes swaps it for a file on disk.
+const DEFAULT_SERVICE_PROBES: &str = include_str!("../service-probes.txt");
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+//Wait this long for the first bytes of a response...
+const FIRST_READ_TIMEOUT: Duration = Duration::from_secs(3);
+//...then keep reading until the service goes quiet for this long.
+const NEXT_READ_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RESPONSE_LEN: usize = 8192;
+const MAX_FINGERPRINTS: usize = 128;
+
+//One Probe section of the probe file. NULL probes have an empty payload and just listen.
+pub struct ServiceProbe {
+    pub name: String,
+    pub payload: Vec<u8>,
+    pub ports: Vec<u32>,
+    pub matches: Vec<ServiceMatch>,
+}
+
+//A match or softmatch line. The templates can pull in capture groups with $1, $2...
+pub struct ServiceMatch {
+    pub service: String,
+    pub pattern: Regex,
+    pub soft: bool,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub info: Option<String>,
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub device_type: Option<String>,
+}
+
+//What we decided is running on a port.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub service: String,
+    pub product: String,
+    pub version: String,
+    pub info: String,
+    pub hostname: String,
+    pub os: String,
+    pub device_type: String,
+}
+
+impl ServiceInfo {
+    //"OpenSSH 8.9p1 (Ubuntu Linux; protocol 2.0)" style summary for printing.
+    pub fn summary(&self) -> String {
+        let mut summary = [self.product.as_str(), self.version.as_str()].iter()
+            .filter(|part| !part.is_empty())
+            .cloned()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        if !self.info.is_empty() {
+            if !summary.is_empty() {
+                summary.push(' ');
+            }
+            summary.push_str(&format!("({})", self.info));
+        }
+        summary
+    }
+}
+
+//DESCRIPTION: Loads the probe database from path, or the built in one if path is None.
+//TAKES: Optional path to a probe file.
+//RETURNS: The parsed probes in file order. Panics on a bad line like the exclusions file does.
+pub fn load_service_probes(path: Option<&str>) -> Vec<ServiceProbe> {
+    match path {
+        Some(path) => {
+            let contents = match fs::read_to_string(path) {
+                Err(e) => panic!("Couldn't Read {}: {}", path, e),
+                Ok(contents) => contents,
+            };
+            parse_service_probes(&contents, path)
+        },
+        None => parse_service_probes(DEFAULT_SERVICE_PROBES, "built in service probes"),
+    }
+}
+
+//DESCRIPTION: Parses probe file text. Understands the part of the nmap-service-probes format we use:
+//             Probe TCP <name> q|<payload>|, ports <list>, match/softmatch <service> m|<regex>|[si]
+//             followed by p/ v/ i/ h/ o/ d/ version fields. Everything else is skipped.
+//TAKES: The file contents and a name to put in error messages.
+//RETURNS: The probes in file order.
+pub fn parse_service_probes(contents: &str, source: &str) -> Vec<ServiceProbe> {
+    let mut probes: Vec<ServiceProbe> = Vec::new();
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match directive {
+            "Probe" => {
+                let mut parts = rest.splitn(3, ' ');
+                let proto = parts.next().unwrap_or("");
+                let name = parts.next().unwrap_or("");
+                let payload = parts.next().unwrap_or("");
+                if proto != "TCP" {
+                    //UDP probes live in udpscan, skip anything else in the file.
+                    continue;
+                }
+                let Some(('q', payload, _)) = split_delimited(payload) else {
+                    panic!("Something is wrong with the formatting of {}: Faulty Line {} >{}<", source, line_no + 1, line);
+                };
+                probes.push(ServiceProbe { name: name.to_string(), payload: unescape_payload(payload), ports: Vec::new(), matches: Vec::new() });
+            },
+            "ports" => {
+                let Some(probe) = probes.last_mut() else { continue };
+                probe.ports = parse_port_list(rest);
+            },
+            "match" | "softmatch" => {
+                let Some(probe) = probes.last_mut() else { continue };
+                match parse_match(rest, directive == "softmatch") {
+                    Some(service_match) => probe.matches.push(service_match),
+                    None => panic!("Something is wrong with the formatting of {}: Faulty Line {} >{}<", source, line_no + 1, line),
+                }
+            },
+            _ => continue, //rarity, totalwaitms, sslports and friends are not used.
+        }
+    }
+    probes
+}
+
+//DESCRIPTION: Parses "<service> m|<regex>|<flags> p/<product>/ v/<version>/ ..." off a match line.
+//TAKES: Everything after the match/softmatch keyword and whether it was a softmatch.
+//RETURNS: The match, or None if the line does not parse or the regex does not compile.
+fn parse_match(rest: &str, soft: bool) -> Option<ServiceMatch> {
+    let (service, rest) = rest.split_once(' ')?;
+    let ('m', pattern, mut rest) = split_delimited(rest)? else { return None };
+    //Regex flags sit right after the closing delimiter.
+    let flags: String = rest.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+    rest = &rest[flags.len()..];
+    let pattern = RegexBuilder::new(pattern)
+        .unicode(false)
+        .dot_matches_new_line(flags.contains('s'))
+        .case_insensitive(flags.contains('i'))
+        .build()
+        .ok()?;
+
+    let mut service_match = ServiceMatch {
+        service: service.to_string(),
+        pattern,
+        soft,
+        product: None,
+        version: None,
+        info: None,
+        hostname: None,
+        os: None,
+        device_type: None,
+    };
+    loop {
+        let field_text = rest.trim_start();
+        //cpe:/a:vendor:product/ has a four letter field name, step over "cpe" so ':' is the field.
+        let field_text = field_text.strip_prefix("cpe").unwrap_or(field_text);
+        let Some((field, value, after)) = split_delimited(field_text) else { break };
+        let value = Some(value.to_string());
+        match field {
+            'p' => service_match.product = value,
+            'v' => service_match.version = value,
+            'i' => service_match.info = value,
+            'h' => service_match.hostname = value,
+            'o' => service_match.os = value,
+            'd' => service_match.device_type = value,
+            _ => {}, //cpe: and anything else we don't track.
+        }
+        rest = after;
+    }
+    Some(service_match)
+}
+
+//DESCRIPTION: Splits "x|value|rest" into ('x', "value", "rest") for any delimiter character.
+//TAKES: The text starting at the field letter.
+//RETURNS: The field letter, the value between the delimiters, and whatever follows.
+fn split_delimited(text: &str) -> Option<(char, &str, &str)> {
+    let mut chars = text.char_indices();
+    let (_, field) = chars.next()?;
+    let (delim_at, delim) = chars.next()?;
+    let value_start = delim_at + delim.len_utf8();
+    let value_len = text[value_start..].find(delim)?;
+    let value = &text[value_start..value_start + value_len];
+    let rest = &text[value_start + value_len + delim.len_utf8()..];
+    Some((field, value, rest))
+}
+
+//DESCRIPTION: Turns the escapes allowed in a probe payload (\r \n \t \0 \\ \xNN) into bytes.
+//TAKES: The payload text from between q| and |.
+//RETURNS: The bytes to send.
+fn unescape_payload(payload: &str) -> Vec<u8> {
+    let bytes = payload.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 >= bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes[i + 1] {
+            b'r' => out.push(b'\r'),
+            b'n' => out.push(b'\n'),
+            b't' => out.push(b'\t'),
+            b'0' => out.push(0),
+            //Bad or cut off hex is kept as it was written.
+            b'x' => {
+                let hex = bytes.get(i + 2..i + 4).and_then(|hex| std::str::from_utf8(hex).ok()).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 4;
+                        continue;
+                    },
+                    Err(_) => out.extend_from_slice(b"\\x"),
+                }
+            },
+            other => out.push(other),
+        }
+        i += 2;
+    }
+    out
+}
+
+//DESCRIPTION: Parses "21,22,80-90" into a list of ports.
+//TAKES: The port list text.
+//RETURNS: Every port in it. Garbage entries are skipped.
+fn parse_port_list(list: &str) -> Vec<u32> {
+    let mut ports = Vec::new();
+    for part in list.split(',').map(|part| part.trim()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+                    ports.extend(start..=end);
+                }
+            },
+            None => {
+                if let Ok(port) = part.parse::<u32>() {
+                    ports.push(port);
+                }
+            },
+        }
+    }
+    ports
+}
+
+//DESCRIPTION: Fingerprints every open port and writes services.txt as
+//             ip,port,service,product,version,info,hostname,os,device_type. Fields with a comma or
+//             quote in them are quoted like CSV.
+//TAKES: The open (ip, port) pairs, the probe database, and banners already grabbed (these stand
+//       in for the NULL probe so we don't connect twice just to listen), and the run folder to write
+//       to, and the stop flag.
+//RETURNS: The identified services. Ports nothing matched come back as service "unknown".
+pub async fn fingerprint_services(open_ports: &[(IpAddr, u32)], probes: Arc<Vec<ServiceProbe>>, banners: &[Banner], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<ServiceInfo> {
+    let mut port_probes = Vec::with_capacity(open_ports.len());
+    for (host, port) in open_ports {
+        let probes = probes.clone();
+        let host = *host;
+        let port = *port;
+        let banner = banners.iter().find(|banner| banner.host == host && banner.port == port).cloned();
+        port_probes.push(async move {
+            fingerprint_service(host, port, &probes, banner.as_ref()).await
+        });
+    }
+    let answers = crate::run_probes(port_probes, MAX_FINGERPRINTS, stop).await;
+
+    let services_rfp = out_dir.join("services.txt");
+    let services_rf = match File::create(&services_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", services_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut services_buff = BufWriter::new(services_rf);
+
+    let mut services = Vec::with_capacity(answers.len());
+    for service in answers.into_iter().flatten() {
+        eprintln!("{}:{}/tcp {} {}", service.host, service.port, service.service, service.summary());
+        services_buff.write_all(format!(
+            "{},{},{},{},{},{},{},{},{}\n", service.host, service.port, csv_field(&service.service), csv_field(&service.product), csv_field(&service.version),
+            csv_field(&service.info), csv_field(&service.hostname), csv_field(&service.os), csv_field(&service.device_type),
+        ).as_bytes()).expect("Unable to write service to services.txt");
+        services.push(service);
+    }
+    services
+}
+
+//DESCRIPTION: Runs probes against one port until something hard matches. The NULL probe goes
+//             first, then the probes that list this port, then everything else so services on
+//             odd ports still get found.
+//TAKES: Host, port, the probe database and the banner if we already have one.
+//RETURNS: The best match. A softmatch is used if nothing hard matched.
+pub async fn fingerprint_service(host: IpAddr, port: u32, probes: &[ServiceProbe], banner: Option<&Banner>) -> ServiceInfo {
+    let null_probe = probes.iter().find(|probe| probe.payload.is_empty());
+    let mut ordered: Vec<&ServiceProbe> = Vec::with_capacity(probes.len());
+    ordered.extend(null_probe);
+    ordered.extend(probes.iter().filter(|probe| !probe.payload.is_empty() && probe.ports.contains(&port)));
+    ordered.extend(probes.iter().filter(|probe| !probe.payload.is_empty() && !probe.ports.contains(&port)));
+
+    let mut soft: Option<ServiceInfo> = None;
+    for probe in ordered {
+        let response = match banner {
+            //The banner grab already listened with nothing sent, reuse that answer.
+            Some(banner) if probe.payload.is_empty() && !banner.probed => Some(banner.raw.clone()),
+            //The banner grab had to poke it, so the NULL probe would just hear silence again.
+            Some(banner) if probe.payload.is_empty() && banner.probed => None,
+            _ => send_probe(host, port, &probe.payload).await,
+        };
+        let Some(response) = response else { continue };
+        if response.is_empty() {
+            continue;
+        }
+        //Try the probe's own matches and then the NULL ones, plenty of services say the same
+        //thing no matter what they were sent.
+        let candidates = probe.matches.iter().chain(null_probe.filter(|null| null.name != probe.name).into_iter().flat_map(|null| null.matches.iter()));
+        for service_match in candidates {
+            //Once softmatched only bother with matches for that service.
+            if let Some(soft) = &soft {
+                if soft.service != service_match.service {
+                    continue;
+                }
+            }
+            let Some(captures) = service_match.pattern.captures(&response) else { continue };
+            let fill = |template: &Option<String>| template.as_ref().map(|t| fill_template(t, &captures)).unwrap_or_default();
+            let service = ServiceInfo {
+                host,
+                port,
+                service: service_match.service.clone(),
+                product: fill(&service_match.product),
+                version: fill(&service_match.version),
+                info: fill(&service_match.info),
+                hostname: fill(&service_match.hostname),
+                os: fill(&service_match.os),
+                device_type: fill(&service_match.device_type),
+            };
+            if !service_match.soft {
+                return service;
+            }
+            if soft.is_none() {
+                soft = Some(service);
+            }
+        }
+    }
+    soft.unwrap_or(ServiceInfo {
+        host,
+        port,
+        service: "unknown".to_string(),
+        product: String::new(),
+        version: String::new(),
+        info: String::new(),
+        hostname: String::new(),
+        os: String::new(),
+        device_type: String::new(),
+    })
+}
+
+//DESCRIPTION: Swaps $1..$9 in a version template for the matching capture group.
+//TAKES: The template and the regex captures.
+//RETURNS: The filled in text, cleaned up the same way banners are.
+fn fill_template(template: &str, captures: &regex::bytes::Captures) -> String {
+    let mut filled: Vec<u8> = Vec::with_capacity(template.len());
+    let bytes = template.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'$' && i + 1 < bytes.len() && bytes[i + 1].is_ascii_digit() {
+            let group = (bytes[i + 1] - b'0') as usize;
+            if let Some(capture) = captures.get(group) {
+                filled.extend_from_slice(capture.as_bytes());
+            }
+            i += 2;
+            continue;
+        }
+        filled.push(bytes[i]);
+        i += 1;
+    }
+    sanitize_banner(&filled)
+}
+
+//DESCRIPTION: Opens a fresh connection, sends the payload (if any) and reads the reply.
+//TAKES: Host, port and the payload.
+//RETURNS: The response bytes, or None if we could not connect.
+async fn send_probe(host: IpAddr, port: u32, payload: &[u8]) -> Option<Vec<u8>> {
+    let socket = SocketAddr::new(host, port as u16);
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    if !payload.is_empty() {
+        stream.write_all(payload).await.ok()?;
+    }
+    let mut response = Vec::new();
+    let mut buf = [0u8; 2048];
+    let mut wait = FIRST_READ_TIMEOUT;
+    while response.len() < MAX_RESPONSE_LEN {
+        match timeout(wait, stream.read(&mut buf)).await {
+            Ok(Ok(len)) if len > 0 => response.extend_from_slice(&buf[..len]),
+            _ => break, //Closed, errored, or went quiet.
+        }
+        wait = NEXT_READ_TIMEOUT;
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //What OpenSSH on Ubuntu 22.04 says as soon as you connect.
+    const UBUNTU_SSH_BANNER: &[u8] = b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6\r\n";
+
+    //DESCRIPTION: A banner grabbed without having to send anything first.
+    //TAKES: What the service said.
+    //RETURNS: The banner.
+    fn quiet_banner(raw: &[u8]) -> Banner {
+        Banner { host: "10.0.0.1".parse().unwrap(), port: 22, raw: raw.to_vec(), text: sanitize_banner(raw), probed: false }
+    }
+
+    #[tokio::test]
+    async fn openssh_banner_matches_built_in_probes() {
+        let probes = load_service_probes(None);
+        let banner = quiet_banner(UBUNTU_SSH_BANNER);
+        //A hard match off the NULL probe, so nothing goes out on the network.
+        let service = fingerprint_service(banner.host, banner.port, &probes, Some(&banner)).await;
+        assert_eq!(service.service, "ssh");
+        assert_eq!(service.product, "OpenSSH");
+        assert_eq!(service.version, "8.9p1");
+        assert_eq!(service.info, "protocol 2.0");
+        assert_eq!(service.os, "Linux");
+        assert_eq!(service.summary(), "OpenSSH 8.9p1 (protocol 2.0)");
+    }
+
+    #[test]
+    fn parses_probe_ports_and_match_fields() {
+        let probes = parse_service_probes(r#"
+# Comments and directives we don't use are skipped.
+Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+rarity 1
+ports 80,8000-8002
+match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: Apache/([\d.]+) \(([^)]+)\)|s p/Apache httpd/ v/$1/ i/$2/ cpe:/a:apache:http_server:$1/
+softmatch http m|^HTTP/1\.[01] \d\d\d|i
+Probe UDP DNSStatusRequest q|\0\0\x10\0\0\0\0\0\0\0\0\0|
+"#, "test probes");
+        assert_eq!(probes.len(), 1, "UDP probes are left to udpscan");
+        let probe = &probes[0];
+        assert_eq!(probe.name, "GetRequest");
+        assert_eq!(probe.payload, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(probe.ports, vec![80, 8000, 8001, 8002]);
+        assert_eq!(probe.matches.len(), 2);
+
+        let apache = &probe.matches[0];
+        assert!(!apache.soft);
+        assert_eq!(apache.product.as_deref(), Some("Apache httpd"));
+        assert_eq!(apache.version.as_deref(), Some("$1"));
+        assert_eq!(apache.info.as_deref(), Some("$2"));
+        let response = b"HTTP/1.1 200 OK\r\nDate: Mon, 19 Oct 2026 07:00:00 GMT\r\nServer: Apache/2.4.41 (Ubuntu)\r\n\r\n";
+        let captures = apache.pattern.captures(response).expect("Apache reply didn't match");
+        assert_eq!(fill_template(apache.version.as_ref().unwrap(), &captures), "2.4.41");
+        assert_eq!(fill_template(apache.info.as_ref().unwrap(), &captures), "Ubuntu");
+        //Groups the pattern doesn't have come out empty.
+        assert_eq!(fill_template("$1/$7", &captures), "2.4.41/");
+
+        assert!(probe.matches[1].soft);
+        assert!(probe.matches[1].pattern.is_match(b"http/1.0 404 Not Found\r\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Faulty Line 2")]
+    fn truncated_match_line_panics() {
+        parse_service_probes("Probe TCP NULL q||\nmatch ssh m|^SSH-([\\d.]+)", "test probes");
+    }
+
+    #[test]
+    fn truncated_fields_and_escapes() {
+        assert_eq!(split_delimited("p/OpenSSH/ v/8.9/"), Some(('p', "OpenSSH", " v/8.9/")));
+        assert_eq!(split_delimited("p/OpenSSH"), None);
+        assert_eq!(split_delimited("p"), None);
+        assert!(parse_match("ssh m|^SSH-(|", false).is_none(), "Regex that doesn't compile");
+        assert_eq!(unescape_payload(r"\x41\r\n\0\\"), b"A\r\n\0\\");
+        //An escape cut off at the end of the payload is kept as it was written.
+        assert_eq!(unescape_payload(r"\x4"), b"\\x4");
+        assert_eq!(unescape_payload(r"\x"), b"\\x");
+        assert_eq!(unescape_payload("\\"), b"\\");
+        assert_eq!(unescape_payload(r"\xZZ"), b"\\xZZ");
+        assert_eq!(parse_port_list("22, 80-81,junk,90-x"), vec![22, 80, 81]);
+    }
+}
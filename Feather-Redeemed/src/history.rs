@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use rusqlite::{params, Connection};
+
+use crate::results::{Exclusions, HostEnrichment, HostReport, PortReport, RunInfo, ScanReport};
+
+//One row per run, everything else hangs off run_id. Enrichment is kept per host as the same JSON
+//that goes in results.json since every stage has its own shape.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    version TEXT NOT NULL,
+    command_line TEXT NOT NULL, -- JSON list of the arguments
+    started TEXT NOT NULL,
+    finished TEXT NOT NULL,
+    duration_secs INTEGER NOT NULL,
+    stages TEXT NOT NULL,
+    ports TEXT NOT NULL,
+    incomplete INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS targets (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    target TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS exclusions (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS subnets (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    subnet TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS hosts (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    address TEXT NOT NULL,
+    hostname TEXT,
+    discovery TEXT NOT NULL,
+    enrichment TEXT NOT NULL,
+    PRIMARY KEY (run_id, address)
+);
+CREATE TABLE IF NOT EXISTS ports (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    address TEXT NOT NULL,
+    protocol TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    service TEXT NOT NULL,
+    version TEXT NOT NULL,
+    latency_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS hosts_address ON hosts (address);
+CREATE INDEX IF NOT EXISTS ports_address ON ports (address, port);
+";
+
+//DESCRIPTION: Opens the history database, creating it and its tables if they aren't there yet.
+//TAKES: Path to the database file.
+//RETURNS: The connection. Panics if it can't be opened.
+pub fn open_history(path: &str) -> Connection {
+    let conn = match Connection::open(path) {
+        Err(e) => panic!("Couldn't Open {}: {}", path, e),
+        Ok(conn) => conn,
+    };
+    if let Err(e) = conn.execute_batch(SCHEMA) {
+        panic!("Couldn't Create the tables in {}: {}", path, e);
+    }
+    conn
+}
+
+//DESCRIPTION: Saves a whole run in one transaction.
+//TAKES: The connection and the report.
+//RETURNS: The new run's id.
+pub fn save_run(conn: &mut Connection, report: &ScanReport) -> rusqlite::Result<i64> {
+    let tx = conn.transaction()?;
+    let run = &report.run;
+    let ports: Vec<String> = run.ports.iter().map(|port| port.to_string()).collect();
+    tx.execute(
+        "INSERT INTO runs (version, command_line, started, finished, duration_secs, stages, ports, incomplete) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![run.version, serde_json::to_string(&run.command_line).expect("Unable to serialize the command line"), run.started, run.finished, run.duration_secs as i64, run.stages.join(","), ports.join(","), run.incomplete],
+    )?;
+    let run_id = tx.last_insert_rowid();
+    {
+        let mut insert_target = tx.prepare("INSERT INTO targets (run_id, target) VALUES (?1, ?2)")?;
+        for target in report.targets.iter() {
+            insert_target.execute(params![run_id, target])?;
+        }
+        let mut insert_exclusion = tx.prepare("INSERT INTO exclusions (run_id, kind, value) VALUES (?1, ?2, ?3)")?;
+        for host in report.exclusions.hosts.iter() {
+            insert_exclusion.execute(params![run_id, "host", host])?;
+        }
+        for subnet in report.exclusions.subnets.iter() {
+            insert_exclusion.execute(params![run_id, "subnet", subnet])?;
+        }
+        let mut insert_subnet = tx.prepare("INSERT INTO subnets (run_id, subnet) VALUES (?1, ?2)")?;
+        for subnet in report.subnets.iter() {
+            insert_subnet.execute(params![run_id, subnet])?;
+        }
+        let mut insert_host = tx.prepare("INSERT INTO hosts (run_id, address, hostname, discovery, enrichment) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        let mut insert_port = tx.prepare("INSERT INTO ports (run_id, address, protocol, port, state, service, version, latency_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?;
+        for host in report.hosts.iter() {
+            let address = host.address.to_string();
+            let enrichment = serde_json::to_string(&host.enrichment).expect("Unable to serialize enrichment");
+            insert_host.execute(params![run_id, address, host.hostname, host.discovery, enrichment])?;
+            for port in host.ports.iter() {
+                insert_port.execute(params![run_id, address, port.protocol, port.port, port.state, port.service, port.version, port.latency_ms as i64])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(run_id)
+}
+
+//DESCRIPTION: Every /24 that had hosts in any earlier run.
+//TAKES: The connection.
+//RETURNS: The subnets the way the sweep skips them, "10.1.2.0" with no mask.
+pub fn known_subnets(conn: &Connection) -> rusqlite::Result<HashSet<String>> {
+    let mut query = conn.prepare("SELECT DISTINCT subnet FROM subnets")?;
+    let subnets = query.query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(subnets.into_iter().map(|subnet| subnet.trim_end_matches("/24").to_string()).collect())
+}
+
+//DESCRIPTION: Reads a saved run back into a report. Enrichment stays in the database, the same
+//             as when a report is read back from results.json.
+//TAKES: The connection and the run's id.
+//RETURNS: The report, or the error if there's no such run.
+pub fn load_run(conn: &Connection, run_id: i64) -> rusqlite::Result<ScanReport> {
+    let split = |list: String| -> Vec<String> { list.split(',').filter(|item| !item.is_empty()).map(|item| item.to_string()).collect() };
+    let run = conn.query_row(
+        "SELECT version, command_line, started, finished, duration_secs, stages, ports, incomplete FROM runs WHERE id = ?1",
+        params![run_id],
+        |row| Ok(RunInfo {
+            version: row.get(0)?,
+            //Saved as a JSON list so arguments with spaces in them come back whole.
+            command_line: serde_json::from_str(&row.get::<_, String>(1)?)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e)))?,
+            started: row.get(2)?,
+            finished: row.get(3)?,
+            duration_secs: row.get::<_, i64>(4)? as u64,
+            stages: split(row.get(5)?),
+            ports: split(row.get(6)?).iter().filter_map(|port| port.parse().ok()).collect(),
+            incomplete: row.get(7)?,
+        }),
+    )?;
+    let column = |sql: &str| -> rusqlite::Result<Vec<String>> {
+        let mut query = conn.prepare(sql)?;
+        let values = query.query_map(params![run_id], |row| row.get(0))?.collect();
+        values
+    };
+    let targets = column("SELECT target FROM targets WHERE run_id = ?1 ORDER BY rowid")?;
+    let exclusions = Exclusions {
+        hosts: column("SELECT value FROM exclusions WHERE run_id = ?1 AND kind = 'host' ORDER BY value")?,
+        subnets: column("SELECT value FROM exclusions WHERE run_id = ?1 AND kind = 'subnet' ORDER BY value")?,
+    };
+    let subnets = column("SELECT subnet FROM subnets WHERE run_id = ?1 ORDER BY rowid")?;
+
+    let mut ports: HashMap<String, Vec<PortReport>> = HashMap::new();
+    let mut query = conn.prepare("SELECT address, protocol, port, state, service, version, latency_ms FROM ports WHERE run_id = ?1 ORDER BY protocol, port")?;
+    let rows = query.query_map(params![run_id], |row| {
+        let port = PortReport {
+            port: row.get(2)?,
+            protocol: row.get(1)?,
+            state: row.get(3)?,
+            service: row.get(4)?,
+            version: row.get(5)?,
+            latency_ms: row.get::<_, i64>(6)? as u64,
+        };
+        Ok((row.get::<_, String>(0)?, port))
+    })?;
+    for row in rows {
+        let (address, port) = row?;
+        ports.entry(address).or_default().push(port);
+    }
+
+    let mut hosts = Vec::new();
+    let mut query = conn.prepare("SELECT address, hostname, discovery FROM hosts WHERE run_id = ?1")?;
+    let rows = query.query_map(params![run_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, String>(2)?)))?;
+    for row in rows {
+        let (address, hostname, discovery) = row?;
+        let Ok(ip) = address.parse::<IpAddr>() else { continue };
+        let host_ports = ports.remove(&address).unwrap_or_default();
+        hosts.push(HostReport { address: ip, hostname, discovery, ports: host_ports, enrichment: HostEnrichment::default() });
+    }
+    hosts.sort_by_key(|host| host.address);
+    Ok(ScanReport { run, targets, exclusions, subnets, hosts })
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use crate::banner::sanitize_banner;
+use crate::tls;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+//Enough to get past the <head> of anything sane, and any favicon worth hashing.
+const MAX_BODY_LEN: usize = 512 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const MAX_HTTP_PROBES: usize = 64;
+
+static TITLE_PATTERN: OnceLock<Regex> = OnceLock::new();
+static ICON_LINK_PATTERN: OnceLock<Regex> = OnceLock::new();
+static HREF_PATTERN: OnceLock<Regex> = OnceLock::new();
+
+//Ports we treat as web servers when they are open.
+pub const HTTP_PORTS: &[u32] = &[80, 81, 591, 3000, 5000, 7001, 8000, 8008, 8080, 8081, 8088, 8888, 9000, 9090];
+pub const HTTPS_PORTS: &[u32] = &[443, 4443, 5001, 7443, 8443, 9443, 10443];
+
+//One request in the redirect chain. status is None for a redirect we didn't follow off the host.
+#[derive(Clone, Debug, Serialize)]
+pub struct HttpHop {
+    pub url: String,
+    pub status: Option<u16>,
+}
+
+//What a web port told us.
+#[derive(Clone, Debug, Serialize)]
+pub struct HttpInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub status: u16,
+    pub title: String,
+    pub server: String,
+    pub powered_by: String,
+    pub redirects: Vec<HttpHop>,
+    pub favicon_hash: Option<i32>,
+}
+
+//A parsed response.
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+//Where a request goes. host is only what goes in the Host header and URLs, we always connect to the IP.
+#[derive(Clone)]
+struct HttpTarget {
+    https: bool,
+    port: u32,
+    host: String,
+    path: String,
+}
+
+impl HttpTarget {
+    fn url(&self) -> String {
+        let scheme = if self.https {"https"} else {"http"};
+        format!("{}://{}:{}{}", scheme, self.host, self.port, self.path)
+    }
+}
+
+//DESCRIPTION: Requests / on every open web port, follows redirects that stay on the host and
+//             writes the status, title, headers, redirect chain and favicon hash to http.txt.
+//TAKES: The open (ip, port) pairs to check and the ip -> hostname list for Host headers,
+//       the run folder to write to and the stop flag.
+//RETURNS: What every web server said.
+pub async fn http_enrich(open_ports: &[(IpAddr, u32)], hostnames: &HashMap<String, String>, out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<HttpInfo> {
+    let connector = tls::insecure_tls_connector();
+    let mut probes = Vec::with_capacity(open_ports.len());
+    for (host, port) in open_ports {
+        let connector = connector.clone();
+        let host = *host;
+        let port = *port;
+        let hostname = hostnames.get(&host.to_string()).filter(|name| name.as_str() != "no_hostname").cloned();
+        probes.push(async move {
+            http_probe(&connector, host, port, hostname.as_deref()).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_HTTP_PROBES, stop).await;
+
+    let http_rfp = out_dir.join("http.txt");
+    let http_rf = match File::create(&http_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", http_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut http_buff = BufWriter::new(http_rf);
+
+    let mut results = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        let first_url = info.redirects.first().map(|hop| hop.url.as_str()).unwrap_or("");
+        eprintln!("{}:{} {} {}", info.host, info.port, info.status, info.title);
+        let chain: Vec<String> = info.redirects.iter().map(|hop| match hop.status {
+            Some(status) => format!("{} ({})", hop.url, status),
+            None => format!("{} (not followed)", hop.url),
+        }).collect();
+        let favicon = info.favicon_hash.map(|hash| hash.to_string()).unwrap_or_default();
+        let block = format!(
+            "{} {} {}\n    Server: {}\n    X-Powered-By: {}\n    Redirects: {}\n    Favicon mmh3: {}\n",
+            first_url, info.status, info.title, info.server, info.powered_by, chain.join(" -> "), favicon,
+        );
+        http_buff.write_all(block.as_bytes()).expect("Unable to write to http.txt");
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: Fetches / from one web port and walks the redirects.
+//TAKES: TLS connector, host, port and the hostname if we know one.
+//RETURNS: What we found, or None if the port never gave back an HTTP response.
+pub async fn http_probe(connector: &TlsConnector, host: IpAddr, port: u32, hostname: Option<&str>) -> Option<HttpInfo> {
+    let host_name = hostname.map(|name| name.to_string()).unwrap_or(host.to_string());
+    let mut target = HttpTarget { https: HTTPS_PORTS.contains(&port), port, host: host_name.clone(), path: "/".to_string() };
+    let mut hops: Vec<HttpHop> = Vec::new();
+    let mut responses: Vec<HttpResponse> = Vec::new();
+
+    for _ in 0..=MAX_REDIRECTS {
+        let Some(response) = http_get(connector, host, &target).await else { break };
+        hops.push(HttpHop { url: target.url(), status: Some(response.status) });
+        let location = response.header("Location").map(|location| location.to_string());
+        responses.push(response);
+        let Some(location) = location.filter(|_| (300..400).contains(&responses.last().unwrap().status)) else { break };
+        match resolve_redirect(&target, &location, host, &host_name) {
+            Some(next) => target = next,
+            None => {
+                //Off to some other host. Note where it wanted to go and stop there.
+                hops.push(HttpHop { url: location, status: None });
+                break;
+            },
+        }
+    }
+    let last = responses.last()?;
+
+    //Headers from the last hop that sent them, redirectors often leave them off.
+    let header_from_any = |name: &str| responses.iter().rev().find_map(|response| response.header(name)).map(sanitize_header).unwrap_or_default();
+    let body = String::from_utf8_lossy(&last.body);
+    let favicon_path = favicon_href(&body).unwrap_or("/favicon.ico".to_string());
+    let favicon_hash = match resolve_redirect(&target, &favicon_path, host, &host_name) {
+        Some(icon_target) => match http_get(connector, host, &icon_target).await {
+            Some(icon) if icon.status == 200 && !icon.body.is_empty() => Some(favicon_mmh3(&icon.body)),
+            _ => None,
+        },
+        None => None,
+    };
+
+    Some(HttpInfo {
+        host,
+        port,
+        status: last.status,
+        title: page_title(&body),
+        server: header_from_any("Server"),
+        powered_by: header_from_any("X-Powered-By"),
+        redirects: hops,
+        favicon_hash,
+    })
+}
+
+//DESCRIPTION: Works out where a Location header (or link href) points. Only targets on the same
+//             host come back, we never go chasing redirects off to other machines.
+//TAKES: The request it came from, the Location value, the IP and the name we are using for it.
+//RETURNS: The next request, or None if it leaves the host.
+fn resolve_redirect(current: &HttpTarget, location: &str, host: IpAddr, host_name: &str) -> Option<HttpTarget> {
+    let location = location.trim();
+    let (https, rest) = if let Some(rest) = location.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = location.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = location.strip_prefix("//") {
+        (current.https, rest)
+    } else if location.starts_with('/') {
+        return Some(HttpTarget { path: location.to_string(), ..current.clone() });
+    } else {
+        //Relative to the current directory.
+        let dir = &current.path[..current.path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        return Some(HttpTarget { path: format!("{}{}", if dir.is_empty() {"/"} else {dir}, location), ..current.clone() });
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (name, port) = match authority.rsplit_once(':').map(|(name, port)| (name, port.parse::<u32>())) {
+        Some((name, Ok(port))) => (name, port),
+        _ => (authority, if https {443} else {80}),
+    };
+    let same_host = name.eq_ignore_ascii_case(host_name) || name.eq_ignore_ascii_case(&host.to_string());
+    if !same_host {
+        return None;
+    }
+    Some(HttpTarget { https, port, host: name.to_string(), path: path.to_string() })
+}
+
+//DESCRIPTION: Sends one GET and reads the whole response.
+//TAKES: TLS connector, the IP to connect to and the request target.
+//RETURNS: The response, or None if the connection failed or it wasn't HTTP.
+async fn http_get(connector: &TlsConnector, host: IpAddr, target: &HttpTarget) -> Option<HttpResponse> {
+    let socket = SocketAddr::new(host, target.port as u16);
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    let default_port = if target.https {443} else {80};
+    let host_header = if target.port == default_port { target.host.clone() } else { format!("{}:{}", target.host, target.port) };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: Mozilla/5.0 (compatible; valk2)\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        target.path, host_header,
+    );
+    let raw = if target.https {
+        let sni = Some(target.host.as_str()).filter(|name| name.parse::<IpAddr>().is_err());
+        let tls_stream = tls::tls_wrap(connector, stream, host, sni).await?;
+        send_and_read(tls_stream, request.as_bytes()).await?
+    } else {
+        send_and_read(stream, request.as_bytes()).await?
+    };
+    parse_response(&raw)
+}
+
+//DESCRIPTION: Writes the request and reads until the server hangs up or we have enough.
+//TAKES: Any stream and the request bytes.
+//RETURNS: The raw response, or None if the write failed or nothing came back.
+async fn send_and_read<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> Option<Vec<u8>> {
+    stream.write_all(request).await.ok()?;
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 8192];
+    while raw.len() < MAX_BODY_LEN {
+        match timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(len)) if len > 0 => raw.extend_from_slice(&buf[..len]),
+            _ => break, //Closed, timed out, or a TLS close_notify we don't care about.
+        }
+    }
+    if raw.is_empty() { None } else { Some(raw) }
+}
+
+//DESCRIPTION: Splits a raw HTTP/1.x response into status, headers and (de-chunked) body.
+//TAKES: The raw bytes.
+//RETURNS: The response, or None if it doesn't start with an HTTP status line.
+fn parse_response(raw: &[u8]) -> Option<HttpResponse> {
+    let header_end = raw.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&raw[..header_end]);
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next()?;
+    if !status_line.starts_with("HTTP/") {
+        return None;
+    }
+    let status: u16 = status_line.split_whitespace().nth(1)?.parse().ok()?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut body = raw[header_end + 4..].to_vec();
+    let chunked = headers.iter().any(|(key, value)| key.eq_ignore_ascii_case("Transfer-Encoding") && value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        body = dechunk(&body);
+    }
+    Some(HttpResponse { status, headers, body })
+}
+
+//DESCRIPTION: Undoes chunked transfer encoding. Stops quietly at the first thing that doesn't parse
+//             since a cut off body is still good enough for a title.
+//TAKES: The chunked body.
+//RETURNS: The plain body.
+fn dechunk(body: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(body.len());
+    let mut at = 0;
+    while let Some(line_end) = body[at..].windows(2).position(|window| window == b"\r\n") {
+        let size_line = String::from_utf8_lossy(&body[at..at + line_end]);
+        let Ok(size) = usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16) else { break };
+        at += line_end + 2;
+        if size == 0 {
+            break;
+        }
+        let end = (at + size).min(body.len());
+        plain.extend_from_slice(&body[at..end]);
+        at = end + 2;
+        if at >= body.len() {
+            break;
+        }
+    }
+    plain
+}
+
+//DESCRIPTION: Pulls the <title> out of a page.
+//TAKES: The page body.
+//RETURNS: The title with entities decoded and whitespace squashed, empty if there isn't one.
+fn page_title(body: &str) -> String {
+    let title_pattern = TITLE_PATTERN.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+    let Some(captures) = title_pattern.captures(body) else { return String::new() };
+    let title = captures[1].split_whitespace().collect::<Vec<&str>>().join(" ");
+    let title = title.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&nbsp;", " ").replace("&amp;", "&");
+    sanitize_banner(title.as_bytes())
+}
+
+//DESCRIPTION: Finds the favicon a page links to with <link rel="icon" href="...">.
+//TAKES: The page body.
+//RETURNS: The href, or None if the page doesn't name one.
+fn favicon_href(body: &str) -> Option<String> {
+    let link_pattern = ICON_LINK_PATTERN.get_or_init(|| Regex::new(r#"(?is)<link[^>]+rel=["']?(?:shortcut )?icon["']?[^>]*>"#).unwrap());
+    let href_pattern = HREF_PATTERN.get_or_init(|| Regex::new(r#"(?is)href=["']?([^"' >]+)"#).unwrap());
+    let link = link_pattern.find(body)?;
+    let href = href_pattern.captures(link.as_str())?;
+    Some(href[1].to_string())
+}
+
+//DESCRIPTION: Header value made safe for the text output.
+//TAKES: The raw header value.
+//RETURNS: The cleaned value.
+fn sanitize_header(value: &str) -> String {
+    sanitize_banner(value.as_bytes())
+}
+
+//DESCRIPTION: Favicon hash the way Shodan does it, MurmurHash3 (x86 32 bit) of the base64 encoded
+//             icon with a newline every 76 characters, so hashes can be searched for there.
+//TAKES: The favicon bytes.
+//RETURNS: The signed 32 bit hash.
+pub fn favicon_mmh3(icon: &[u8]) -> i32 {
+    murmur3_32(base64_lines(icon).as_bytes(), 0) as i32
+}
+
+//DESCRIPTION: Base64 with a newline after every 76 characters and at the end.
+//TAKES: Bytes to encode.
+//RETURNS: The encoded text.
+fn base64_lines(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len() * 4 / 3 + data.len() / 57 + 4);
+    for line in data.chunks(57) {
+        encoded.push_str(&base64(line));
+        encoded.push('\n');
+    }
+    encoded
+}
+
+//DESCRIPTION: Plain base64 with padding.
+//TAKES: Data to encode.
+//RETURNS: The encoded string.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        encoded.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        encoded.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 { ALPHABET[(n >> 6) as usize & 63] as char } else { '=' });
+        encoded.push(if chunk.len() > 2 { ALPHABET[n as usize & 63] as char } else { '=' });
+    }
+    encoded
+}
+
+//DESCRIPTION: MurmurHash3 x86 32 bit.
+//TAKES: Data and seed.
+//RETURNS: The hash.
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mut hash = seed;
+    let blocks = data.chunks_exact(4);
+    let tail = blocks.remainder();
+    for block in blocks {
+        let mut k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let mut k: u32 = 0;
+    for (i, byte) in tail.iter().enumerate() {
+        k |= (*byte as u32) << (8 * i);
+    }
+    if !tail.is_empty() {
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+    }
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^= hash >> 16;
+    hash
+}
@@ -0,0 +1,345 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use crate::ber::{decode_integer, der, read_tlv};
+use crate::ntlm;
+use crate::tls;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+//A rootDSE is a few KB even on a big domain controller.
+const MAX_LDAP_MESSAGE: usize = 65536;
+const MAX_LDAP_PROBES: usize = 64;
+
+//Plain LDAP and the global catalog.
+pub const LDAP_PORTS: &[u32] = &[389, 3268];
+//LDAP over TLS.
+pub const LDAPS_PORTS: &[u32] = &[636];
+
+//rootDSE attributes we ask for.
+const ROOT_DSE_ATTRIBUTES: &[&str] = &[
+    "namingContexts", "defaultNamingContext", "dnsHostName",
+    "domainFunctionality", "forestFunctionality", "supportedSASLMechanisms",
+];
+
+//Result codes that answer the signing and channel binding checks.
+const LDAP_INVALID_CREDENTIALS: i64 = 49;
+const LDAP_STRONGER_AUTH_REQUIRED: i64 = 8;
+const LDAP_SASL_BIND_IN_PROGRESS: i64 = 14;
+
+//What an LDAP server said in its rootDSE and how it treated our binds.
+#[derive(Clone, Debug, Serialize)]
+pub struct LdapInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub naming_contexts: Vec<String>,
+    pub default_naming_context: String,
+    pub dns_host_name: String,
+    pub domain_functionality: String,
+    pub forest_functionality: String,
+    pub sasl_mechanisms: Vec<String>,
+    pub signing_required: Option<bool>,         //Plain ports only. None if the answer wasn't clear.
+    pub channel_binding_required: Option<bool>, //TLS ports only. None if the answer wasn't clear.
+}
+
+//DESCRIPTION: Reads the rootDSE from every open LDAP port and writes ldap.txt. Servers that
+//             don't require signing or channel binding also go in ldap_signing_not_required.txt
+//             and ldap_channel_binding_not_required.txt.
+//TAKES: The open LDAP ports as (host, port), the run folder to write to and the stop flag.
+//RETURNS: What every LDAP server said.
+pub async fn ldap_enrich(open_ldap: &[(IpAddr, u32)], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<LdapInfo> {
+    let connector = tls::insecure_tls_connector();
+    let mut probes = Vec::with_capacity(open_ldap.len());
+    for (host, port) in open_ldap {
+        let connector = connector.clone();
+        let (host, port) = (*host, *port);
+        probes.push(async move {
+            ldap_probe(&connector, host, port).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_LDAP_PROBES, stop).await;
+
+    let ldap_rfp = out_dir.join("ldap.txt");
+    let ldap_rf = match File::create(&ldap_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", ldap_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut ldap_buff = BufWriter::new(ldap_rf);
+    let no_signing_rfp = out_dir.join("ldap_signing_not_required.txt");
+    let no_signing_rf = match File::create(&no_signing_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", no_signing_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut no_signing_buff = BufWriter::new(no_signing_rf);
+    let no_binding_rfp = out_dir.join("ldap_channel_binding_not_required.txt");
+    let no_binding_rf = match File::create(&no_binding_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", no_binding_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut no_binding_buff = BufWriter::new(no_binding_rf);
+
+    let mut results = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        let enforced = |required: Option<bool>| match required {
+            Some(true) => "required",
+            Some(false) => "NOT required",
+            None => "unknown",
+        };
+        let mut block = format!(
+            "{}:{} {}\n    Naming Contexts: {}\n    Default Naming Context: {}\n    DNS Host Name: {}\n    Domain Functional Level: {}\n    Forest Functional Level: {}\n    SASL Mechanisms: {}\n",
+            info.host, info.port, info.dns_host_name, info.naming_contexts.join(", "), info.default_naming_context, info.dns_host_name,
+            info.domain_functionality, info.forest_functionality, info.sasl_mechanisms.join(", "),
+        );
+        //Each check only means anything on the one kind of port.
+        let check = if LDAPS_PORTS.contains(&info.port) {
+            block.push_str(&format!("    Channel Binding: {}\n", enforced(info.channel_binding_required)));
+            if info.channel_binding_required == Some(false) {
+                writeln!(no_binding_buff, "{}:{}", info.host, info.port).expect("Unable to write to ldap_channel_binding_not_required.txt");
+            }
+            format!("channel binding {}", enforced(info.channel_binding_required))
+        }
+        else {
+            block.push_str(&format!("    Signing: {}\n", enforced(info.signing_required)));
+            if info.signing_required == Some(false) {
+                writeln!(no_signing_buff, "{}:{}", info.host, info.port).expect("Unable to write to ldap_signing_not_required.txt");
+            }
+            format!("signing {}", enforced(info.signing_required))
+        };
+        eprintln!("{}:{} {} {} {}", info.host, info.port, info.dns_host_name, info.default_naming_context, check);
+        ldap_buff.write_all(block.as_bytes()).expect("Unable to write to ldap.txt");
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: Reads the rootDSE anonymously from one LDAP port, then binds with made up
+//             credentials to see whether it wants signing (plain ports) or channel binding (TLS).
+//TAKES: TLS connector, host and port.
+//RETURNS: What we found, or None if it didn't answer the search like an LDAP server.
+pub async fn ldap_probe(connector: &TlsConnector, host: IpAddr, port: u32) -> Option<LdapInfo> {
+    let socket = SocketAddr::new(host, port as u16);
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    if LDAPS_PORTS.contains(&port) {
+        let mut tls_stream = tls::tls_wrap(connector, stream, host, None).await?;
+        let mut info = root_dse(&mut tls_stream, host, port).await?;
+        info.channel_binding_required = channel_binding_check(&mut tls_stream).await;
+        Some(info)
+    }
+    else {
+        let mut stream = stream;
+        let mut info = root_dse(&mut stream, host, port).await?;
+        info.signing_required = signing_check(&mut stream).await;
+        Some(info)
+    }
+}
+
+//DESCRIPTION: Runs a base search of the empty DN, which every LDAP server answers without a bind.
+//TAKES: The stream, host and port.
+//RETURNS: The rootDSE attributes we care about, or None if no SearchResultDone came back.
+async fn root_dse<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: IpAddr, port: u32) -> Option<LdapInfo> {
+    let attributes: Vec<u8> = ROOT_DSE_ATTRIBUTES.iter().flat_map(|attribute| der(0x04, attribute.as_bytes())).collect();
+    let search = der(0x63, &[
+        der(0x04, b""),   //baseObject
+        der(0x0A, &[0]),  //scope baseObject
+        der(0x0A, &[0]),  //derefAliases never
+        der(0x02, &[0]),  //sizeLimit
+        der(0x02, &[0]),  //timeLimit
+        der(0x01, &[0]),  //typesOnly false
+        der(0x87, b"objectClass"), //(objectClass=*)
+        der(0x30, &attributes),
+    ].concat());
+    ldap_send(stream, 1, &search).await?;
+
+    let mut info = LdapInfo {
+        host,
+        port,
+        naming_contexts: Vec::new(),
+        default_naming_context: String::new(),
+        dns_host_name: String::new(),
+        domain_functionality: String::new(),
+        forest_functionality: String::new(),
+        sasl_mechanisms: Vec::new(),
+        signing_required: None,
+        channel_binding_required: None,
+    };
+    loop {
+        let (op, body) = ldap_read(stream).await?;
+        match op {
+            //SearchResultEntry: objectName then a sequence of (type, set of values).
+            0x64 => {
+                let mut at = 0;
+                read_tlv(&body, &mut at)?;
+                let (_, attributes) = read_tlv(&body, &mut at)?;
+                let mut at = 0;
+                while at < attributes.len() {
+                    let (_, attribute) = read_tlv(attributes, &mut at)?;
+                    let mut inner = 0;
+                    let (_, name) = read_tlv(attribute, &mut inner)?;
+                    let (_, set) = read_tlv(attribute, &mut inner)?;
+                    let mut values = Vec::new();
+                    let mut value_at = 0;
+                    while let Some((_, value)) = read_tlv(set, &mut value_at) {
+                        values.push(String::from_utf8_lossy(value).to_string());
+                    }
+                    let first = values.first().cloned().unwrap_or_default();
+                    match String::from_utf8_lossy(name).to_ascii_lowercase().as_str() {
+                        "namingcontexts" => info.naming_contexts = values,
+                        "defaultnamingcontext" => info.default_naming_context = first,
+                        "dnshostname" => info.dns_host_name = first,
+                        "domainfunctionality" => info.domain_functionality = functional_level(&first),
+                        "forestfunctionality" => info.forest_functionality = functional_level(&first),
+                        "supportedsaslmechanisms" => info.sasl_mechanisms = values,
+                        _ => {},
+                    }
+                }
+            },
+            0x65 => return Some(info),
+            _ => {}, //References and anything else
+        }
+    }
+}
+
+//DESCRIPTION: Simple binds with a made up user and password. A DC that requires signing turns
+//             down any simple bind over plain LDAP before it looks at the password.
+//TAKES: The stream, after the rootDSE search.
+//RETURNS: Some(true) if it asked for stronger auth, Some(false) if it just said the credentials
+//         were wrong, None for anything else.
+async fn signing_check<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Option<bool> {
+    let bind = der(0x60, &[der(0x02, &[3]), der(0x04, b"valk2"), der(0x80, b"valk2")].concat());
+    ldap_send(stream, 2, &bind).await?;
+    let (code, _, _) = bind_response(stream).await?;
+    match code {
+        LDAP_STRONGER_AUTH_REQUIRED => Some(true),
+        LDAP_INVALID_CREDENTIALS => Some(false),
+        _ => None,
+    }
+}
+
+//DESCRIPTION: Runs an NTLM SASL bind over TLS with an AUTHENTICATE that carries no channel
+//             bindings. A DC that enforces them says so (80090346) before it checks the
+//             password, one that doesn't just says the credentials were wrong (52e).
+//TAKES: The TLS stream, after the rootDSE search.
+//RETURNS: Some(true) if it wanted channel bindings, Some(false) if it didn't, None for anything else.
+async fn channel_binding_check<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Option<bool> {
+    let sasl_bind = |credentials: &[u8]| der(0x60, &[
+        der(0x02, &[3]),
+        der(0x04, b""),
+        der(0xA3, &[der(0x04, b"GSS-SPNEGO"), der(0x04, credentials)].concat()),
+    ].concat());
+    ldap_send(stream, 2, &sasl_bind(&ntlm::spnego_ntlm_negotiate())).await?;
+    let (code, _, server_creds) = bind_response(stream).await?;
+    if code != LDAP_SASL_BIND_IN_PROGRESS {
+        return None;
+    }
+    //SPNEGO NegTokenResp { responseToken AUTHENTICATE }
+    let authenticate = ntlm::ntlm_bogus_authenticate(&server_creds)?;
+    ldap_send(stream, 3, &sasl_bind(&der(0xA1, &der(0x30, &der(0xA2, &der(0x04, &authenticate)))))).await?;
+    let (code, message, _) = bind_response(stream).await?;
+    let message = message.to_ascii_lowercase();
+    if code == LDAP_INVALID_CREDENTIALS && message.contains("80090346") {
+        Some(true)
+    }
+    else if code == LDAP_INVALID_CREDENTIALS && message.contains("data 52e") {
+        Some(false)
+    }
+    else {
+        None
+    }
+}
+
+//DESCRIPTION: Reads a BindResponse.
+//TAKES: The stream.
+//RETURNS: The result code, the diagnostic message and the server SASL credentials (empty if none).
+async fn bind_response<S: AsyncRead + Unpin>(stream: &mut S) -> Option<(i64, String, Vec<u8>)> {
+    let (op, body) = ldap_read(stream).await?;
+    if op != 0x61 {
+        return None;
+    }
+    let mut at = 0;
+    let (_, code) = read_tlv(&body, &mut at)?;
+    read_tlv(&body, &mut at)?; //matchedDN
+    let (_, message) = read_tlv(&body, &mut at)?;
+    let mut server_creds = Vec::new();
+    while let Some((tag, value)) = read_tlv(&body, &mut at) {
+        if tag == 0x87 {
+            server_creds = value.to_vec();
+        }
+    }
+    Some((decode_integer(code), String::from_utf8_lossy(message).to_string(), server_creds))
+}
+
+//DESCRIPTION: Wraps a protocol op in an LDAPMessage and sends it.
+//TAKES: The stream, the message id and the encoded op.
+//RETURNS: Some(()) if it went, None if the write failed.
+async fn ldap_send<S: AsyncWrite + Unpin>(stream: &mut S, message_id: u8, op: &[u8]) -> Option<()> {
+    let message = der(0x30, &[der(0x02, &[message_id]), op.to_vec()].concat());
+    stream.write_all(&message).await.ok()?;
+    stream.flush().await.ok()
+}
+
+//DESCRIPTION: Reads one whole LDAPMessage. The length has to be read first to know how much is coming.
+//TAKES: The stream.
+//RETURNS: The protocol op tag and its content, or None if it isn't LDAP or takes too long.
+async fn ldap_read<S: AsyncRead + Unpin>(stream: &mut S) -> Option<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    timeout(READ_TIMEOUT, stream.read_exact(&mut head)).await.ok()?.ok()?;
+    if head[0] != 0x30 {
+        return None;
+    }
+    let mut message = head.to_vec();
+    let length = if head[1] & 0x80 == 0 {
+        head[1] as usize
+    } else {
+        let count = (head[1] & 0x7F) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let mut length_bytes = vec![0u8; count];
+        timeout(READ_TIMEOUT, stream.read_exact(&mut length_bytes)).await.ok()?.ok()?;
+        message.extend_from_slice(&length_bytes);
+        length_bytes.iter().fold(0usize, |length, byte| length << 8 | *byte as usize)
+    };
+    if length > MAX_LDAP_MESSAGE {
+        return None;
+    }
+    let start = message.len();
+    message.resize(start + length, 0);
+    timeout(READ_TIMEOUT, stream.read_exact(&mut message[start..])).await.ok()?.ok()?;
+
+    let (_, content) = read_tlv(&message, &mut 0)?;
+    let mut at = 0;
+    read_tlv(content, &mut at)?; //messageID
+    let (op, body) = read_tlv(content, &mut at)?;
+    Some((op, body.to_vec()))
+}
+
+//DESCRIPTION: Names a domain or forest functional level.
+//TAKES: The number from the rootDSE.
+//RETURNS: The Windows Server version it goes with and the number, or the number alone if we don't know it.
+fn functional_level(level: &str) -> String {
+    let name = match level {
+        "0" => "Windows 2000",
+        "1" => "Windows Server 2003 Interim",
+        "2" => "Windows Server 2003",
+        "3" => "Windows Server 2008",
+        "4" => "Windows Server 2008 R2",
+        "5" => "Windows Server 2012",
+        "6" => "Windows Server 2012 R2",
+        "7" => "Windows Server 2016",
+        "10" => "Windows Server 2025",
+        _ => return level.to_string(),
+    };
+    format!("{} ({})", name, level)
+}
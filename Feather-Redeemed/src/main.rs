@@ -1,16 +1,44 @@
-use clap::{Parser};
+use clap::{Parser, Subcommand, ValueEnum};
 use dns_lookup::lookup_addr;
 use std::{time::Duration, fs::File, fs};
-use std::net::{IpAddr, TcpStream, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::collections::{HashMap,HashSet};
-use std::path::Path;
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
 use std::io::{self, BufRead, BufWriter, Write};
 use std::env::current_dir;
 use regex::Regex;
 use tokio::task::JoinSet;
+use tokio::sync::Semaphore;
+use std::future::Future;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use pnet::datalink;
 
+mod banner;
+mod ber;
+mod checkpoint;
+mod classify;
+mod database;
+mod diff;
+mod events;
+mod fingerprint;
+mod history;
+mod http;
+mod ldap;
+mod nmapxml;
+mod ntlm;
+mod osfingerprint;
+mod rdp;
+mod report;
+mod results;
+mod smb;
+mod snmp;
+mod ssh;
+mod synscan;
+mod tls;
+mod udpscan;
+
 
 //Bling it out.
 const BANNER: &str = "
@@ -39,7 +67,7 @@ struct Cli {
     #[arg(short = 's', action, default_value="A", default_missing_value="A", help = "Specify Subnet in CIDR notation or A for all private subnets.")]
     subnets: String,
 
-    #[arg(short = 'p', help = "Using this flag enables portscanning of all addresses in subnets (a /24 address block) with hosts in them. Scans 80,443,445")]
+    #[arg(short = 'p', help = "Using this flag enables portscanning of all addresses in subnets (a /24 address block) with hosts in them. Scans the ports given with -P (80,443,445 by default).")]
     portscan: bool,
     
     #[arg(short = 'e', default_value="exclusions.txt", default_missing_value="exclusions.txt", help = "File of excluded hosts and subnets. (10.0.0.1 or 10.1.1.0/24) \nIf no -e flag specified. exclusions.txt will be used. \nWill auto exclude interfaces on the scanning computer.")]
@@ -48,8 +76,228 @@ struct Cli {
     #[arg(short = 'w', long = "ping", help = "Enable pingsweeps. WARNING: VERY SLOW RIGHT NOW.")]
     pingsweeps: bool,
 
-    //#[arg(short = 'u', long = "udp", help = "Enable UDP scanning over TCP.")]
-    //udp_enabled: bool,
+    #[arg(short = 'S', long = "syn", help = "Use raw socket SYN (half-open) scans when portscanning. \nNeeds root or CAP_NET_RAW, falls back to connect scans without it.")]
+    syn_scan: bool,
+
+    #[arg(short = 'u', long = "udp", help = "Enable UDP scanning of common services when portscanning. \nDNS, TFTP, NTP, NetBIOS-NS, SNMP, IKE, SSDP and mDNS. Open ports go to udp_<port>.txt")]
+    udp_enabled: bool,
+
+    #[arg(short = 'P', long = "ports", value_delimiter = ',', default_value = "80,443,445", value_parser = clap::value_parser!(u16).range(1..), help = "Comma separated TCP ports to portscan. Each open port goes to <port>.txt")]
+    ports: Vec<u16>,
+
+    #[arg(short = 'b', long = "banners", help = "Grab banners from open TCP ports after portscanning. Saved to banners.txt")]
+    banners: bool,
+
+    #[arg(short = 'f', long = "fingerprint", help = "Identify the service, product and version on open TCP ports. Turns on -b. \nSaved to services.txt")]
+    fingerprint: bool,
+
+    #[arg(long = "service-probes", value_name = "FILE", help = "Probe and match file to fingerprint with instead of the built in one. \nSame layout as service-probes.txt")]
+    service_probes: Option<String>,
+
+    #[arg(short = 'O', long = "os", help = "Guess each host's OS family from the TTL, window and TCP options in the replies to SYN scans \n(-S) and pingsweeps (-w), with a confidence score. Saved to os.txt. Needs root or \nCAP_NET_RAW to see the replies.")]
+    os_fingerprint: bool,
+
+    #[arg(long = "os-signatures", value_name = "FILE", help = "Signature file to guess OSes with instead of the built in one. \nSame layout as os-signatures.txt")]
+    os_signatures: Option<String>,
+
+    #[arg(short = 't', long = "tls", help = "Grab TLS certificates, protocol and cipher from open HTTPS and other TLS ports. \nSaved to tls.txt. Names on the certificate are used for hosts with no PTR record.")]
+    tls: bool,
+
+    #[arg(short = 'H', long = "http", help = "Request / from open web ports and record the status, title, Server and X-Powered-By headers, \nredirects and favicon hash. Saved to http.txt")]
+    http: bool,
+
+    #[arg(short = 'm', long = "smb", help = "Negotiate with hosts that have 445 open to get their SMB dialects, SMBv1 and signing status, \nand the computer and domain names from NTLM. Saved to smb.txt, hosts that don't \nrequire signing also go in smb_signing_not_required.txt. Adds 445 to the ports scanned.")]
+    smb: bool,
+
+    #[arg(short = 'k', long = "ssh", help = "Run the SSH key exchange against open SSH ports to record the banner, offered algorithms and host \nkey fingerprints. Saved to ssh.txt, keys seen on more than one host go in \nssh_shared_keys.txt. Adds 22 to the ports scanned.")]
+    ssh: bool,
+
+    #[arg(short = 'd', long = "rdp", help = "Negotiate with hosts that have 3389 open to get the RDP security protocols, whether NLA is \nenforced, the TLS certificate, and the computer and domain names from CredSSP. Saved to \nrdp.txt, hosts that don't enforce NLA also go in rdp_nla_not_required.txt. \nAdds 3389 to the ports scanned.")]
+    rdp: bool,
+
+    #[arg(short = 'l', long = "ldap", help = "Read the rootDSE anonymously from hosts with 389, 636 or 3268 open to get the naming \ncontexts, DNS host name, functional levels and SASL mechanisms, and check whether LDAP signing \nand channel binding are enforced. Saved to ldap.txt, servers that don't enforce them \nalso go in ldap_signing_not_required.txt and ldap_channel_binding_not_required.txt. \nAdds 389, 636 and 3268 to the ports scanned.")]
+    ldap: bool,
+
+    #[arg(short = 'D', long = "databases", help = "Identify MSSQL, MySQL, PostgreSQL, Redis and MongoDB on their default ports from what they \nsay before login, and ask SQL Browser (UDP 1434) on hosts with anything open for their SQL \nServer instances. Saved to databases.txt. Adds 1433, 3306, 5432, 6379 and 27017 to the \nports scanned.")]
+    databases: bool,
+
+    #[arg(short = 'n', long = "snmp", help = "Try SNMPv1 and v2c communities against every host found and GET sysDescr, sysObjectID, \nsysContact and sysName. Saved to snmp.txt. sysName is used for hosts with no PTR record.")]
+    snmp: bool,
+
+    #[arg(long = "snmp-communities", value_name = "FILE", help = "Community wordlist for --snmp, one per line. public and private by default.")]
+    snmp_communities: Option<String>,
+
+    #[arg(short = 'c', long = "classify", help = "Tag every host with a device category (domain controller, hypervisor, printer, NAS, camera, \nnetwork gear, workstation) from its open ports, banners, HTTP titles, certificates, SNMP \nsysObjectID, OS guess and MAC. Uses whatever the other enabled stages found. Saved to \nclassification.txt.")]
+    classify: bool,
+
+    #[arg(long = "classification-rules", value_name = "FILE", help = "Rule file to classify with instead of the built in one. \nSame layout as classification-rules.txt")]
+    classification_rules: Option<String>,
+
+    #[arg(short = 'j', long = "json", help = "Also save the whole run as one JSON document in results.json: run details, targets, \nexclusions, and every host with its hostname, how it was found, open ports and whatever the \nenrichment stages found on it.")]
+    json: bool,
+
+    #[arg(short = 'x', long = "xml", help = "Also save the run as nmap XML (the same layout as nmap -oX) in results.xml: hosts, \naddresses, hostnames, and open ports with their state and service. Loads into anything that \ntakes nmap XML, like Metasploit's db_import.")]
+    xml: bool,
+
+    #[arg(long = "format", value_enum, value_delimiter = ',', default_value = "text", help = "How to write the hosts and open ports, comma separated for more than one. text is \nip_hostname.txt, up_ips.txt and one <port>.txt per port. csv is \nresults.csv, a row per open port with the hostname, state and service. grep is \nresults.gnmap, a line per host laid out like nmap -oG.")]
+    format: Vec<OutputFormat>,
+
+    #[arg(long = "history", value_name = "FILE", help = "Save every run to a SQLite database (created if it isn't there): runs, targets, exclusions, \nsubnets with hosts, hosts with their hostnames, open ports and enrichment, so runs against the \nsame network can be queried across months.")]
+    history: Option<String>,
+
+    #[arg(long = "known-subnets", requires = "history", help = "Only sweep the /24s that had hosts in earlier runs saved in --history instead of the whole \nprivate space.")]
+    known_subnets: bool,
+
+    #[arg(long = "events", value_name = "FILE", help = "Stream what the scan finds as it goes, one JSON object a line: host-found, hostname-resolved, \nport-open, subnet-complete and phase-complete. Written to FILE as they happen so it can be \ntailed, or - for stdout (the progress messages all go to stderr).")]
+    events: Option<String>,
+
+    #[arg(long = "output-dir", value_name = "DIR", default_value = "output", help = "Where results go. Each run gets its own folder in here named after when it started and what \nit scanned, like output/20250131T134500Z_private.")]
+    output_dir: String,
+
+    #[arg(long = "no-run-folder", help = "Write results straight into --output-dir instead of a folder per run.")]
+    no_run_folder: bool,
+
+    #[arg(long = "overwrite", help = "Write into a folder that already has results in it. Files this run writes replace the \nold ones, anything else in there is left alone. Without it valk2 stops before scanning.")]
+    overwrite: bool,
+
+    #[arg(long = "resume", value_name = "STATE", exclusive = true, help = "Carry on a run that was stopped or killed, from the state.json in its folder. Runs with the \nflags it was started with, skips the /24s it already swept, and uses its portscan results if \nthat finished. The stages after the portscan run again.")]
+    resume: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+//Things valk2 does with results it already has instead of scanning.
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Compare two runs: new and gone hosts, hostname changes, opened and closed ports and changed \nservice versions. Each run is a results.json (-j), or a run id in --history.")]
+    Diff {
+        #[arg(help = "The earlier run, a results.json or a run id.")]
+        old: String,
+
+        #[arg(help = "The later run, a results.json or a run id.")]
+        new: String,
+
+        #[arg(long = "history", value_name = "FILE", help = "History database to read the run ids from. Without it both runs are results.json files.")]
+        history: Option<String>,
+
+        #[arg(long = "json", help = "Print the changes as JSON instead of text.")]
+        json: bool,
+    },
+
+    #[command(about = "Render a run as one self-contained HTML page to attach to a deliverable: summary, hosts per \nsubnet, a sortable host table with hostnames and open ports, services and versions, and the \nexclusions applied. The run is a results.json (-j), or a run id in --history.")]
+    Report {
+        #[arg(help = "The run, a results.json or a run id.")]
+        run: String,
+
+        #[arg(long = "history", value_name = "FILE", help = "History database to read the run id from. Without it the run is a results.json file.")]
+        history: Option<String>,
+
+        #[arg(short = 'o', long = "output", value_name = "FILE", default_value = "report.html", help = "Where to write the page.")]
+        output: String,
+    },
+}
+
+//The ways hosts and open ports can be written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Csv,
+    Grep,
+}
+
+//Everything the scanning stages need out of the command line.
+struct ScanOptions {
+    portscan: bool,
+    pingsweep: bool,
+    syn_scan: bool,
+    udp_scan: bool,
+    banners: bool,
+    ports: Vec<u32>,
+    service_probes: Option<Arc<Vec<fingerprint::ServiceProbe>>>, //Only loaded when fingerprinting.
+    os_signatures: Option<Vec<osfingerprint::OsSignature>>, //Only loaded when OS fingerprinting.
+    tls: bool,
+    http: bool,
+    smb: bool,
+    ssh: bool,
+    rdp: bool,
+    ldap: bool,
+    databases: bool,
+    snmp_communities: Option<Arc<Vec<String>>>, //Only loaded when SNMP is enabled.
+    classification_rules: Option<Vec<classify::ClassificationRule>>, //Only loaded when classifying.
+    json: bool,
+    xml: bool,
+    formats: Vec<OutputFormat>,
+    history: Option<String>,
+    known_subnets: Option<HashSet<String>>, //Only sweep these when set, "10.1.2.0" with no mask.
+    output_dir: PathBuf, //This run's folder, every output file goes in here.
+    resume: Option<checkpoint::Checkpoint>, //Where a stopped run got to, when resuming one.
+    stop: Arc<AtomicBool>, //Set by Ctrl-C or SIGTERM. Nothing new gets scheduled once it is.
+}
+
+impl ScanOptions {
+    //Names of the stages that are on, for the run details in the outputs.
+    fn stages(&self) -> Vec<String> {
+        let stages = [
+            ("pingsweep", self.pingsweep), ("portscan", self.portscan), ("syn_scan", self.syn_scan), ("udp_scan", self.udp_scan),
+            ("banners", self.banners), ("fingerprint", self.service_probes.is_some()), ("os_fingerprint", self.os_signatures.is_some()),
+            ("tls", self.tls), ("http", self.http), ("smb", self.smb), ("ssh", self.ssh), ("rdp", self.rdp), ("ldap", self.ldap),
+            ("databases", self.databases), ("snmp", self.snmp_communities.is_some()), ("classify", self.classification_rules.is_some()),
+        ];
+        stages.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()).collect()
+    }
+
+    //True once the run has been told to stop.
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+//What came back from probing a single port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PortState {
+    Open,        //Handshake completed.
+    Closed,      //Got a RST back. Nothing listening but the host is alive.
+    Filtered,    //Nothing came back before the timeout.
+    OpenFiltered,//UDP only. No answer and no ICMP error, could be either.
+    Unreachable, //ICMP host or network unreachable.
+    Error,       //Anything else the OS threw at us.
+}
+
+impl PortState {
+    //Open and Closed both need something on the other end to answer.
+    fn host_is_up(&self) -> bool {
+        matches!(self, PortState::Open | PortState::Closed)
+    }
+
+    //What the outputs call the state, same words nmap uses.
+    fn name(&self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::OpenFiltered => "open|filtered",
+            PortState::Unreachable => "unreachable",
+            PortState::Error => "error",
+        }
+    }
+
+    //Back from name(), for port results read back in.
+    fn from_name(name: &str) -> Option<PortState> {
+        [PortState::Open, PortState::Closed, PortState::Filtered, PortState::OpenFiltered, PortState::Unreachable, PortState::Error]
+            .into_iter()
+            .find(|state| state.name() == name)
+    }
+}
+
+//Result of a single port probe. Latency is how long the probe took to get its answer.
+#[derive(Clone, Copy, Debug)]
+struct PortResult {
+    host: IpAddr,
+    port: u32,
+    proto: &'static str, //"tcp" or "udp"
+    state: PortState,
+    latency: Duration,
 }
 
 type Db = Arc<Mutex<HashMap<String,String>>>;
@@ -57,33 +305,52 @@ type Sbool = Arc<Mutex<bool>>;
 
 //GLOBAL VARIABLES
 const MAX_OCTET: i32 =255 ; //Set this to 255 when ready for the full program.
+const INCOMPLETE_FILE: &str = "INCOMPLETE"; //Left in the run folder when a run is stopped early.
+const DRAIN_DEADLINE: Duration = Duration::from_secs(10); //How long probes in flight get to finish after a stop.
+const MAX_CONNECT_PROBES: usize = 512; //Connect scans open at once, well under the usual 1024 file limit.
 
 #[tokio::main]
 async fn main() {
     let starttime = std::time::Instant::now();
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    if let Some(command) = cli.command {
+        run_command(command);
+        return;
+    }
+    //A resumed run goes with the flags it was first started with.
+    let resume = cli.resume.as_deref().map(checkpoint::load_checkpoint);
+    if let Some(checkpoint) = &resume {
+        //Relative paths in those flags (-e, --history, --events...) are from where it was started.
+        if let Err(e) = std::env::set_current_dir(&checkpoint.cwd) {
+            panic!("Couldn't go back to {} where the run was started: {}", checkpoint.cwd.display(), e);
+        }
+        cli = Cli::parse_from(&checkpoint.args);
+    }
     let cidr_pattern = Regex::new(r"^(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)($|/(8|16|24))?$").unwrap();
     let ip_pattern = Regex::new(r"^(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.(25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)$").unwrap();
     let exclu_filename = cli.exclusions;
     let mut working_dir = current_dir().expect("Getting Current Directory Errored.");
     //PUT FANCY BANNER HERE LOL//
 
-    println!("{}",BANNER);
-    println!("Use the flag -h for help with flags and commands.");
+    eprintln!("{}",BANNER);
+    eprintln!("Use the flag -h for help with flags and commands.");
 
 
     //==============Setting Up Exclusions========================//
     //Check for exclusions file. If it does not exists we exit! Need exclusions!
-    println!("Using exclude file {}.", exclu_filename); //DEBUGGING
+    eprintln!("Using exclude file {}.", exclu_filename); //DEBUGGING
     
     let mut subnet_exclusions_list: HashSet<String> = HashSet::new();
     let mut ip_exclusions_list: HashSet<String> = HashSet::new();
 
-    //Create Output Folder
-    match fs::create_dir_all("output") {
-        Err(e) => panic!("Failed to Create Dir: {}", e),
-        Ok(_) => {},
-    }
+    //Create Output Folder. Done before anything else so a folder with old results in it stops us
+    //before the exclusions prompt.
+    let target_label = if cli.subnets == "A" { "private".to_string() } else { cli.subnets.replace('/', "_") };
+    let output_dir = match &resume {
+        //Back into the folder the stopped run was writing to.
+        Some(checkpoint) => prepare_output_dir(&checkpoint.output_dir.to_string_lossy(), &target_label, false, true),
+        None => prepare_output_dir(&cli.output_dir, &target_label, !cli.no_run_folder, cli.overwrite),
+    };
     
     //Check and Make sure exclusions.txt or whatever specified file does exist.
     working_dir.push(&exclu_filename);
@@ -124,13 +391,13 @@ async fn main() {
         if ip_exclusions_list.len() == 0 && subnet_exclusions_list.len() == 0 {
             let mut input = String::new();
             
-            println!("!!!-No Exclusions found in exclusions.txt before adding device intefaces.\nIf this is intentional type 'y' if not go check exclusions and type 'n'");
-            print!("Would you like to continue: ");
-            io::stdout().flush().unwrap(); //Have to flush to make the buffered write data actually output.
+            eprintln!("!!!-No Exclusions found in exclusions.txt before adding device intefaces.\nIf this is intentional type 'y' if not go check exclusions and type 'n'");
+            eprint!("Would you like to continue: ");
+            io::stderr().flush().unwrap(); //Have to flush to make the buffered write data actually output.
 
             io::stdin().read_line(&mut input).expect("Failed to read line");
             match input.trim() {
-                "y" => println!("Continuing On"),
+                "y" => eprintln!("Continuing On"),
                 "n" => panic!("Canceling. Go check exclusions and come back."),
                 _ => panic!("INVALID::Your response is not a mundane detail Michael!"),
             }
@@ -145,21 +412,21 @@ async fn main() {
     }
 
     //DEBUG
-    println!("\n<<====Excluding the Following Hosts and Subnets====>>");
-    println!("Hosts:");
+    eprintln!("\n<<====Excluding the Following Hosts and Subnets====>>");
+    eprintln!("Hosts:");
     for item in &ip_exclusions_list {
         eprintln!("{}",item);
     }
-    println!("\nSubnets:");
+    eprintln!("\nSubnets:");
     for item2 in &subnet_exclusions_list {
         eprintln!("{}/24", item2); //All skipped subnets will be a /24 as of right now. Maybe
         //consier a subnet list for each one: /24 /16 /8.
     }
-    println!("\n\n");
+    eprintln!("\n\n");
     
 
     //Flag Check
-    println!("<<======Flag Check======>>");
+    eprintln!("<<======Flag Check======>>");
     //DEBUGGING Say whether pingsweeps are enabled   
     if cli.pingsweeps {
         eprintln!("[x] Pingsweeps Enabled");
@@ -174,6 +441,216 @@ async fn main() {
     else {
         eprintln!("[ ] Portscanning Disabled");
     }
+    //DEBUGGING Say whether SYN scanning is enabled.
+    if cli.syn_scan {
+        eprintln!("[x] SYN Scanning Enabled");
+    }
+    else {
+        eprintln!("[ ] SYN Scanning Disabled");
+    }
+    //DEBUGGING Say whether UDP scanning is enabled.
+    if cli.udp_enabled {
+        eprintln!("[x] UDP Scanning Enabled");
+    }
+    else {
+        eprintln!("[ ] UDP Scanning Disabled");
+    }
+    //DEBUGGING Say whether banner grabbing is enabled.
+    if cli.banners || cli.fingerprint {
+        eprintln!("[x] Banner Grabbing Enabled");
+    }
+    else {
+        eprintln!("[ ] Banner Grabbing Disabled");
+    }
+    //DEBUGGING Say whether fingerprinting is enabled. Load the probes now so a bad file fails early.
+    let mut service_probes = None;
+    if cli.fingerprint {
+        let probes = fingerprint::load_service_probes(cli.service_probes.as_deref());
+        eprintln!("[x] Service Fingerprinting Enabled ({} probes)", probes.len());
+        service_probes = Some(Arc::new(probes));
+    }
+    else {
+        eprintln!("[ ] Service Fingerprinting Disabled");
+    }
+    //DEBUGGING Say whether OS fingerprinting is enabled. Load the signatures now so a bad file fails early.
+    let mut os_signatures = None;
+    if cli.os_fingerprint {
+        let signatures = osfingerprint::load_os_signatures(cli.os_signatures.as_deref());
+        eprintln!("[x] OS Fingerprinting Enabled ({} signatures)", signatures.len());
+        if !cli.syn_scan && !cli.pingsweeps {
+            eprintln!("    OS fingerprinting only sees replies from SYN scans (-S) and pingsweeps (-w), turn one on.");
+        }
+        os_signatures = Some(signatures);
+    }
+    else {
+        eprintln!("[ ] OS Fingerprinting Disabled");
+    }
+    //DEBUGGING Say whether SNMP is enabled. Load the wordlist now so a bad path fails early.
+    let mut snmp_communities = None;
+    if cli.snmp {
+        let communities = snmp::load_snmp_communities(cli.snmp_communities.as_deref());
+        eprintln!("[x] SNMP Enabled ({} communities)", communities.len());
+        snmp_communities = Some(Arc::new(communities));
+    }
+    else {
+        eprintln!("[ ] SNMP Disabled");
+    }
+    //DEBUGGING Say whether classification is enabled. Load the rules now so a bad file fails early.
+    let mut classification_rules = None;
+    if cli.classify {
+        let rules = classify::load_classification_rules(cli.classification_rules.as_deref());
+        eprintln!("[x] Device Classification Enabled ({} rules)", rules.len());
+        classification_rules = Some(rules);
+    }
+    else {
+        eprintln!("[ ] Device Classification Disabled");
+    }
+    //DEBUGGING Say whether runs are being saved. Open it now so a bad path fails early, and pick up
+    //the /24s earlier runs found if we're only sweeping those.
+    let mut known_subnets = None;
+    if let Some(path) = &cli.history {
+        let conn = history::open_history(path);
+        eprintln!("[x] Scan History Enabled ({})", path);
+        if cli.known_subnets {
+            let subnets = history::known_subnets(&conn).unwrap_or_else(|e| panic!("Couldn't Read the subnets in {}: {}", path, e));
+            eprintln!("    Only sweeping the {} /24s with hosts in earlier runs.", subnets.len());
+            known_subnets = Some(subnets);
+        }
+    }
+    else {
+        eprintln!("[ ] Scan History Disabled");
+    }
+    let mut ports: Vec<u32> = cli.ports.iter().map(|port| *port as u32).collect();
+    if cli.smb && !ports.contains(&smb::SMB_PORT) {
+        ports.push(smb::SMB_PORT);
+    }
+    if cli.ssh && !ports.contains(&22) {
+        ports.push(22);
+    }
+    if cli.rdp && !ports.contains(&rdp::RDP_PORT) {
+        ports.push(rdp::RDP_PORT);
+    }
+    if cli.ldap {
+        for port in ldap::LDAP_PORTS.iter().chain(ldap::LDAPS_PORTS) {
+            if !ports.contains(port) {
+                ports.push(*port);
+            }
+        }
+    }
+    if cli.databases {
+        for port in database::DATABASE_PORTS {
+            if !ports.contains(port) {
+                ports.push(*port);
+            }
+        }
+    }
+    let scan_opts = ScanOptions {
+        portscan: cli.portscan,
+        pingsweep: cli.pingsweeps,
+        syn_scan: cli.syn_scan,
+        udp_scan: cli.udp_enabled,
+        banners: cli.banners || cli.fingerprint,
+        ports,
+        service_probes,
+        os_signatures,
+        tls: cli.tls,
+        http: cli.http,
+        smb: cli.smb,
+        ssh: cli.ssh,
+        rdp: cli.rdp,
+        ldap: cli.ldap,
+        databases: cli.databases,
+        snmp_communities,
+        classification_rules,
+        json: cli.json,
+        xml: cli.xml,
+        formats: cli.format.clone(),
+        history: cli.history.clone(),
+        known_subnets,
+        output_dir,
+        resume,
+        stop: Arc::new(AtomicBool::new(false)),
+    };
+    //DEBUGGING Say whether TLS certificate grabbing is enabled.
+    if cli.tls {
+        eprintln!("[x] TLS Certificate Grabbing Enabled");
+    }
+    else {
+        eprintln!("[ ] TLS Certificate Grabbing Disabled");
+    }
+    //DEBUGGING Say whether HTTP enrichment is enabled.
+    if cli.http {
+        eprintln!("[x] HTTP Enrichment Enabled");
+    }
+    else {
+        eprintln!("[ ] HTTP Enrichment Disabled");
+    }
+    //DEBUGGING Say whether SMB enrichment is enabled.
+    if cli.smb {
+        eprintln!("[x] SMB Enrichment Enabled");
+    }
+    else {
+        eprintln!("[ ] SMB Enrichment Disabled");
+    }
+    //DEBUGGING Say whether the SSH inventory is enabled.
+    if cli.ssh {
+        eprintln!("[x] SSH Inventory Enabled");
+    }
+    else {
+        eprintln!("[ ] SSH Inventory Disabled");
+    }
+    //DEBUGGING Say whether RDP enrichment is enabled.
+    if cli.rdp {
+        eprintln!("[x] RDP Enrichment Enabled");
+    }
+    else {
+        eprintln!("[ ] RDP Enrichment Disabled");
+    }
+    //DEBUGGING Say whether LDAP enrichment is enabled.
+    if cli.ldap {
+        eprintln!("[x] LDAP Enrichment Enabled");
+    }
+    else {
+        eprintln!("[ ] LDAP Enrichment Disabled");
+    }
+    //DEBUGGING Say whether database identification is enabled.
+    if cli.databases {
+        eprintln!("[x] Database Identification Enabled");
+    }
+    else {
+        eprintln!("[ ] Database Identification Disabled");
+    }
+    //DEBUGGING Say whether the event stream is on. Open it now so a bad path fails early.
+    if let Some(path) = &cli.events {
+        events::open_event_stream(path, scan_opts.resume.is_some());
+        eprintln!("[x] Event Stream Enabled ({})", path);
+    }
+    else {
+        eprintln!("[ ] Event Stream Disabled");
+    }
+    //DEBUGGING Say whether the JSON document is being written.
+    if cli.json {
+        eprintln!("[x] JSON Output Enabled");
+    }
+    else {
+        eprintln!("[ ] JSON Output Disabled");
+    }
+    //DEBUGGING Say whether the nmap XML is being written.
+    if cli.xml {
+        eprintln!("[x] Nmap XML Output Enabled");
+    }
+    else {
+        eprintln!("[ ] Nmap XML Output Disabled");
+    }
+    //DEBUGGING Say how hosts and ports are being written.
+    let format_names: Vec<String> = cli.format.iter().map(|format| format!("{:?}", format).to_lowercase()).collect();
+    eprintln!("[x] Output Formats: {}", format_names.join(", "));
+    //DEBUGGING Say where a resumed run left off.
+    if let Some(checkpoint) = &scan_opts.resume {
+        eprintln!("[x] Resuming: {} /24s already swept, {} hosts found", checkpoint.completed_subnets.len(), checkpoint.hosts.len());
+    }
+    //Ctrl-C or SIGTERM from here on stops the scan and writes out what it has.
+    watch_for_stop(scan_opts.stop.clone());
     //=================RDNS and Pingsweeps================//
     //Perform rDNS searching for subnets with vaild hosts in them. 
     //Make a list of hostnames discovered and a list of subents (/24) with hosts in it.
@@ -183,8 +660,8 @@ async fn main() {
         if cli.subnets =="A"{
             eprintln!("\n\n<<=======Reverse DNS Scanning=======>>");
             eprintln!("  Scanning Entire Private Subnet Space");
-            eprintln!("  Output will be saved in ~/output/");
-            rdns_and_ping_full_private(cli.portscan,cli.pingsweeps,subnet_exclusions_list,ip_exclusions_list).await;
+            eprintln!("  Output will be saved in {}", scan_opts.output_dir.display());
+            rdns_and_ping_full_private(&scan_opts,subnet_exclusions_list,ip_exclusions_list).await;
         }               
         else {
             if cli.subnets.len()== 0{
@@ -209,16 +686,45 @@ async fn main() {
 //DESCRIPTION:
 //TAKES:
 //RETURNS:
-async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut sub_ex_list: HashSet<String>, mut ip_ex_list: HashSet<String>) {
-    let mut subnets_with_hosts:Vec<String> = Vec::new();
-    let list_of_hosts = Arc::new(Mutex::new(HashMap::new()));
+async fn rdns_and_ping_full_private(scan_opts: &ScanOptions, mut sub_ex_list: HashSet<String>, mut ip_ex_list: HashSet<String>) {
+    //Progress is saved as the run goes. A resumed run starts from where the stopped one got to.
+    let mut checkpoint = match &scan_opts.resume {
+        Some(checkpoint) => checkpoint.clone(),
+        None => checkpoint::Checkpoint::new(std::time::SystemTime::now(), &scan_opts.output_dir),
+    };
+    let started = checkpoint.started();
+    //The skips below eat the exclusion lists, keep them as given for the outputs.
+    let mut exclusions = results::Exclusions {
+        hosts: ip_ex_list.iter().cloned().collect(),
+        subnets: sub_ex_list.iter().map(|subnet| format!("{}/24", subnet)).collect(),
+    };
+    exclusions.hosts.sort();
+    exclusions.subnets.sort();
+    let en_pingsweep = scan_opts.pingsweep;
+    let mut subnets_with_hosts:Vec<String> = checkpoint.subnets_with_hosts.clone();
+    let list_of_hosts: Db = Arc::new(Mutex::new(checkpoint.hosts.clone().into_iter().collect()));
     let copy_ip_ex_list: HashSet<String>  = ip_ex_list.clone(); 
+    let os_observations: osfingerprint::OsObservations = Arc::new(Mutex::new(HashMap::new()));
+    //Pick up the TTL of ping replies while the sweep runs.
+    let mut icmp_listener = None;
+    if scan_opts.os_signatures.is_some() && en_pingsweep {
+        match osfingerprint::IcmpListener::start(os_observations.clone()) {
+            Ok(listener) => icmp_listener = Some(listener),
+            Err(e) => eprintln!("Can't watch ping replies for OS fingerprinting ({}). Need root or CAP_NET_RAW.", e),
+        }
+    }
     let rdns_time = std::time::Instant::now();
     //======================= rDNS Sweeping 10.0.0.0/8 ==========================================//
     for second_octet in 0..=MAX_OCTET {
+        if scan_opts.stopping() {
+            break;
+        }
         let ten_slash_8_time = std::time::Instant::now();
         eprintln!("    Scanning Subnet: 10.{}.0.0/16", second_octet);    
         for third_octet in 0..=MAX_OCTET {
+            if scan_opts.stopping() {
+                break;
+            }
             //Check if we need to skip SUBNET
             let subnet = format!("10.{}.{}.0",second_octet,third_octet);
             if sub_ex_list.len() > 0 && sub_ex_list.contains(&subnet) {
@@ -226,6 +732,14 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                 sub_ex_list.remove(&subnet);
                 continue;
             }
+            //Only the /24s that had hosts in earlier runs when asked to.
+            if scan_opts.known_subnets.as_ref().is_some_and(|known| !known.contains(&subnet)) {
+                continue;
+            }
+            //Already swept before the run was stopped.
+            if checkpoint.completed_subnets.contains(&subnet) {
+                continue;
+            }
                
             
             let mut tasks = JoinSet::new();
@@ -248,14 +762,22 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                     rdns_and_ping_ip(ip_addr_to_test, list_of_hosts_clone, subnet_has_hosts_clone, en_pingsweep).await;
                 });
             }
-            //Should wait for all spawned tasks to complete.
-            tasks.join_all().await;
+            //Should wait for all spawned tasks to complete, or as many as finish in time when stopping.
+            let (_, subnet_complete) = drain_tasks(tasks, &scan_opts.stop).await;
 
             let subnet_has_hosts_clone = subnet_has_hosts.clone(); 
             let mut subnet_value = subnet_has_hosts_clone.lock().unwrap(); 
-            if *subnet_value {
-                subnets_with_hosts.push(format!("10.{}.{}.0/24", second_octet, third_octet));
-                *subnet_value = false;
+            if subnet_complete {
+                events::emit(events::Event::SubnetComplete { subnet: &format!("10.{}.{}.0/24", second_octet, third_octet), has_hosts: *subnet_value });
+            }
+            //A /24 cut short by a stop is swept again on resume, it only goes in the list once.
+            let subnet_cidr = format!("10.{}.{}.0/24", second_octet, third_octet);
+            if *subnet_value && !subnets_with_hosts.contains(&subnet_cidr) {
+                subnets_with_hosts.push(subnet_cidr);
+            }
+            *subnet_value = false;
+            if subnet_complete {
+                checkpoint.subnet_swept(&subnet, &subnets_with_hosts, &list_of_hosts);
             }
             //if (third_octet%4 == 0){
             //    thread::sleep(Duration::from_secs(1));
@@ -268,9 +790,15 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
     //========================= rDNS Sweeping 172.16.0.0/12 =========================//
     //Chaing the second_octect to match the CIDR Priavate Subnet Range Convention.
     for second_octet in 16..31 {
+        if scan_opts.stopping() {
+            break;
+        }
         let one72_slash_12_time = std::time::Instant::now();
         eprintln!("    Scanning Subnet: 172.{}.0.0/16", second_octet);
         for third_octet in 0..=MAX_OCTET {
+            if scan_opts.stopping() {
+                break;
+            }
             //Check if we need to skip SUBNET
             let subnet = format!("172.{}.{}.0",second_octet,third_octet); 
             if sub_ex_list.len() > 0 && sub_ex_list.contains(&subnet) {
@@ -278,6 +806,14 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                 sub_ex_list.remove(&subnet);
                 continue;
             } 
+            //Only the /24s that had hosts in earlier runs when asked to.
+            if scan_opts.known_subnets.as_ref().is_some_and(|known| !known.contains(&subnet)) {
+                continue;
+            }
+            //Already swept before the run was stopped.
+            if checkpoint.completed_subnets.contains(&subnet) {
+                continue;
+            }
             
             let mut tasks = JoinSet::new();
             let subnet_has_hosts = Arc::new(Mutex::new(false));
@@ -299,14 +835,22 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                     rdns_and_ping_ip(ip_addr_to_test, list_of_hosts_clone, subnet_has_hosts_clone, en_pingsweep).await;
                 });
             }
-            //Should wait for all spawned tasks to complete.
-            tasks.join_all().await;
+            //Should wait for all spawned tasks to complete, or as many as finish in time when stopping.
+            let (_, subnet_complete) = drain_tasks(tasks, &scan_opts.stop).await;
 
             let subnet_has_hosts_clone = subnet_has_hosts.clone(); 
             let mut subnet_value = subnet_has_hosts_clone.lock().unwrap(); 
-            if *subnet_value {
-                subnets_with_hosts.push(format!("172.{}.{}.0/24", second_octet, third_octet));
-                *subnet_value = false;
+            if subnet_complete {
+                events::emit(events::Event::SubnetComplete { subnet: &format!("172.{}.{}.0/24", second_octet, third_octet), has_hosts: *subnet_value });
+            }
+            //A /24 cut short by a stop is swept again on resume, it only goes in the list once.
+            let subnet_cidr = format!("172.{}.{}.0/24", second_octet, third_octet);
+            if *subnet_value && !subnets_with_hosts.contains(&subnet_cidr) {
+                subnets_with_hosts.push(subnet_cidr);
+            }
+            *subnet_value = false;
+            if subnet_complete {
+                checkpoint.subnet_swept(&subnet, &subnets_with_hosts, &list_of_hosts);
             }
         }
         eprintln!("The Subnet 172.{}.0.0/16 took {} seconds to complete.", second_octet, one72_slash_12_time.elapsed().as_secs());     
@@ -317,6 +861,9 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
     //Only need the thrid and forth since the second does not change for this space.
     let one92_slash_16_time = std::time::Instant::now();
     for third_octet in 0..=MAX_OCTET {
+        if scan_opts.stopping() {
+            break;
+        }
         //Check if we need to skip SUBNET
         let subnet = format!("192.168.{}.0",third_octet); 
         if sub_ex_list.len() > 0 && sub_ex_list.contains(&subnet) {
@@ -324,6 +871,14 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                 sub_ex_list.remove(&subnet);
                 continue;
         }
+        //Only the /24s that had hosts in earlier runs when asked to.
+        if scan_opts.known_subnets.as_ref().is_some_and(|known| !known.contains(&subnet)) {
+            continue;
+        }
+        //Already swept before the run was stopped.
+        if checkpoint.completed_subnets.contains(&subnet) {
+            continue;
+        }
         //eprintln!("Scanning Subnet: 192.168.{}.0/24", third_octet); DEBUG        
         let mut tasks = JoinSet::new();
         let subnet_has_hosts = Arc::new(Mutex::new(false));
@@ -346,27 +901,268 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
                 rdns_and_ping_ip(ip_addr_to_test, list_of_hosts_clone, subnet_has_hosts_clone, en_pingsweep).await;
             });
         }
-        //Should wait for all spawned tasks to complete.
-        tasks.join_all().await;
+        //Should wait for all spawned tasks to complete, or as many as finish in time when stopping.
+        let (_, subnet_complete) = drain_tasks(tasks, &scan_opts.stop).await;
 
         let subnet_has_hosts_clone = subnet_has_hosts.clone(); 
         let mut subnet_value = subnet_has_hosts_clone.lock().unwrap(); 
-        if *subnet_value {
-            subnets_with_hosts.push(format!("192.168.{}.0/24", third_octet));
-            *subnet_value = false;
+        if subnet_complete {
+            events::emit(events::Event::SubnetComplete { subnet: &format!("192.168.{}.0/24", third_octet), has_hosts: *subnet_value });
+        }
+        //A /24 cut short by a stop is swept again on resume, it only goes in the list once.
+        let subnet_cidr = format!("192.168.{}.0/24", third_octet);
+        if *subnet_value && !subnets_with_hosts.contains(&subnet_cidr) {
+            subnets_with_hosts.push(subnet_cidr);
+        }
+        *subnet_value = false;
+        if subnet_complete {
+            checkpoint.subnet_swept(&subnet, &subnets_with_hosts, &list_of_hosts);
         }
     }
     eprintln!("The Subnet 192.168.0.0/16 took {} seconds to complete.", one92_slash_16_time.elapsed().as_secs());     
     eprintln!("Total RDNS time took {} seconds to complete.", rdns_time.elapsed().as_secs());     
+    events::emit(events::Event::PhaseComplete { phase: "rdns", secs: rdns_time.elapsed().as_secs() });
+    if let Some(listener) = icmp_listener {
+        listener.stop();
+    }
+    checkpoint.save(&subnets_with_hosts, &list_of_hosts);
+
+    //What each stage found, for classifying hosts and the outputs at the end.
+    let mut evidence: classify::Evidence = HashMap::new();
+    let mut stage_results = results::StageResults::default();
+    //Anything not in here by now was found by the portscan. A resumed run whose portscan had finished
+    //got the portscan's hosts back with the rest, so it uses the list saved from before the portscan.
+    let discovered: HashMap<String, String> = match &checkpoint.swept_hosts {
+        Some(swept_hosts) => swept_hosts.clone().into_iter().collect(),
+        None => list_of_hosts.lock().unwrap().clone(),
+    };
 
     //========================PORT SCANNING===========================//
     //Use the List of Subnets with Hosts to Scan them for Open Ports 80,443,445
-    if en_portscan {
+    if scan_opts.portscan && !scan_opts.stopping() {
         let list_of_hosts_clone = list_of_hosts.clone();
         let portscan_time = std::time::Instant::now();
-        subnet_portscan(&subnets_with_hosts, list_of_hosts_clone, copy_ip_ex_list).await;
+        let port_results = match checkpoint.port_results() {
+            //The portscan finished before the run was stopped, no need to do it again.
+            Some(port_results) => {
+                eprintln!("Portscan already done before the run was stopped, using its {} port results.", port_results.len());
+                port_results
+            },
+            None => {
+                let port_results = subnet_portscan(&subnets_with_hosts, list_of_hosts_clone, copy_ip_ex_list, scan_opts, os_observations.clone()).await;
+                //A stopped portscan only got some of the ports, a resumed run scans again.
+                if !scan_opts.stopping() {
+                    checkpoint.portscan_done(&port_results, &discovered);
+                    checkpoint.save(&subnets_with_hosts, &list_of_hosts);
+                }
+                port_results
+            },
+        };
         eprintln!("Total Portscan time took {} seconds to complete.", portscan_time.elapsed().as_secs());     
-        println!("<--Portscan Output saved in /output.-->");
+        events::emit(events::Event::PhaseComplete { phase: "portscan", secs: portscan_time.elapsed().as_secs() });
+        eprintln!("<--Portscan Output saved in {}-->", scan_opts.output_dir.display());
+        classify::add_port_results(&mut evidence, &port_results);
+        stage_results.port_results = port_results.clone();
+
+        //=====================BANNER GRABBING=====================//
+        //Only TCP ports, UDP answers already came back from the service probes.
+        if scan_opts.banners && !scan_opts.stopping() {
+            let banner_time = std::time::Instant::now();
+            let open_tcp: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp")
+                .map(|result| (result.host, result.port))
+                .collect();
+            eprintln!("//=============Grabbing Banners=========//");
+            let banners = banner::grab_banners(&open_tcp, &scan_opts.output_dir, &scan_opts.stop).await;
+            classify::add_banners(&mut evidence, &banners);
+            eprintln!("Total Banner grabbing time took {} seconds to complete.", banner_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "banners", secs: banner_time.elapsed().as_secs() });
+
+            //=====================SERVICE FINGERPRINTING=====================//
+            if let Some(probes) = &scan_opts.service_probes {
+                let fingerprint_time = std::time::Instant::now();
+                eprintln!("//=============Fingerprinting Services=========//");
+                let services = fingerprint::fingerprint_services(&open_tcp, probes.clone(), &banners, &scan_opts.output_dir, &scan_opts.stop).await;
+                classify::add_services(&mut evidence, &services);
+                stage_results.services = services;
+                eprintln!("Total Fingerprinting time took {} seconds to complete.", fingerprint_time.elapsed().as_secs());     
+                events::emit(events::Event::PhaseComplete { phase: "fingerprint", secs: fingerprint_time.elapsed().as_secs() });
+            }
+            stage_results.banners = banners;
+        }
+
+        //=====================TLS CERTIFICATES=====================//
+        if scan_opts.tls && !scan_opts.stopping() {
+            let tls_time = std::time::Instant::now();
+            let open_tls: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp" && tls::TLS_PORTS.contains(&result.port))
+                .map(|result| (result.host, result.port))
+                .collect();
+            eprintln!("//=============Grabbing TLS Certificates=========//");
+            let hostnames = list_of_hosts.lock().unwrap().clone();
+            let certificates = tls::harvest_certificates(&open_tls, &hostnames, &scan_opts.output_dir, &scan_opts.stop).await;
+            classify::add_certificates(&mut evidence, &certificates);
+            //Hosts with no PTR record get named after their certificate.
+            let mut list = list_of_hosts.lock().unwrap();
+            for cert in certificates.iter() {
+                let Some(name) = cert.best_hostname() else { continue };
+                if let Some(hostname) = list.get_mut(&cert.host.to_string()) {
+                    if hostname == "no_hostname" {
+                        eprintln!("{} named {} from its certificate", cert.host, name);
+                        events::emit(events::Event::HostnameResolved { host: cert.host, hostname: &name, source: "certificate" });
+                        *hostname = name;
+                    }
+                }
+            }
+            stage_results.certificates = certificates;
+            eprintln!("Total TLS time took {} seconds to complete.", tls_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "tls", secs: tls_time.elapsed().as_secs() });
+        }
+
+        //=====================HTTP ENRICHMENT=====================//
+        //After TLS so hosts named from their certificate get the right Host header.
+        if scan_opts.http && !scan_opts.stopping() {
+            let http_time = std::time::Instant::now();
+            let open_web: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp")
+                .filter(|result| http::HTTP_PORTS.contains(&result.port) || http::HTTPS_PORTS.contains(&result.port))
+                .map(|result| (result.host, result.port))
+                .collect();
+            eprintln!("//=============HTTP Enrichment=========//");
+            let hostnames = list_of_hosts.lock().unwrap().clone();
+            let pages = http::http_enrich(&open_web, &hostnames, &scan_opts.output_dir, &scan_opts.stop).await;
+            classify::add_http(&mut evidence, &pages);
+            stage_results.pages = pages;
+            eprintln!("Total HTTP time took {} seconds to complete.", http_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "http", secs: http_time.elapsed().as_secs() });
+        }
+
+        //=====================SMB ENRICHMENT=====================//
+        if scan_opts.smb && !scan_opts.stopping() {
+            let smb_time = std::time::Instant::now();
+            let smb_hosts: Vec<IpAddr> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp" && result.port == smb::SMB_PORT)
+                .map(|result| result.host)
+                .collect();
+            eprintln!("//=============SMB Enrichment=========//");
+            stage_results.smb = smb::smb_enrich(&smb_hosts, &scan_opts.output_dir, &scan_opts.stop).await;
+            eprintln!("Total SMB time took {} seconds to complete.", smb_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "smb", secs: smb_time.elapsed().as_secs() });
+        }
+
+        //=====================SSH INVENTORY=====================//
+        if scan_opts.ssh && !scan_opts.stopping() {
+            let ssh_time = std::time::Instant::now();
+            let open_ssh: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp" && ssh::SSH_PORTS.contains(&result.port))
+                .map(|result| (result.host, result.port))
+                .collect();
+            eprintln!("//=============SSH Inventory=========//");
+            stage_results.ssh = ssh::ssh_inventory(&open_ssh, &scan_opts.output_dir, &scan_opts.stop).await;
+            eprintln!("Total SSH time took {} seconds to complete.", ssh_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "ssh", secs: ssh_time.elapsed().as_secs() });
+        }
+
+        //=====================RDP ENRICHMENT=====================//
+        if scan_opts.rdp && !scan_opts.stopping() {
+            let rdp_time = std::time::Instant::now();
+            let rdp_hosts: Vec<IpAddr> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp" && result.port == rdp::RDP_PORT)
+                .map(|result| result.host)
+                .collect();
+            eprintln!("//=============RDP Enrichment=========//");
+            stage_results.rdp = rdp::rdp_enrich(&rdp_hosts, &scan_opts.output_dir, &scan_opts.stop).await;
+            eprintln!("Total RDP time took {} seconds to complete.", rdp_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "rdp", secs: rdp_time.elapsed().as_secs() });
+        }
+
+        //=====================LDAP ENRICHMENT=====================//
+        if scan_opts.ldap && !scan_opts.stopping() {
+            let ldap_time = std::time::Instant::now();
+            let open_ldap: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp")
+                .filter(|result| ldap::LDAP_PORTS.contains(&result.port) || ldap::LDAPS_PORTS.contains(&result.port))
+                .map(|result| (result.host, result.port))
+                .collect();
+            eprintln!("//=============LDAP Enrichment=========//");
+            stage_results.ldap = ldap::ldap_enrich(&open_ldap, &scan_opts.output_dir, &scan_opts.stop).await;
+            eprintln!("Total LDAP time took {} seconds to complete.", ldap_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "ldap", secs: ldap_time.elapsed().as_secs() });
+        }
+
+        //=====================DATABASE IDENTIFICATION=====================//
+        if scan_opts.databases && !scan_opts.stopping() {
+            let database_time = std::time::Instant::now();
+            let open_databases: Vec<(IpAddr, u32)> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp" && database::DATABASE_PORTS.contains(&result.port))
+                .map(|result| (result.host, result.port))
+                .collect();
+            //Named instances pick their own port so ask SQL Browser on any host with something open, not just 1433.
+            let mut browser_hosts: Vec<IpAddr> = port_results.iter()
+                .filter(|result| result.state == PortState::Open && result.proto == "tcp")
+                .map(|result| result.host)
+                .collect();
+            browser_hosts.sort();
+            browser_hosts.dedup();
+            eprintln!("//=============Database Identification=========//");
+            stage_results.databases = database::database_enrich(&open_databases, &browser_hosts, &scan_opts.output_dir, &scan_opts.stop).await;
+            eprintln!("Total database time took {} seconds to complete.", database_time.elapsed().as_secs());     
+            events::emit(events::Event::PhaseComplete { phase: "databases", secs: database_time.elapsed().as_secs() });
+        }
+    }
+
+    //=====================OS FINGERPRINTING=====================//
+    if let Some(signatures) = &scan_opts.os_signatures {
+        let os_time = std::time::Instant::now();
+        eprintln!("//=============OS Fingerprinting=========//");
+        let guesses = osfingerprint::guess_os(&os_observations, signatures, &scan_opts.output_dir);
+        classify::add_os_guesses(&mut evidence, &guesses);
+        stage_results.os_guesses = guesses;
+        eprintln!("Total OS fingerprinting time took {} seconds to complete.", os_time.elapsed().as_secs());     
+        events::emit(events::Event::PhaseComplete { phase: "os_fingerprint", secs: os_time.elapsed().as_secs() });
+    }
+
+    //=====================SNMP=====================//
+    //Every host we found, SNMP answers nothing at all to a wrong community so there is no port to check first.
+    if let Some(communities) = scan_opts.snmp_communities.as_ref().filter(|_| !scan_opts.stopping()) {
+        let snmp_time = std::time::Instant::now();
+        let mut snmp_hosts: Vec<IpAddr> = list_of_hosts.lock().unwrap().keys().filter_map(|ip| ip.parse().ok()).collect();
+        snmp_hosts.sort();
+        eprintln!("//=============SNMP=========//");
+        let agents = snmp::snmp_enrich(&snmp_hosts, communities.clone(), &scan_opts.output_dir, &scan_opts.stop).await;
+        classify::add_snmp(&mut evidence, &agents);
+        //Hosts with no PTR record get named after their sysName.
+        let mut list = list_of_hosts.lock().unwrap();
+        for agent in agents.iter().filter(|agent| !agent.sys_name.is_empty()) {
+            if let Some(hostname) = list.get_mut(&agent.host.to_string()) {
+                if hostname == "no_hostname" {
+                    eprintln!("{} named {} from its sysName", agent.host, agent.sys_name);
+                    events::emit(events::Event::HostnameResolved { host: agent.host, hostname: &agent.sys_name, source: "snmp" });
+                    *hostname = agent.sys_name.clone();
+                }
+            }
+        }
+        stage_results.snmp = agents;
+        eprintln!("Total SNMP time took {} seconds to complete.", snmp_time.elapsed().as_secs());     
+        events::emit(events::Event::PhaseComplete { phase: "snmp", secs: snmp_time.elapsed().as_secs() });
+    }
+
+    //=====================DEVICE CLASSIFICATION=====================//
+    //Last so it sees everything the other stages found.
+    if let Some(rules) = &scan_opts.classification_rules {
+        let classify_time = std::time::Instant::now();
+        eprintln!("//=============Device Classification=========//");
+        let hostnames = list_of_hosts.lock().unwrap().clone();
+        //Hosts that only answered a ping still get a line, even if it's unknown.
+        for ip in hostnames.keys() {
+            if let Ok(ip) = ip.parse() {
+                evidence.entry(ip).or_default();
+            }
+        }
+        classify::add_arp_cache(&mut evidence);
+        stage_results.classifications = classify::classify_hosts(&evidence, rules, &hostnames, &scan_opts.output_dir);
+        eprintln!("Total classification time took {} seconds to complete.", classify_time.elapsed().as_secs());     
+        events::emit(events::Event::PhaseComplete { phase: "classify", secs: classify_time.elapsed().as_secs() });
     }
 
     
@@ -378,7 +1174,7 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
 
 
     //Write Gathered Data to Subnets.txt
-    let subnet_results_file_path = Path::new("output/subnets.txt");
+    let subnet_results_file_path = scan_opts.output_dir.join("subnets.txt");
     let display_sub = subnet_results_file_path.display();
     let subnet_results_file = match File::create(&subnet_results_file_path) {
         Err(e) => panic!("Couldn't Create {}: {}", display_sub, e),
@@ -390,30 +1186,71 @@ async fn rdns_and_ping_full_private(en_portscan: bool, en_pingsweep: bool, mut s
     }
 
     //Write Gathered Data to ip_hostname.txt and up_ips.txt
-    let ip_host_results_file_path = Path::new("output/ip_hostname.txt");
-    let up_ip_results_file_path = Path::new("output/up_ips.txt");
-    let display_ip_host = ip_host_results_file_path.display();
-    let display_up_ip = up_ip_results_file_path.display();
+    if scan_opts.formats.contains(&OutputFormat::Text) {
+        let ip_host_results_file_path = scan_opts.output_dir.join("ip_hostname.txt");
+        let up_ip_results_file_path = scan_opts.output_dir.join("up_ips.txt");
+        let display_ip_host = ip_host_results_file_path.display();
+        let display_up_ip = up_ip_results_file_path.display();
     
-    //Create File and Create Write Buffer
-    let ip_host_results_file = match File::create(&ip_host_results_file_path) {
-        Err(e) => panic!("Couldn't Create {}: {}", display_ip_host, e),
-        Ok(file) => file,
-    };
-    let mut ip_host_buff = BufWriter::new(ip_host_results_file);
+        //Create File and Create Write Buffer
+        let ip_host_results_file = match File::create(&ip_host_results_file_path) {
+            Err(e) => panic!("Couldn't Create {}: {}", display_ip_host, e),
+            Ok(file) => file,
+        };
+        let mut ip_host_buff = BufWriter::new(ip_host_results_file);
 
-    //Create File and Create Write Buffer
-    let up_ip_results_file = match File::create(&up_ip_results_file_path) {
-        Err(e) => panic!("Couldn't Create {}: {}", display_up_ip, e),
-        Ok(file) => file,
-    };
-    let mut up_ip_buff = BufWriter::new(up_ip_results_file);
+        //Create File and Create Write Buffer
+        let up_ip_results_file = match File::create(&up_ip_results_file_path) {
+            Err(e) => panic!("Couldn't Create {}: {}", display_up_ip, e),
+            Ok(file) => file,
+        };
+        let mut up_ip_buff = BufWriter::new(up_ip_results_file);
     
-    //Loop through vector of host and ips "ip,hostname" and add them to output files.
-    let list_of_hosts_clone = list_of_hosts.clone();
-    for (ip, hostname) in list_of_hosts_clone.lock().unwrap().iter() {
-        ip_host_buff.write_all(format!("{},{}\n",ip,hostname).as_bytes()).expect("Unable to write ip,hostname to ip_hostname.txt");
-        up_ip_buff.write_all(format!("{}\n",ip).as_bytes()).expect("Unable to write ip to up_ips.txt");
+        //Loop through vector of host and ips "ip,hostname" and add them to output files.
+        let list_of_hosts_clone = list_of_hosts.clone();
+        for (ip, hostname) in list_of_hosts_clone.lock().unwrap().iter() {
+            ip_host_buff.write_all(format!("{},{}\n",ip,hostname).as_bytes()).expect("Unable to write ip,hostname to ip_hostname.txt");
+            up_ip_buff.write_all(format!("{}\n",ip).as_bytes()).expect("Unable to write ip to up_ips.txt");
+        }
+    }
+
+    //Write everything to results.json, results.xml, results.csv, results.gnmap and the history database
+    let csv = scan_opts.formats.contains(&OutputFormat::Csv);
+    let grep = scan_opts.formats.contains(&OutputFormat::Grep);
+    if scan_opts.json || scan_opts.xml || csv || grep || scan_opts.history.is_some() {
+        let run = results::run_info(started, scan_opts.stages(), scan_opts.ports.clone(), scan_opts.stopping());
+        let targets = vec!["10.0.0.0/8".to_string(), "172.16.0.0/12".to_string(), "192.168.0.0/16".to_string()];
+        let hostnames = list_of_hosts.lock().unwrap().clone();
+        let report = results::build_report(run, targets, exclusions, &subnets_with_hosts, &hostnames, &discovered, stage_results);
+        if scan_opts.json {
+            results::write_json(&report, &scan_opts.output_dir);
+        }
+        if scan_opts.xml {
+            nmapxml::write_nmap_xml(&report, &scan_opts.output_dir);
+        }
+        if csv {
+            results::write_csv(&report, &scan_opts.output_dir);
+        }
+        if grep {
+            results::write_grepable(&report, &scan_opts.output_dir);
+        }
+        if let Some(path) = &scan_opts.history {
+            let mut conn = history::open_history(path);
+            match history::save_run(&mut conn, &report) {
+                Ok(run_id) => eprintln!("Saved as run {} in {}", run_id, path),
+                Err(e) => eprintln!("Couldn't save the run to {}: {}", path, e),
+            }
+        }
+    }
+    if scan_opts.stopping() {
+        checkpoint.save(&subnets_with_hosts, &list_of_hosts);
+        mark_incomplete(&scan_opts.output_dir, Some(&checkpoint.path()));
+        eprintln!("!!!-Run was stopped early. What it found before then is saved in {}.", scan_opts.output_dir.display());
+        eprintln!("!!!-Pick up where it left off with: valk2 --resume {}", checkpoint.path().display());
+    }
+    else {
+        checkpoint.remove();
+        mark_incomplete(&scan_opts.output_dir, None);
     }
 }
 
@@ -427,6 +1264,7 @@ async fn rdns_and_ping_ip(ip:IpAddr, host_list: Db, host_on_subnet: Sbool, pings
             //No Hostname so need to ping the IP address and see if that works.
             if pingsweeps_enabled && ping_host(ip, 200) {
                 eprintln!("{} is reachable",ip);
+                events::emit(events::Event::HostFound { host: ip, via: "ping" });
                 //Set the boolean to show there is a host on this subnet.
                 let mut host_on_subnet_value = host_on_subnet.lock().unwrap(); 
                 *host_on_subnet_value = true;
@@ -437,6 +1275,8 @@ async fn rdns_and_ping_ip(ip:IpAddr, host_list: Db, host_on_subnet: Sbool, pings
             } //No Ping and No RDNS so NO host.
         } ,
         _ => {
+            events::emit(events::Event::HostFound { host: ip, via: "rdns" });
+            events::emit(events::Event::HostnameResolved { host: ip, hostname: &hostname, source: "rdns" });
             //Set the boolean to show there is a host on this subnet.
             let mut host_on_subnet_value = host_on_subnet.lock().unwrap(); 
             *host_on_subnet_value = true;
@@ -461,6 +1301,228 @@ fn ping_host(host: IpAddr, timeout_milis: u64) -> bool {
     }
 }
 
+//DESCRIPTION: Runs a subcommand. None of them scan so they skip the banner and exclusions.
+//TAKES: The subcommand.
+//RETURNS: Nothing.
+fn run_command(command: Command) {
+    match command {
+        Command::Diff { old, new, history, json } => {
+            let (old_report, old_source) = load_results(&old, history.as_deref());
+            let (new_report, new_source) = load_results(&new, history.as_deref());
+            let changes = diff::diff_reports(&old_report, &new_report, &old_source, &new_source);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&changes).expect("Unable to serialize diff"));
+            }
+            else {
+                print!("{}", diff::diff_text(&changes));
+            }
+        },
+        Command::Report { run, history, output } => {
+            let (report, source) = load_results(&run, history.as_deref());
+            report::write_html_report(&report, &source, &output);
+            eprintln!("Report of {} written to {}", source, output);
+        },
+    }
+}
+
+//DESCRIPTION: Loads a run for a subcommand, from a results.json or from the history database.
+//TAKES: The path or run id, and the history database if it's a run id.
+//RETURNS: The report and what to call it. Panics if it can't be loaded.
+fn load_results(run: &str, history: Option<&str>) -> (results::ScanReport, String) {
+    let Some(path) = history else {
+        return (results::load_json(run), run.to_string());
+    };
+    if !Path::new(path).exists() {
+        panic!("{} does not exist.", path);
+    }
+    let run_id: i64 = match run.parse() {
+        Err(_) => panic!("{} isn't a run id. With --history runs are ids.", run),
+        Ok(run_id) => run_id,
+    };
+    let conn = history::open_history(path);
+    match history::load_run(&conn, run_id) {
+        Err(e) => panic!("Couldn't Load run {} from {}: {}", run_id, path, e),
+        Ok(report) => (report, format!("{} run {}", path, run_id)),
+    }
+}
+
+//DESCRIPTION: Watches for Ctrl-C or SIGTERM in the background. The first one sets the stop flag so the
+//             sweeps and portscan stop scheduling probes and the run writes out what it has. A
+//             second one quits straight away.
+//TAKES: The stop flag.
+//RETURNS: Nothing.
+fn watch_for_stop(stop: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        eprintln!("\n!!!-Stopping. Giving probes in flight up to {} seconds to finish, then writing out what was found. \nCtrl-C again to quit without writing anything.", DRAIN_DEADLINE.as_secs());
+        stop.store(true, Ordering::Relaxed);
+        wait_for_signal().await;
+        eprintln!("!!!-Quitting.");
+        std::process::exit(130);
+    });
+}
+
+//DESCRIPTION: Waits for Ctrl-C, or SIGTERM too on Unix.
+//TAKES: Nothing.
+//RETURNS: Once one comes in.
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Err(e) => panic!("Couldn't watch for SIGTERM: {}", e),
+            Ok(terminate) => terminate,
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.expect("Couldn't watch for Ctrl-C");
+    }
+}
+
+//DESCRIPTION: Waits on a set of probe tasks. Once a stop comes in they get DRAIN_DEADLINE to finish
+//             and anything still running after that is aborted.
+//TAKES: The tasks and the stop flag.
+//RETURNS: What the tasks that finished returned, and whether every one of them finished.
+async fn drain_tasks<T: 'static>(mut tasks: JoinSet<T>, stop: &AtomicBool) -> (Vec<T>, bool) {
+    let mut finished = Vec::with_capacity(tasks.len());
+    while !tasks.is_empty() {
+        if stop.load(Ordering::Relaxed) {
+            let deadline = tokio::time::Instant::now() + DRAIN_DEADLINE;
+            while let Ok(Some(result)) = tokio::time::timeout_at(deadline, tasks.join_next()).await {
+                finished.push(result.unwrap());
+            }
+            if !tasks.is_empty() {
+                eprintln!("{} probes didn't finish in time and were dropped.", tasks.len());
+                tasks.abort_all();
+                return (finished, false);
+            }
+            break;
+        }
+        //Look up every so often to see if a stop came in.
+        if let Ok(Some(result)) = tokio::time::timeout(Duration::from_millis(250), tasks.join_next()).await {
+            finished.push(result.unwrap());
+        }
+    }
+    (finished, true)
+}
+
+//DESCRIPTION: Runs probes at most limit at a time and waits on them with drain_tasks. Once a stop
+//             comes in the probes still waiting for their turn don't start.
+//TAKES: The probes, how many can run at once and the stop flag.
+//RETURNS: What each probe returned in the order they were given, None for any that didn't get to
+//         run or didn't finish in time.
+async fn run_probes<T, F>(probes: Vec<F>, limit: usize, stop: &Arc<AtomicBool>) -> Vec<Option<T>>
+where T: Send + 'static, F: Future<Output = T> + Send + 'static {
+    let limiter = Arc::new(Semaphore::new(limit));
+    let mut results: Vec<Option<T>> = probes.iter().map(|_| None).collect();
+    let mut tasks = JoinSet::new();
+    for (index, probe) in probes.into_iter().enumerate() {
+        let limiter = limiter.clone();
+        let stop = stop.clone();
+        tasks.spawn(async move {
+            let _permit = limiter.acquire().await.unwrap();
+            if stop.load(Ordering::Relaxed) {
+                return (index, None);
+            }
+            (index, Some(probe.await))
+        });
+    }
+    let (finished, _) = drain_tasks(tasks, stop).await;
+    for (index, result) in finished {
+        results[index] = result;
+    }
+    results
+}
+
+//DESCRIPTION: Makes the folder this run's results go in. Refuses one that already has something in
+//             it unless told to overwrite, so a rerun doesn't clobber the last one's results.
+//TAKES: The output directory, what was scanned for the folder name, whether to make a folder per
+//       run and whether overwriting is fine.
+//RETURNS: The folder. Panics if it can't be made or has results in it.
+fn prepare_output_dir(output_dir: &str, target_label: &str, run_folder: bool, overwrite: bool) -> PathBuf {
+    let mut dir = PathBuf::from(output_dir);
+    if run_folder {
+        let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0);
+        //20250131T134500Z, no colons so it's a valid folder name on Windows too.
+        let stamp = format_timestamp(secs).replace(['-', ':'], "");
+        dir.push(format!("{}_{}", stamp, target_label));
+    }
+    let has_results = fs::read_dir(&dir).map(|mut entries| entries.next().is_some()).unwrap_or(false);
+    if has_results && !overwrite {
+        panic!("{} already has results in it. Use --overwrite to write over them or pick another --output-dir.", dir.display());
+    }
+    if let Err(e) = fs::create_dir_all(&dir) {
+        panic!("Failed to Create Dir {}: {}", dir.display(), e);
+    }
+    dir
+}
+
+//DESCRIPTION: Leaves an INCOMPLETE file in the run folder of a stopped run so the text outputs, which
+//             have nowhere to say so, can't be taken for a finished run. A resumed run that finishes
+//             takes it away again.
+//TAKES: The run folder and the state file to resume from, None once the run has finished.
+//RETURNS: Nothing.
+fn mark_incomplete(out_dir: &Path, state_rfp: Option<&Path>) {
+    let marker_rfp = out_dir.join(INCOMPLETE_FILE);
+    match state_rfp {
+        Some(state_rfp) => {
+            let note = format!("This run was stopped before it finished, the results in this folder are partial.\nResume it with: valk2 --resume {}\n", state_rfp.display());
+            if let Err(e) = fs::write(&marker_rfp, note) {
+                eprintln!("Couldn't Create {}: {}", marker_rfp.display(), e);
+            }
+        },
+        None => {
+            if marker_rfp.exists() {
+                if let Err(e) = fs::remove_file(&marker_rfp) {
+                    eprintln!("Couldn't remove {}: {}", marker_rfp.display(), e);
+                }
+            }
+        },
+    }
+}
+
+//DESCRIPTION: Turns seconds since the epoch into an ISO 8601 UTC time.
+//TAKES: Unix timestamp.
+//RETURNS: "2024-01-31T13:45:00Z"
+fn format_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+    //Civil from days, Howard Hinnant's algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time_of_day / 3600, time_of_day % 3600 / 60, time_of_day % 60)
+}
+
+//DESCRIPTION: Turns an ISO 8601 UTC time from format_timestamp back into seconds since the epoch.
+//TAKES: "2024-01-31T13:45:00Z"
+//RETURNS: Unix timestamp, or None if it isn't in that form.
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else { return None };
+    //Days from civil, the other half of Howard Hinnant's algorithm.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
 //DESCRIPTION:
 //TAKES:
 //RETURNS:
@@ -469,48 +1531,16 @@ fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> wher
     Ok(io::BufReader::new(file).lines())
 }
 
-//DESCRIPTION:
-//TAKES:
-//RETURNS:
-async fn subnet_portscan(subs_with_hosts: &Vec<String>, host_list: Db, mut ip_ex_hashmap: HashSet<String>) {
+//DESCRIPTION: Portscans every address in the subnets that had hosts and writes open ports out per port.
+//TAKES: Subnets with hosts, the host list to add new hosts to, excluded IPs, the scan options and
+//       where SYN scans keep their SYN/ACKs for OS fingerprinting.
+//RETURNS: Every TCP and UDP port result. Open ports also go to the run folder and new hosts to the host list.
+async fn subnet_portscan(subs_with_hosts: &Vec<String>, host_list: Db, mut ip_ex_hashmap: HashSet<String>, scan_opts: &ScanOptions, os_observations: osfingerprint::OsObservations) -> Vec<PortResult> {
     //PORT SCANNING
-    let all_ports = vec![80,443,445];
-    
-    let open_port_80_rfp = Path::new("output/80.txt");
-    let open_port_443_rfp = Path::new("output/443.txt");
-    let open_port_445_rfp = Path::new("output/445.txt");
+    let all_ports = scan_opts.ports.clone();
     
-    //OPEN Write Buffer for 80
-    let open_port_80_rf = match File::create(&open_port_80_rfp) {
-        Err(e) => panic!("Couldn't Create {}: {}", open_port_80_rfp.display(), e),
-        Ok(file) => file,
-    };
-    let mut buff_80 = BufWriter::new(open_port_80_rf);
-
-    //OPEN Write Buffer for 443
-    let open_port_443_rf = match File::create(&open_port_443_rfp) {
-        Err(e) => panic!("Couldn't Create {}: {}", open_port_443_rfp.display(), e),
-        Ok(file) => file,
-    };
-    let mut buff_443 = BufWriter::new(open_port_443_rf);
-
-    //OPEN Write Buffer for 445
-    let open_port_445_rf = match File::create(&open_port_445_rfp) {
-        Err(e) => panic!("Couldn't Create {}: {}", open_port_445_rfp.display(), e),
-        Ok(file) => file,
-    };
-    let mut buff_445 = BufWriter::new(open_port_445_rf);
-
-
-    
-    //Make Sure Ouput Directory is there.
-    match fs::create_dir_all("output") {
-        Err(e) => panic!("Failed to Create Dir: {}", e),
-        Ok(_) => {},
-    }
-    println!("//=============Begining Port Scans=========//");
-    let max_tasks = MAX_OCTET as usize * all_ports.len();
-    let mut tasks = Vec::with_capacity(max_tasks);
+    eprintln!("//=============Begining Port Scans=========//");
+    let mut addrs_to_scan: Vec<IpAddr> = Vec::new();
     for subnet in subs_with_hosts.iter() {
         for addr_octet in 0..=MAX_OCTET {
             let addr = subnet.replace("0/24",format!("{}",addr_octet).as_str());
@@ -519,39 +1549,160 @@ async fn subnet_portscan(subs_with_hosts: &Vec<String>, host_list: Db, mut ip_ex
                 ip_ex_hashmap.remove(&addr);
                 continue;
             }
-            for port in all_ports.iter() {   
-
-                tasks.push(tokio::spawn(addr_portscan(addr.parse().unwrap(), *port)));
-            }
+            addrs_to_scan.push(addr.parse().unwrap());
         }        
     }
-    for task in tasks {
-        let result = task.await.unwrap();
-        if result.0 {
-            match result.2 {
-                80 => buff_80.write_all(format!("{}\n",result.1).as_bytes()).expect("Unable to write data"),
-                443 => buff_443.write_all(format!("{}\n",result.1).as_bytes()).expect("Unable to write data"),
-                445 => buff_445.write_all(format!("{}\n",result.1).as_bytes()).expect("Unable to write data"),
-                _ => println!("How in the BlAaAakE did we get here!"),
-            }   
-            ////======TODO ADD ANY NEW HOSTS TO THE HOSTS LIST===============//
-            if !host_list.lock().unwrap().contains_key(&format!("{}",result.1)){
-                //Access the Mutex Protected Host list and Add the IP and hostname to it.
-                let mut list = host_list.lock().unwrap();
-                list.insert(format!("{}",result.1), "no_hostname".to_string());
+
+    //Try the half-open scan first if asked. No raw socket means connect scans it is.
+    let mut results: Vec<PortResult> = Vec::new();
+    let mut syn_done = false;
+    if scan_opts.syn_scan {
+        let syn_addrs = addrs_to_scan.clone();
+        let syn_ports = all_ports.clone();
+        let stop = scan_opts.stop.clone();
+        let syn_host_list = host_list.clone();
+        match tokio::task::spawn_blocking(move || synscan::syn_scan(&syn_addrs, &syn_ports, os_observations, syn_host_list, stop)).await.unwrap() {
+            Ok(syn_results) => {
+                results = syn_results;
+                syn_done = true;
+            },
+            Err(e) => eprintln!("SYN scan unavailable ({}). Need root or CAP_NET_RAW. Falling back to connect scans.", e),
+        }
+    }
+    if !syn_done {
+        //Limited like the other stages so a big sweep doesn't run out of sockets.
+        let mut probes = Vec::with_capacity(addrs_to_scan.len() * all_ports.len());
+        for addr in addrs_to_scan.iter() {
+            for port in all_ports.iter() {   
+                let (addr, port, host_list) = (*addr, *port, host_list.clone());
+                probes.push(async move {
+                    let result = addr_portscan(addr, port).await;
+                    port_answered(&result, &host_list);
+                    result
+                });
             }
         }
+        results = run_probes(probes, MAX_CONNECT_PROBES, &scan_opts.stop).await.into_iter().flatten().collect();
+        //The subnets aren't in order, put the results in address order for the port files.
+        results.sort_by_key(|result| (result.host, result.port));
+    }
+
+    let port_files = scan_opts.formats.contains(&OutputFormat::Text);
+    if port_files {
+        save_port_results(&results, &all_ports, "tcp", &scan_opts.output_dir);
+    }
+
+    //=====================UDP SCANNING=====================//
+    if scan_opts.udp_scan && !scan_opts.stopping() {
+        eprintln!("//=============Begining UDP Scans=========//");
+        let udp_ports: Vec<u32> = udpscan::UDP_SERVICES.iter().map(|service| service.port).collect();
+        let udp_results = udpscan::udp_scan(&addrs_to_scan, &host_list, &scan_opts.stop).await;
+        if port_files {
+            save_port_results(&udp_results, &udp_ports, "udp", &scan_opts.output_dir);
+        }
+        results.extend(udp_results);
     }
+    results
 }
 
-//DESCRIPTION:
-//TAKES:
-//RETURNS: Tuple (true if port is open, ip, port);
-async fn addr_portscan (host: IpAddr, port: u32) -> (bool, IpAddr, u32) {
-    let socket: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-    match TcpStream::connect_timeout(&socket, Duration::from_secs(1)) {
-        Ok(_) => (true, host, port),
-        Err(_) => (false, "0.0.0.0".parse().unwrap(), 0),
+//DESCRIPTION: Writes each open port's hosts to <port>.txt (udp_<port>.txt for UDP) in the run folder,
+//             one IP per line.
+//TAKES: Port results, every port that was scanned (each gets a file even if nothing was open),
+//       the protocol ("tcp" or "udp") and the run folder.
+//RETURNS: Nothing.
+fn save_port_results(results: &[PortResult], ports: &[u32], proto: &str, out_dir: &Path) {
+    let file_prefix = if proto == "udp" {"udp_"} else {""};
+    //OPEN Write Buffer for every port
+    let mut port_buffs: HashMap<u32, BufWriter<File>> = HashMap::new();
+    for port in ports.iter() {
+        let port_rfp = out_dir.join(format!("{}{}.txt", file_prefix, port));
+        let port_rf = match File::create(&port_rfp) {
+            Err(e) => panic!("Couldn't Create {}: {}", port_rfp.display(), e),
+            Ok(file) => file,
+        };
+        port_buffs.insert(*port, BufWriter::new(port_rf));
+    }
+
+    for result in results.iter().filter(|result| result.state == PortState::Open) {
+        match port_buffs.get_mut(&result.port) {
+            Some(buff) => buff.write_all(format!("{}\n",result.host).as_bytes()).expect("Unable to write data"),
+            None => eprintln!("How in the BlAaAakE did we get here!"),
+        }
     }
 }
 
+//DESCRIPTION: Reports a port probe as soon as it comes back rather than when the whole scan is done.
+//             Open ports go to stderr and the event stream, and a host that answered and isn't in
+//             the host list yet is added to it.
+//TAKES: The port result and the host list.
+//RETURNS: Nothing.
+fn port_answered(result: &PortResult, host_list: &Db) {
+    if result.state == PortState::Open {
+        eprintln!("{}:{}/{} is open ({} ms)", result.host, result.port, result.proto, result.latency.as_millis());
+        events::emit(events::Event::PortOpen { host: result.host, port: result.port, protocol: result.proto, latency_ms: result.latency.as_millis() as u64 });
+    }
+    //An open port or a RST both mean something answered so the host is up.
+    if result.state.host_is_up() {
+        ////======ADD ANY NEW HOSTS TO THE HOSTS LIST===============//
+        if let Entry::Vacant(entry) = host_list.lock().unwrap().entry(format!("{}",result.host)) {
+            entry.insert("no_hostname".to_string());
+            events::emit(events::Event::HostFound { host: result.host, via: "portscan" });
+        }
+    }
+}
+
+//DESCRIPTION: Tries a full TCP connect to host:port and sorts the outcome into a PortState.
+//TAKES: The host to scan and the port to connect to.
+//RETURNS: PortResult with the state of the port and how long the probe took.
+async fn addr_portscan (host: IpAddr, port: u32) -> PortResult {
+    let socket: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
+    let probe_time = std::time::Instant::now();
+    let state = match tokio::time::timeout(Duration::from_secs(1), tokio::net::TcpStream::connect(socket)).await {
+        Ok(Ok(_)) => PortState::Open,
+        Ok(Err(e)) => match e.kind() {
+            io::ErrorKind::ConnectionRefused => PortState::Closed,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => PortState::Filtered,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => PortState::Unreachable,
+            _ => PortState::Error,
+        },
+        //Nothing came back within the second, SYN dropped somewhere.
+        Err(_) => PortState::Filtered,
+    };
+    PortResult { host, port, proto: "tcp", state, latency: probe_time.elapsed() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_match_known_dates() {
+        let known = [
+            (0, "1970-01-01T00:00:00Z"),
+            (-1, "1969-12-31T23:59:59Z"),
+            (951868799, "2000-02-29T23:59:59Z"),
+            (1706708700, "2024-01-31T13:45:00Z"),
+            (4107542400, "2100-03-01T00:00:00Z"),
+        ];
+        for (secs, timestamp) in known {
+            assert_eq!(format_timestamp(secs), timestamp);
+            assert_eq!(parse_timestamp(timestamp), Some(secs), "{}", timestamp);
+        }
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        //Every day from 1900 to 2200 at an odd time of day, leap years and century years included.
+        for day in -25567..84000 {
+            let secs = day * 86400 + 49_999;
+            assert_eq!(parse_timestamp(&format_timestamp(secs)), Some(secs), "{}", format_timestamp(secs));
+        }
+    }
+
+    #[test]
+    fn malformed_timestamps_are_rejected() {
+        for timestamp in ["", "Z", "2024-01-31", "2024-01-31T13:45:00", "2024-01-31 13:45:00Z", "2024-01-31T13:45Z", "2024-01-31T13:45:00:00Z", "2024-01-xxT13:45:00Z"] {
+            assert_eq!(parse_timestamp(timestamp), None, "{}", timestamp);
+        }
+    }
+}
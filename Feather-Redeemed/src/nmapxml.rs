@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::parse_timestamp;
+use crate::results::{HostReport, ScanReport};
+use crate::udpscan::UDP_SERVICES;
+
+//DESCRIPTION: Writes the report as nmap XML (what nmap -oX writes) to results.xml, so it can
+//             go anywhere nmap results go, like Metasploit's db_import.
+//TAKES: The report and the run folder to write to.
+//RETURNS: Nothing.
+pub fn write_nmap_xml(report: &ScanReport, out_dir: &Path) {
+    let xml_rfp = out_dir.join("results.xml");
+    let xml_rf = match File::create(&xml_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", xml_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut xml_buff = BufWriter::new(xml_rf);
+    xml_buff.write_all(nmap_xml(report).as_bytes()).expect("Unable to write to results.xml");
+}
+
+//DESCRIPTION: Builds the nmap XML document.
+//TAKES: The report.
+//RETURNS: The document.
+fn nmap_xml(report: &ScanReport) -> String {
+    let run = &report.run;
+    let start = parse_timestamp(&run.started).unwrap_or(0);
+    let finish = parse_timestamp(&run.finished).unwrap_or(0);
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE nmaprun>\n");
+    xml.push_str(&format!(
+        "<nmaprun scanner=\"valk2\" args=\"{}\" start=\"{}\" startstr=\"{}\" version=\"{}\" xmloutputversion=\"1.05\">\n",
+        escape(&run.command_line.join(" ")), start, escape(&run.started), escape(&run.version),
+    ));
+    let scan_type = if run.stages.iter().any(|stage| stage == "syn_scan") { "syn" } else { "connect" };
+    xml.push_str(&format!("<scaninfo type=\"{}\" protocol=\"tcp\" numservices=\"{}\" services=\"{}\"/>\n", scan_type, run.ports.len(), join_ports(&run.ports)));
+    if run.stages.iter().any(|stage| stage == "udp_scan") {
+        let udp_ports: Vec<u32> = UDP_SERVICES.iter().map(|service| service.port).collect();
+        xml.push_str(&format!("<scaninfo type=\"udp\" protocol=\"udp\" numservices=\"{}\" services=\"{}\"/>\n", udp_ports.len(), join_ports(&udp_ports)));
+    }
+    for host in report.hosts.iter() {
+        xml.push_str(&host_xml(host, start, finish));
+    }
+    //nmap marks a scan that didn't finish with exit="error" and says why.
+    let exit = if run.incomplete { "exit=\"error\" errormsg=\"Interrupted by a signal\"" } else { "exit=\"success\"" };
+    xml.push_str(&format!(
+        "<runstats><finished time=\"{}\" timestr=\"{}\" elapsed=\"{}\" summary=\"valk2 done at {}; {} IP addresses ({} hosts up) scanned in {} seconds\" {}/>",
+        finish, escape(&run.finished), run.duration_secs, escape(&run.finished), report.hosts.len(), report.hosts.len(), run.duration_secs, exit,
+    ));
+    //Only hosts that answered make it into the report so every one is up.
+    xml.push_str(&format!("<hosts up=\"{}\" down=\"0\" total=\"{}\"/>\n</runstats>\n</nmaprun>\n", report.hosts.len(), report.hosts.len()));
+    xml
+}
+
+//DESCRIPTION: Builds the <host> element for one host.
+//TAKES: The host and when the run started and finished.
+//RETURNS: The element.
+fn host_xml(host: &HostReport, start: i64, finish: i64) -> String {
+    //nmap's reason for calling a host up, as close as we get to it.
+    let reason = match host.discovery.as_str() {
+        "ping" => "echo-reply",
+        "portscan" => "syn-ack",
+        _ => "user-set",
+    };
+    let addrtype = match host.address {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    };
+    let mut xml = format!("<host starttime=\"{}\" endtime=\"{}\"><status state=\"up\" reason=\"{}\" reason_ttl=\"0\"/>\n", start, finish, reason);
+    xml.push_str(&format!("<address addr=\"{}\" addrtype=\"{}\"/>\n", host.address, addrtype));
+    match &host.hostname {
+        //Only rDNS names are PTR records, the rest came off certificates or sysName.
+        Some(hostname) => {
+            let name_type = if host.discovery == "rdns" { "PTR" } else { "user" };
+            xml.push_str(&format!("<hostnames>\n<hostname name=\"{}\" type=\"{}\"/>\n</hostnames>\n", escape(hostname), name_type));
+        },
+        None => xml.push_str("<hostnames>\n</hostnames>\n"),
+    }
+    xml.push_str("<ports>");
+    for port in host.ports.iter() {
+        let reason = if port.protocol == "udp" { "udp-response" } else { "syn-ack" };
+        xml.push_str(&format!(
+            "<port protocol=\"{}\" portid=\"{}\"><state state=\"{}\" reason=\"{}\" reason_ttl=\"0\"/>",
+            escape(&port.protocol), port.port, escape(&port.state), reason,
+        ));
+        //The full fingerprint is split the way nmap splits it, without one all we have is the summary.
+        let fingerprinted = host.enrichment.services.iter().find(|service| service.port == port.port && port.protocol == "tcp");
+        match fingerprinted {
+            Some(service) => {
+                xml.push_str(&format!("<service name=\"{}\"", escape(&service.service)));
+                for (attribute, value) in [("product", &service.product), ("version", &service.version), ("extrainfo", &service.info), ("hostname", &service.hostname), ("ostype", &service.os), ("devicetype", &service.device_type)] {
+                    if !value.is_empty() {
+                        xml.push_str(&format!(" {}=\"{}\"", attribute, escape(value)));
+                    }
+                }
+                xml.push_str(" method=\"probed\" conf=\"10\"/>");
+            },
+            None if !port.service.is_empty() => {
+                xml.push_str(&format!("<service name=\"{}\"", escape(&port.service)));
+                if !port.version.is_empty() {
+                    xml.push_str(&format!(" product=\"{}\"", escape(&port.version)));
+                }
+                xml.push_str(" method=\"probed\" conf=\"10\"/>");
+            },
+            None => {},
+        }
+        xml.push_str("</port>\n");
+    }
+    xml.push_str("</ports>\n");
+    if let Some(guess) = &host.enrichment.os {
+        xml.push_str(&format!("<os><osmatch name=\"{}\" accuracy=\"{}\" line=\"0\"/></os>\n", escape(&guess.family), guess.confidence));
+    }
+    xml.push_str("</host>\n");
+    xml
+}
+
+//DESCRIPTION: Joins ports the way nmap lists them in scaninfo.
+//TAKES: The ports.
+//RETURNS: "22,80,443"
+fn join_ports(ports: &[u32]) -> String {
+    let mut ports = ports.to_vec();
+    ports.sort();
+    ports.iter().map(|port| port.to_string()).collect::<Vec<String>>().join(",")
+}
+
+//DESCRIPTION: Escapes text for an XML attribute and drops the control characters XML can't hold.
+//TAKES: The text.
+//RETURNS: The escaped text.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => {},
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,164 @@
+use serde::Serialize;
+
+use crate::ber::der;
+
+//The first two NTLM messages are all we ever send or read. A NEGOTIATE gets the server to send back
+//a CHALLENGE, and the CHALLENGE names the machine, its domain and its Windows build without us
+//needing any credentials. The only AUTHENTICATE we ever send is one built to fail, for the LDAP
+//channel binding check.
+
+//What a server said about itself in its NTLM CHALLENGE.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NtlmInfo {
+    pub netbios_computer: String,
+    pub netbios_domain: String,
+    pub dns_computer: String,
+    pub dns_domain: String,
+    pub dns_forest: String,
+    pub os_build: String,
+}
+
+impl NtlmInfo {
+    //DESCRIPTION: The best name we have for the machine.
+    //TAKES: Nothing.
+    //RETURNS: The DNS name if it sent one, otherwise the NetBIOS name. Empty if it sent neither.
+    pub fn computer_name(&self) -> &str {
+        if self.dns_computer.is_empty() {&self.netbios_computer} else {&self.dns_computer}
+    }
+}
+
+//DESCRIPTION: Builds a bare NTLM NEGOTIATE asking for target info and the server's version.
+//TAKES: Nothing.
+//RETURNS: The message.
+pub fn ntlm_negotiate() -> Vec<u8> {
+    let mut negotiate = b"NTLMSSP\x00".to_vec();
+    negotiate.extend_from_slice(&1u32.to_le_bytes());
+    //Unicode, OEM, request target, sign, LM key, NTLM, always sign, extended session security, version, 128, key exchange, 56
+    negotiate.extend_from_slice(&0xE208_8297u32.to_le_bytes());
+    negotiate.extend_from_slice(&[0; 16]); //Domain and workstation, both empty
+    negotiate.extend_from_slice(&[0x06, 0x01, 0xB1, 0x1D, 0x00, 0x00, 0x00, 0x0F]); //Version
+    negotiate
+}
+
+//DESCRIPTION: Wraps an NTLM NEGOTIATE in an SPNEGO NegTokenInit, which is what SMB and LDAP want.
+//TAKES: Nothing.
+//RETURNS: The GSS-API token.
+pub fn spnego_ntlm_negotiate() -> Vec<u8> {
+    let spnego_oid = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x02];
+    let ntlmssp_oid = [0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0A];
+    let mech_types = der(0xA0, &der(0x30, &der(0x06, &ntlmssp_oid)));
+    let mech_token = der(0xA2, &der(0x04, &ntlm_negotiate()));
+    let neg_token_init = der(0xA0, &der(0x30, &[mech_types, mech_token].concat()));
+    der(0x60, &[der(0x06, &spnego_oid), neg_token_init].concat())
+}
+
+//DESCRIPTION: Finds an NTLM CHALLENGE anywhere in a reply. It is always wrapped in something
+//             (SPNEGO, a CredSSP TSRequest, an LDAP bind response) so skip the wrapping and go
+//             straight to its signature.
+//TAKES: The whole reply.
+//RETURNS: What was in the CHALLENGE, or None if there isn't one.
+pub fn find_ntlm_challenge(reply: &[u8]) -> Option<NtlmInfo> {
+    let start = reply.windows(8).position(|window| window == b"NTLMSSP\x00")?;
+    parse_ntlm_challenge(&reply[start..])
+}
+
+//DESCRIPTION: Pulls the target info names and OS version out of an NTLM CHALLENGE message.
+//TAKES: The message, starting at its NTLMSSP signature.
+//RETURNS: What was in it, or None if it isn't a CHALLENGE.
+fn parse_ntlm_challenge(message: &[u8]) -> Option<NtlmInfo> {
+    if message.len() < 48 || u32::from_le_bytes(message[8..12].try_into().unwrap()) != 2 {
+        return None;
+    }
+    let mut challenge = NtlmInfo::default();
+    let flags = u32::from_le_bytes(message[20..24].try_into().unwrap());
+    if flags & 0x0200_0000 != 0 && message.len() >= 56 {
+        let build = u16::from_le_bytes([message[50], message[51]]);
+        challenge.os_build = format!("{}.{}.{}", message[48], message[49], build);
+    }
+
+    for (id, value) in av_pairs(challenge_target_info(message)?) {
+        let units: Vec<u16> = value.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+        let text = String::from_utf16_lossy(&units);
+        match id {
+            1 => challenge.netbios_computer = text,
+            2 => challenge.netbios_domain = text,
+            3 => challenge.dns_computer = text,
+            4 => challenge.dns_domain = text,
+            5 => challenge.dns_forest = text,
+            _ => {},
+        }
+    }
+    Some(challenge)
+}
+
+//DESCRIPTION: Builds an NTLMv2 AUTHENTICATE for a made up user that answers the CHALLENGE in a
+//             reply. The proof is garbage so it can never log in, and the blob echoes the server's
+//             own target info with no channel bindings added. What the server complains about
+//             first says whether it wanted channel bindings.
+//TAKES: The whole reply holding the CHALLENGE.
+//RETURNS: The message, or None if there isn't a CHALLENGE in the reply.
+pub fn ntlm_bogus_authenticate(reply: &[u8]) -> Option<Vec<u8>> {
+    let start = reply.windows(8).position(|window| window == b"NTLMSSP\x00")?;
+    let message = &reply[start..];
+    if message.len() < 48 || u32::from_le_bytes(message[8..12].try_into().unwrap()) != 2 {
+        return None;
+    }
+    let target_info = challenge_target_info(message)?;
+    //Servers check the blob timestamp against their own clock, so use the one they sent.
+    let timestamp = av_pairs(target_info).into_iter().find(|(id, value)| *id == 7 && value.len() == 8).map(|(_, value)| value).unwrap_or(&[0; 8]);
+    let mut nt_response = vec![0x56; 16]; //NTProofStr
+    nt_response.extend_from_slice(&[0x01, 0x01, 0, 0, 0, 0, 0, 0]);
+    nt_response.extend_from_slice(timestamp);
+    nt_response.extend_from_slice(&[0x56; 8]); //Client challenge
+    nt_response.extend_from_slice(&[0; 4]);
+    nt_response.extend_from_slice(target_info);
+    nt_response.extend_from_slice(&[0; 4]);
+    let user: Vec<u8> = "valk2".encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+
+    //Fixed part is 88 bytes, then LM response, NT response, domain (empty), user, workstation (empty) and session key (empty).
+    let fields: [&[u8]; 6] = [&[0; 24], &nt_response, &[], &user, &[], &[]];
+    let mut authenticate = b"NTLMSSP\x00".to_vec();
+    authenticate.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = 88u32;
+    for field in fields {
+        authenticate.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        authenticate.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        authenticate.extend_from_slice(&offset.to_le_bytes());
+        offset += field.len() as u32;
+    }
+    authenticate.extend_from_slice(&0xE208_8215u32.to_le_bytes()); //As NEGOTIATE less OEM and LM key
+    authenticate.extend_from_slice(&[0x06, 0x01, 0xB1, 0x1D, 0x00, 0x00, 0x00, 0x0F]); //Version
+    authenticate.extend_from_slice(&[0; 16]); //MIC
+    for field in fields {
+        authenticate.extend_from_slice(field);
+    }
+    Some(authenticate)
+}
+
+//DESCRIPTION: Finds the target info in a CHALLENGE message.
+//TAKES: The message, starting at its NTLMSSP signature.
+//RETURNS: The target info bytes, or None if they run off the end.
+fn challenge_target_info(message: &[u8]) -> Option<&[u8]> {
+    let info_len = u16::from_le_bytes([message[40], message[41]]) as usize;
+    let info_offset = u32::from_le_bytes(message[44..48].try_into().unwrap()) as usize;
+    message.get(info_offset..info_offset + info_len)
+}
+
+//DESCRIPTION: Splits target info into its AV pairs: id, length, value. Id 0 ends the list.
+//TAKES: The target info.
+//RETURNS: Each id with its value, stopping at the end marker or anything that runs off the end.
+fn av_pairs(target_info: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut pairs = Vec::new();
+    let mut at = 0;
+    while at + 4 <= target_info.len() {
+        let id = u16::from_le_bytes([target_info[at], target_info[at + 1]]);
+        let len = u16::from_le_bytes([target_info[at + 2], target_info[at + 3]]) as usize;
+        let Some(value) = target_info.get(at + 4..at + 4 + len) else { break };
+        if id == 0 {
+            break;
+        }
+        pairs.push((id, value));
+        at += 4 + len;
+    }
+    pairs
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::Packet;
+use pnet::transport::{ipv4_packet_iter, transport_channel, TransportChannelType::Layer3};
+use serde::Serialize;
+
+const DEFAULT_OS_SIGNATURES: &str = include_str!("../os-signatures.txt");
+
+//How much each matching field adds to the confidence. They add up to 100.
+const TTL_WEIGHT: u32 = 30;
+const LAYOUT_WEIGHT: u32 = 45;
+const WINDOW_WEIGHT: u32 = 25;
+
+//What came back from a host that says something about its OS. Every host keeps one, a SYN/ACK
+//beats a ping reply since it has far more in it.
+#[derive(Clone, Debug, Serialize)]
+pub struct OsObservation {
+    pub ttl: u8,
+    pub window: Option<u16>,    //None for ping replies.
+    pub mss: Option<u16>,       //None for ping replies or a SYN/ACK without one.
+    pub layout: Option<String>, //None for ping replies.
+    pub source: &'static str,   //"SYN/ACK" or "ping"
+}
+
+pub type OsObservations = Arc<Mutex<HashMap<IpAddr, OsObservation>>>;
+
+//One line of the signature file. None is a * that matches anything.
+pub struct OsSignature {
+    pub family: String,
+    pub ttl: u8,
+    pub window: Option<u16>,
+    pub layout: Option<String>,
+}
+
+//Best guess for one host.
+#[derive(Clone, Debug, Serialize)]
+pub struct OsGuess {
+    pub host: IpAddr,
+    pub family: String,
+    pub confidence: u32,
+    pub observation: OsObservation,
+}
+
+//DESCRIPTION: Loads the signature file at path, or the built in one if path is None.
+//TAKES: Optional path to a signature file.
+//RETURNS: The signatures in file order. Panics on a file that can't be read or a faulty line.
+pub fn load_os_signatures(path: Option<&str>) -> Vec<OsSignature> {
+    match path {
+        Some(path) => {
+            let contents = match fs::read_to_string(path) {
+                Err(e) => panic!("Couldn't Read {}: {}", path, e),
+                Ok(contents) => contents,
+            };
+            parse_os_signatures(&contents, path)
+        },
+        None => parse_os_signatures(DEFAULT_OS_SIGNATURES, "built in OS signatures"),
+    }
+}
+
+//DESCRIPTION: Parses signature file text, <family>|<initial TTL>|<window>|<layout> per line.
+//TAKES: The file contents and a name to put in error messages.
+//RETURNS: The signatures in file order.
+fn parse_os_signatures(contents: &str, source: &str) -> Vec<OsSignature> {
+    let mut signatures = Vec::new();
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('|').map(|field| field.trim()).collect();
+        let faulty = || -> ! { panic!("Something is wrong with the formatting of {}: Faulty Line {} >{}<", source, line_no + 1, line) };
+        if fields.len() != 4 || fields[0].is_empty() {
+            faulty();
+        }
+        let ttl = match fields[1].parse::<u8>() {
+            Ok(ttl @ (32 | 64 | 128 | 255)) => ttl,
+            _ => faulty(),
+        };
+        let window = match fields[2] {
+            "*" => None,
+            window => Some(window.parse::<u16>().unwrap_or_else(|_| faulty())),
+        };
+        let layout = match fields[3] {
+            "*" => None,
+            layout => Some(layout.to_string()),
+        };
+        signatures.push(OsSignature { family: fields[0].to_string(), ttl, window, layout });
+    }
+    signatures
+}
+
+//DESCRIPTION: Pulls the TTL, window, MSS and option layout out of a SYN/ACK.
+//TAKES: The IPv4 packet and the TCP segment in it.
+//RETURNS: The observation.
+pub fn tcp_observation(ip_packet: &Ipv4Packet, tcp: &TcpPacket) -> OsObservation {
+    let header_len = (tcp.get_data_offset() as usize * 4).min(tcp.packet().len());
+    let options = tcp.packet().get(20..header_len).unwrap_or(&[]);
+    let mut layout = Vec::new();
+    let mut mss = None;
+    let mut at = 0;
+    while let Some(&kind) = options.get(at) {
+        let letter = match kind {
+            0 => "E",
+            1 => "N",
+            2 => "M",
+            3 => "W",
+            4 => "S",
+            8 => "T",
+            _ => "?",
+        };
+        layout.push(letter);
+        //End of list and nop are one byte, everything else says how long it is.
+        if kind == 0 {
+            break;
+        }
+        if kind == 1 {
+            at += 1;
+            continue;
+        }
+        let Some(&len) = options.get(at + 1) else { break };
+        if kind == 2 && len == 4 {
+            mss = options.get(at + 2..at + 4).map(|value| u16::from_be_bytes([value[0], value[1]]));
+        }
+        at += (len as usize).max(2);
+    }
+    OsObservation { ttl: ip_packet.get_ttl(), window: Some(tcp.get_window()), mss, layout: Some(layout.join(",")), source: "SYN/ACK" }
+}
+
+//DESCRIPTION: Keeps an observation for a host unless we already have a SYN/ACK from it.
+//TAKES: The observations, the host and what we saw.
+//RETURNS: Nothing.
+pub fn record_observation(observations: &OsObservations, host: IpAddr, observation: OsObservation) {
+    let mut observations = observations.lock().unwrap();
+    match observations.get(&host) {
+        Some(kept) if kept.source == "SYN/ACK" => {},
+        _ => {
+            observations.insert(host, observation);
+        },
+    }
+}
+
+//Watches a raw ICMP socket for echo replies while the pingsweep runs. The pings go out through
+//ping_rs, which only says whether a reply came back, so the TTL has to be picked up on the side.
+pub struct IcmpListener {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl IcmpListener {
+    //DESCRIPTION: Opens the raw socket and starts recording the TTL of every echo reply.
+    //TAKES: The observations to record into.
+    //RETURNS: The running listener, or the error from opening the raw socket (usually
+    //         PermissionDenied when we don't have CAP_NET_RAW).
+    pub fn start(observations: OsObservations) -> io::Result<IcmpListener> {
+        //Layer 3 so the IP header with the TTL in it is still there.
+        let (_, mut rx) = transport_channel(1 << 16, Layer3(IpNextHeaderProtocols::Icmp))?;
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut iter = ipv4_packet_iter(&mut rx);
+                while !done.load(Ordering::Relaxed) {
+                    match iter.next_with_timeout(Duration::from_millis(100)) {
+                        //Type 0 is an echo reply.
+                        Ok(Some((ip_packet, source))) if ip_packet.payload().first() == Some(&0) => {
+                            let observation = OsObservation { ttl: ip_packet.get_ttl(), window: None, mss: None, layout: None, source: "ping" };
+                            record_observation(&observations, source, observation);
+                        },
+                        Ok(_) => continue,
+                        Err(e) => {
+                            eprintln!("ICMP listener errored: {}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+        Ok(IcmpListener { done, thread })
+    }
+
+    //DESCRIPTION: Stops the listener once the pingsweep is over.
+    //TAKES: Nothing.
+    //RETURNS: Nothing.
+    pub fn stop(self) {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread.join().expect("ICMP listener thread panicked");
+    }
+}
+
+//DESCRIPTION: Guesses the OS family of every host we have an observation for and writes os.txt.
+//TAKES: The observations and the signatures to compare them against, and the run folder
+//       to write to.
+//RETURNS: A guess per host, sorted by address.
+pub fn guess_os(observations: &OsObservations, signatures: &[OsSignature], out_dir: &Path) -> Vec<OsGuess> {
+    let mut observations: Vec<(IpAddr, OsObservation)> = observations.lock().unwrap().iter().map(|(host, observation)| (*host, observation.clone())).collect();
+    observations.sort_by_key(|(host, _)| *host);
+
+    let os_rfp = out_dir.join("os.txt");
+    let os_rf = match File::create(&os_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", os_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut os_buff = BufWriter::new(os_rf);
+
+    let mut guesses = Vec::with_capacity(observations.len());
+    for (host, observation) in observations {
+        let mut best: Option<(&OsSignature, u32)> = None;
+        for signature in signatures {
+            let Some(score) = signature_score(signature, &observation) else { continue };
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((signature, score));
+            }
+        }
+        let (family, confidence) = match best {
+            Some((signature, score)) => (signature.family.clone(), score),
+            None => ("unknown".to_string(), 0),
+        };
+        let guess = OsGuess { host, family, confidence, observation };
+        let initial_ttl = initial_ttl(guess.observation.ttl);
+        eprintln!("{} {} ({}%) from {}", guess.host, guess.family, guess.confidence, guess.observation.source);
+        let block = format!(
+            "{} {}\n    Confidence: {}%\n    Seen In: {}\n    Initial TTL: {} ({} hops)\n    Window: {}\n    MSS: {}\n    Options: {}\n",
+            guess.host, guess.family, guess.confidence, guess.observation.source, initial_ttl, initial_ttl - guess.observation.ttl,
+            guess.observation.window.map(|window| window.to_string()).unwrap_or_default(),
+            guess.observation.mss.map(|mss| mss.to_string()).unwrap_or_default(),
+            guess.observation.layout.clone().unwrap_or_default(),
+        );
+        os_buff.write_all(block.as_bytes()).expect("Unable to write to os.txt");
+        guesses.push(guess);
+    }
+    guesses
+}
+
+//DESCRIPTION: Scores one signature against an observation. The initial TTL has to agree, and a
+//             signature that gives a window or layout only counts if the observation has the same.
+//TAKES: The signature and the observation.
+//RETURNS: The weight of the fields that matched out of 100, or None if the signature doesn't apply.
+fn signature_score(signature: &OsSignature, observation: &OsObservation) -> Option<u32> {
+    if signature.ttl != initial_ttl(observation.ttl) {
+        return None;
+    }
+    let mut score = TTL_WEIGHT;
+    if let Some(window) = signature.window {
+        if observation.window != Some(window) {
+            return None;
+        }
+        score += WINDOW_WEIGHT;
+    }
+    if let Some(layout) = &signature.layout {
+        if observation.layout.as_ref() != Some(layout) {
+            return None;
+        }
+        score += LAYOUT_WEIGHT;
+    }
+    Some(score)
+}
+
+//DESCRIPTION: Works out what a TTL started at. Every OS starts from one of a handful of values
+//             and each router on the way takes one off.
+//TAKES: The TTL that arrived.
+//RETURNS: 32, 64, 128 or 255.
+fn initial_ttl(ttl: u8) -> u8 {
+    match ttl {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
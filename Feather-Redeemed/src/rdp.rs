@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use crate::ber::der;
+use crate::ntlm::{self, NtlmInfo};
+use crate::tls::{self, TlsInfo};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+//A CredSSP reply with the CHALLENGE in it is well under this.
+const MAX_CREDSSP_REPLY: usize = 16384;
+const MAX_RDP_PROBES: usize = 64;
+
+pub const RDP_PORT: u32 = 3389;
+
+//Security protocols from the RDP negotiation request and response.
+const PROTOCOL_RDP: u32 = 0x0000_0000;
+const PROTOCOL_SSL: u32 = 0x0000_0001;
+const PROTOCOL_HYBRID: u32 = 0x0000_0002;
+
+//What an RDP server told us before anyone logged in.
+#[derive(Clone, Debug, Serialize)]
+pub struct RdpInfo {
+    pub host: IpAddr,
+    pub protocols: Vec<&'static str>,
+    pub nla_required: bool,
+    pub tls: Option<TlsInfo>,
+    pub ntlm: NtlmInfo,
+}
+
+//DESCRIPTION: Negotiates with every host that has 3389 open and writes rdp.txt. Hosts that
+//             let you connect without NLA also go in rdp_nla_not_required.txt.
+//TAKES: The hosts with 3389 open, the run folder to write to and the stop flag.
+//RETURNS: What every RDP server said.
+pub async fn rdp_enrich(hosts: &[IpAddr], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<RdpInfo> {
+    let connector = tls::insecure_tls_connector();
+    let mut probes = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let connector = connector.clone();
+        let host = *host;
+        probes.push(async move {
+            rdp_probe(&connector, host).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_RDP_PROBES, stop).await;
+
+    let rdp_rfp = out_dir.join("rdp.txt");
+    let rdp_rf = match File::create(&rdp_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", rdp_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut rdp_buff = BufWriter::new(rdp_rf);
+    let no_nla_rfp = out_dir.join("rdp_nla_not_required.txt");
+    let no_nla_rf = match File::create(&no_nla_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", no_nla_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut no_nla_buff = BufWriter::new(no_nla_rf);
+
+    let mut results = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        let nla = if info.nla_required {"required"} else {"NOT required"};
+        let name = info.ntlm.computer_name();
+        eprintln!("{}:{} {} NLA {} {}", info.host, RDP_PORT, info.protocols.join("/"), nla, name);
+        let (tls_line, cert_cn, cert_valid) = match &info.tls {
+            Some(tls) => (format!("{} {}", tls.protocol, tls.cipher), tls.subject_cn.clone(), format!("{} to {}", tls.not_before, tls.not_after)),
+            None => (String::new(), String::new(), String::new()),
+        };
+        let block = format!(
+            "{}:{} {}\n    Protocols: {}\n    NLA: {}\n    TLS: {}\n    Certificate CN: {}\n    Certificate Valid: {}\n    NetBIOS Name: {}\n    NetBIOS Domain: {}\n    DNS Name: {}\n    DNS Domain: {}\n    DNS Forest: {}\n    OS Build: {}\n",
+            info.host, RDP_PORT, name, info.protocols.join(", "), nla, tls_line, cert_cn, cert_valid,
+            info.ntlm.netbios_computer, info.ntlm.netbios_domain, info.ntlm.dns_computer, info.ntlm.dns_domain, info.ntlm.dns_forest, info.ntlm.os_build,
+        );
+        rdp_buff.write_all(block.as_bytes()).expect("Unable to write to rdp.txt");
+        if !info.nla_required {
+            writeln!(no_nla_buff, "{}", info.host).expect("Unable to write to rdp_nla_not_required.txt");
+        }
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: Works out which security protocols one RDP server takes. The first connection asks
+//             for TLS or CredSSP and, whichever it picks, carries on into TLS for the certificate
+//             and for CredSSP into NTLM for the names. Then we ask for plain RDP and TLS on their
+//             own to see if the server will settle for less than NLA.
+//TAKES: TLS connector and the host.
+//RETURNS: What we found, or None if nothing on 3389 spoke RDP.
+pub async fn rdp_probe(connector: &TlsConnector, host: IpAddr) -> Option<RdpInfo> {
+    let mut info = RdpInfo { host, protocols: Vec::new(), nla_required: false, tls: None, ntlm: NtlmInfo::default() };
+    let (stream, picked) = rdp_negotiate(host, PROTOCOL_SSL | PROTOCOL_HYBRID).await?;
+    let (mut rdp, mut ssl) = (picked == Ok(PROTOCOL_RDP), picked == Ok(PROTOCOL_SSL));
+    let credssp = picked == Ok(PROTOCOL_HYBRID);
+    if ssl || credssp {
+        //RDP servers only ever have the one certificate, so no SNI needed.
+        if let Some(mut tls_stream) = tls::tls_wrap(connector, stream, host, None).await {
+            let (_, session) = tls_stream.get_ref();
+            let mut tls_info = TlsInfo {
+                host,
+                port: RDP_PORT,
+                protocol: session.protocol_version().map(|version| tls::tls_version_name(u16::from(version))).unwrap_or_default(),
+                cipher: session.negotiated_cipher_suite().map(|suite| tls::cipher_suite_name(u16::from(suite.suite()))).unwrap_or_default(),
+                subject: String::new(),
+                subject_cn: String::new(),
+                issuer: String::new(),
+                sans: Vec::new(),
+                not_before: String::new(),
+                not_after: String::new(),
+                key_type: String::new(),
+            };
+            if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+                tls::fill_certificate(&mut tls_info, cert.as_ref());
+            }
+            info.tls = Some(tls_info);
+            if credssp {
+                info.ntlm = credssp_challenge(&mut tls_stream).await.unwrap_or_default();
+            }
+        }
+    }
+
+    if !rdp {
+        rdp = matches!(rdp_negotiate(host, PROTOCOL_RDP).await, Some((_, Ok(PROTOCOL_RDP))));
+    }
+    if credssp {
+        ssl = matches!(rdp_negotiate(host, PROTOCOL_SSL).await, Some((_, Ok(PROTOCOL_SSL))));
+    }
+    for (offered, name) in [(rdp, "RDP"), (ssl, "TLS"), (credssp, "CredSSP")] {
+        if offered {
+            info.protocols.push(name);
+        }
+    }
+    info.nla_required = credssp && !rdp && !ssl;
+    Some(info)
+}
+
+//DESCRIPTION: Sends an X.224 Connection Request with an RDP negotiation request for the given protocols.
+//TAKES: The host and the protocol flags to ask for.
+//RETURNS: The stream, left ready for TLS if one was picked, with Ok(the protocol picked) or
+//         Err(the failure code). None if it never answered like an RDP server.
+async fn rdp_negotiate(host: IpAddr, requested: u32) -> Option<(TcpStream, Result<u32, u32>)> {
+    let socket = SocketAddr::new(host, RDP_PORT as u16);
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    //TPKT header, X.224 CR (no cookie), then RDP_NEG_REQ.
+    let mut request = vec![0x03, 0x00, 0x00, 0x13, 0x0E, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x00];
+    request.extend_from_slice(&[0x01, 0x00, 0x08, 0x00]);
+    request.extend_from_slice(&requested.to_le_bytes());
+    stream.write_all(&request).await.ok()?;
+
+    let mut tpkt = [0u8; 4];
+    timeout(READ_TIMEOUT, stream.read_exact(&mut tpkt)).await.ok()?.ok()?;
+    let length = u16::from_be_bytes([tpkt[2], tpkt[3]]) as usize;
+    if tpkt[0] != 0x03 || !(11..=64).contains(&length) {
+        return None;
+    }
+    let mut reply = vec![0u8; length - 4];
+    timeout(READ_TIMEOUT, stream.read_exact(&mut reply)).await.ok()?.ok()?;
+    //X.224 Connection Confirm.
+    if reply[1] & 0xF0 != 0xD0 {
+        return None;
+    }
+    //Servers too old to know about negotiation send no RDP_NEG_RSP at all and only do plain RDP.
+    let Some(negotiation) = reply.get(7..15) else { return Some((stream, Ok(PROTOCOL_RDP))) };
+    let value = u32::from_le_bytes(negotiation[4..8].try_into().unwrap());
+    match negotiation[0] {
+        0x02 => Some((stream, Ok(value))),
+        0x03 => Some((stream, Err(value))),
+        _ => None,
+    }
+}
+
+//DESCRIPTION: Sends the first CredSSP TSRequest with an NTLM NEGOTIATE in it. The server answers
+//             with its CHALLENGE and we go no further.
+//TAKES: The TLS stream on a connection that negotiated CredSSP.
+//RETURNS: The names and build from the CHALLENGE, or None if it didn't send one.
+async fn credssp_challenge<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Option<NtlmInfo> {
+    //TSRequest { version 6, negoTokens [ { negoToken NEGOTIATE } ] }
+    let nego_tokens = der(0xA1, &der(0x30, &der(0x30, &der(0xA0, &der(0x04, &ntlm::ntlm_negotiate())))));
+    let ts_request = der(0x30, &[der(0xA0, &der(0x02, &[6])), nego_tokens].concat());
+    stream.write_all(&ts_request).await.ok()?;
+    stream.flush().await.ok()?;
+
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 4096];
+    while reply.len() < MAX_CREDSSP_REPLY {
+        let len = timeout(READ_TIMEOUT, stream.read(&mut buf)).await.ok()?.ok()?;
+        if len == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..len]);
+        if let Some(challenge) = ntlm::find_ntlm_challenge(&reply) {
+            return Some(challenge);
+        }
+    }
+    None
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+
+use crate::results::{HostReport, ScanReport};
+
+//(service, protocol, port) to (hosts, how many hosts run each version).
+type ServiceCounts = BTreeMap<(String, String, u32), (usize, BTreeMap<String, usize>)>;
+
+//Everything the page needs is inline so the one file can be attached to a deliverable as is.
+const REPORT_STYLE: &str = "
+body { font-family: -apple-system, 'Segoe UI', Helvetica, Arial, sans-serif; margin: 2em auto; max-width: 1200px; color: #222; }
+h1 { border-bottom: 2px solid #333; padding-bottom: .3em; }
+h2 { margin-top: 2em; border-bottom: 1px solid #ccc; padding-bottom: .2em; }
+table { border-collapse: collapse; width: 100%; margin: .5em 0; font-size: 14px; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+th { background: #eee; }
+th.sortable { cursor: pointer; user-select: none; }
+th.sortable:after { content: ' \\2195'; color: #999; }
+tr:nth-child(even) td { background: #f8f8f8; }
+.stats td:first-child { font-weight: bold; width: 14em; }
+.mono { font-family: Consolas, Menlo, monospace; }
+.none { color: #888; }
+";
+
+//Click a header to sort by it, again to reverse. Cells sort by data-sort when they have it.
+const REPORT_SCRIPT: &str = "
+document.querySelectorAll('table.sortable').forEach(function (table) {
+  table.querySelectorAll('th').forEach(function (th, column) {
+    th.classList.add('sortable');
+    th.addEventListener('click', function () {
+      var body = table.tBodies[0];
+      var rows = Array.from(body.rows);
+      var ascending = th.dataset.order !== 'asc';
+      th.dataset.order = ascending ? 'asc' : 'desc';
+      var key = function (row) {
+        var cell = row.cells[column];
+        var value = cell.dataset.sort !== undefined ? cell.dataset.sort : cell.textContent.trim().toLowerCase();
+        return isNaN(value) || value === '' ? value : Number(value);
+      };
+      rows.sort(function (a, b) {
+        var x = key(a), y = key(b);
+        if (x === y) return 0;
+        return (x < y ? -1 : 1) * (ascending ? 1 : -1);
+      });
+      rows.forEach(function (row) { body.appendChild(row); });
+    });
+  });
+});
+";
+
+//DESCRIPTION: Writes the report out as one self-contained HTML page.
+//TAKES: The report, where it came from and the file to write.
+//RETURNS: Nothing.
+pub fn write_html_report(report: &ScanReport, source: &str, path: &str) {
+    let report_rf = match File::create(path) {
+        Err(e) => panic!("Couldn't Create {}: {}", path, e),
+        Ok(file) => file,
+    };
+    let mut report_buff = BufWriter::new(report_rf);
+    report_buff.write_all(html_report(report, source).as_bytes()).expect("Unable to write to the report");
+}
+
+//DESCRIPTION: Builds the page: summary, subnets, hosts, services and exclusions.
+//TAKES: The report and where it came from.
+//RETURNS: The page.
+fn html_report(report: &ScanReport, source: &str) -> String {
+    let run = &report.run;
+    let open_ports: usize = report.hosts.iter().map(|host| host.ports.len()).sum();
+    let named = report.hosts.iter().filter(|host| host.hostname.is_some()).count();
+    let mut discovery: BTreeMap<&str, usize> = BTreeMap::new();
+    for host in report.hosts.iter() {
+        *discovery.entry(host.discovery.as_str()).or_default() += 1;
+    }
+    let discovery: Vec<String> = discovery.iter().map(|(via, count)| format!("{} {}", count, via)).collect();
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>valk2 scan report {}</title>\n<style>{}</style>\n</head>\n<body>\n", escape(&run.started), REPORT_STYLE));
+    html.push_str(&format!("<h1>valk2 scan report</h1>\n<p class=\"none\">Generated from {}</p>\n", escape(source)));
+
+    html.push_str("<h2>Summary</h2>\n<table class=\"stats\">\n");
+    let stats = [
+        ("Started", run.started.clone()),
+        ("Finished", run.finished.clone()),
+        ("Duration", duration(run.duration_secs)),
+        ("Completed", if run.incomplete { "No, stopped early. Only what was found before then is here.".to_string() } else { "Yes".to_string() }),
+        ("Targets", report.targets.join(", ")),
+        ("Stages", run.stages.join(", ")),
+        ("TCP ports scanned", run.ports.iter().map(|port| port.to_string()).collect::<Vec<String>>().join(", ")),
+        ("Hosts up", report.hosts.len().to_string()),
+        ("Found by", discovery.join(", ")),
+        ("Hosts with a hostname", named.to_string()),
+        ("Subnets with hosts", report.subnets.len().to_string()),
+        ("Open ports", open_ports.to_string()),
+        ("Command line", run.command_line.join(" ")),
+    ];
+    for (name, value) in stats {
+        html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", name, escape(&value)));
+    }
+    html.push_str("</table>\n");
+
+    let subnets = subnet_counts(report);
+    html.push_str(&format!("<h2>Subnets ({})</h2>\n", subnets.len()));
+    html.push_str("<table class=\"sortable\">\n<thead><tr><th>Subnet</th><th>Hosts</th><th>Open ports</th></tr></thead>\n<tbody>\n");
+    for (subnet, hosts, ports) in subnets {
+        html.push_str(&format!(
+            "<tr><td class=\"mono\" data-sort=\"{}\">{}</td><td>{}</td><td>{}</td></tr>\n",
+            subnet_sort_key(&subnet), escape(&subnet), hosts, ports,
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    html.push_str(&format!("<h2>Hosts ({})</h2>\n", report.hosts.len()));
+    html.push_str("<table class=\"sortable\">\n<thead><tr><th>Address</th><th>Hostname</th><th>Found by</th><th>Open</th><th>Ports</th></tr></thead>\n<tbody>\n");
+    for host in report.hosts.iter() {
+        html.push_str(&host_row(host));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    let services = service_counts(report);
+    html.push_str(&format!("<h2>Services ({})</h2>\n", services.len()));
+    html.push_str("<table class=\"sortable\">\n<thead><tr><th>Service</th><th>Port</th><th>Hosts</th><th>Versions</th></tr></thead>\n<tbody>\n");
+    for ((service, protocol, port), (hosts, versions)) in services.iter() {
+        let versions: Vec<String> = versions.iter().map(|(version, count)| format!("{} ({})", escape(version), count)).collect();
+        html.push_str(&format!(
+            "<tr><td>{}</td><td data-sort=\"{}\">{}/{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(service), port, port, protocol, hosts, versions.join("<br>"),
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    html.push_str("<h2>Exclusions</h2>\n");
+    if report.exclusions.hosts.is_empty() && report.exclusions.subnets.is_empty() {
+        html.push_str("<p class=\"none\">Nothing was excluded.</p>\n");
+    }
+    else {
+        html.push_str("<table>\n<thead><tr><th>Excluded</th><th>Kind</th></tr></thead>\n<tbody>\n");
+        for subnet in report.exclusions.subnets.iter() {
+            html.push_str(&format!("<tr><td class=\"mono\">{}</td><td>subnet</td></tr>\n", escape(subnet)));
+        }
+        for host in report.exclusions.hosts.iter() {
+            html.push_str(&format!("<tr><td class=\"mono\">{}</td><td>host</td></tr>\n", escape(host)));
+        }
+        html.push_str("</tbody>\n</table>\n");
+    }
+
+    html.push_str(&format!("<script>{}</script>\n</body>\n</html>\n", REPORT_SCRIPT));
+    html
+}
+
+//DESCRIPTION: Builds one row of the host table.
+//TAKES: The host.
+//RETURNS: The row.
+fn host_row(host: &HostReport) -> String {
+    let hostname = match &host.hostname {
+        Some(hostname) => escape(hostname),
+        None => "<span class=\"none\">no_hostname</span>".to_string(),
+    };
+    let ports: Vec<String> = host.ports.iter().map(|port| {
+        let service = format!("{} {}", port.service, port.version);
+        match service.trim() {
+            "" => format!("{}/{}", port.port, port.protocol),
+            service => format!("{}/{} {}", port.port, port.protocol, escape(service)),
+        }
+    }).collect();
+    format!(
+        "<tr><td class=\"mono\" data-sort=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+        address_sort_key(&host.address), host.address, hostname, escape(&host.discovery), host.ports.len(), ports.join("<br>"),
+    )
+}
+
+//DESCRIPTION: Counts hosts and open ports per /24. Subnets from the run with no host in the
+//             report (every host excluded) are still listed with 0.
+//TAKES: The report.
+//RETURNS: (subnet, hosts, open ports) in address order.
+fn subnet_counts(report: &ScanReport) -> Vec<(String, usize, usize)> {
+    let mut counts: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for subnet in report.subnets.iter() {
+        counts.entry(subnet.clone()).or_default();
+    }
+    for host in report.hosts.iter() {
+        let IpAddr::V4(v4) = host.address else { continue };
+        let octets = v4.octets();
+        let count = counts.entry(format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])).or_default();
+        count.0 += 1;
+        count.1 += host.ports.len();
+    }
+    let mut counts: Vec<(String, usize, usize)> = counts.into_iter().map(|(subnet, (hosts, ports))| (subnet, hosts, ports)).collect();
+    counts.sort_by_key(|(subnet, _, _)| subnet_sort_key(subnet));
+    counts
+}
+
+//DESCRIPTION: Groups the open ports by service and port, with how many hosts have each version.
+//TAKES: The report.
+//RETURNS: (service, protocol, port) to (hosts, version counts).
+fn service_counts(report: &ScanReport) -> ServiceCounts {
+    let mut services = ServiceCounts::new();
+    for host in report.hosts.iter() {
+        for port in host.ports.iter() {
+            let service = if port.service.is_empty() { "unknown".to_string() } else { port.service.clone() };
+            let entry = services.entry((service, port.protocol.clone(), port.port)).or_default();
+            entry.0 += 1;
+            if !port.version.is_empty() {
+                *entry.1.entry(port.version.clone()).or_default() += 1;
+            }
+        }
+    }
+    services
+}
+
+//DESCRIPTION: Pads an address so it sorts as a number in the table.
+//TAKES: The address.
+//RETURNS: A fixed width number.
+fn address_sort_key(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => format!("{:039}", u32::from(*v4)),
+        IpAddr::V6(v6) => format!("{:039}", u128::from(*v6)),
+    }
+}
+
+//DESCRIPTION: The sort key of a "10.1.2.0/24".
+//TAKES: The subnet.
+//RETURNS: Its address's key, or the subnet itself if it isn't one.
+fn subnet_sort_key(subnet: &str) -> String {
+    match subnet.trim_end_matches("/24").parse::<IpAddr>() {
+        Ok(address) => address_sort_key(&address),
+        Err(_) => subnet.to_string(),
+    }
+}
+
+//DESCRIPTION: How long the run took, the way a person would say it.
+//TAKES: Seconds.
+//RETURNS: "2h 5m 12s"
+fn duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m {}s", secs / 3600, secs % 3600 / 60, secs % 60),
+    }
+}
+
+//DESCRIPTION: Escapes text for HTML.
+//TAKES: The text.
+//RETURNS: The escaped text.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::banner::Banner;
+use crate::classify::Classification;
+use crate::database::DatabaseInfo;
+use crate::fingerprint::ServiceInfo;
+use crate::http::HttpInfo;
+use crate::ldap::LdapInfo;
+use crate::osfingerprint::OsGuess;
+use crate::rdp::RdpInfo;
+use crate::smb::SmbInfo;
+use crate::snmp::SnmpInfo;
+use crate::ssh::SshInfo;
+use crate::tls::TlsInfo;
+use crate::udpscan::UDP_SERVICES;
+use crate::{format_timestamp, PortResult, PortState};
+
+//Everything one run found, as a single document.
+#[derive(Serialize, Deserialize)]
+pub struct ScanReport {
+    pub run: RunInfo,
+    pub targets: Vec<String>,
+    pub exclusions: Exclusions,
+    pub subnets: Vec<String>, //The /24s that had hosts.
+    pub hosts: Vec<HostReport>,
+}
+
+//When and how valk2 was run.
+#[derive(Serialize, Deserialize)]
+pub struct RunInfo {
+    pub version: String,
+    pub command_line: Vec<String>,
+    pub started: String,
+    pub finished: String,
+    pub duration_secs: u64,
+    pub stages: Vec<String>, //Every stage that was turned on.
+    pub ports: Vec<u32>,     //TCP ports portscanned.
+    pub incomplete: bool,    //Stopped by Ctrl-C or SIGTERM before it finished.
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Exclusions {
+    pub hosts: Vec<String>,
+    pub subnets: Vec<String>,
+}
+
+//One host and everything we know about it.
+#[derive(Serialize, Deserialize)]
+pub struct HostReport {
+    pub address: IpAddr,
+    pub hostname: Option<String>,
+    pub discovery: String, //"rdns", "ping" or "portscan", whichever found it first.
+    pub ports: Vec<PortReport>,
+    //Only written. Anything reading a report back in gets the hosts and ports.
+    #[serde(skip_deserializing)]
+    pub enrichment: HostEnrichment,
+}
+
+//One open port. service is the fingerprinted service (or the one a UDP probe was built for) and
+//version is the product, version and extra info, both empty when nothing identified it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortReport {
+    pub port: u32,
+    pub protocol: String,
+    pub state: String,
+    pub service: String,
+    pub version: String,
+    pub latency_ms: u64,
+}
+
+//What each enrichment stage found on a host. Stages that were off or found nothing are left out.
+#[derive(Default, Serialize)]
+pub struct HostEnrichment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<Classification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<OsGuess>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub banners: Vec<Banner>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tls: Vec<TlsInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub http: Vec<HttpInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smb: Option<SmbInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ssh: Vec<SshInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rdp: Option<RdpInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ldap: Vec<LdapInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub databases: Vec<DatabaseInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snmp: Option<SnmpInfo>,
+}
+
+//What every stage handed back, kept until the end of the run to build the report from.
+#[derive(Default)]
+pub struct StageResults {
+    pub port_results: Vec<PortResult>,
+    pub banners: Vec<Banner>,
+    pub services: Vec<ServiceInfo>,
+    pub certificates: Vec<TlsInfo>,
+    pub pages: Vec<HttpInfo>,
+    pub smb: Vec<SmbInfo>,
+    pub ssh: Vec<SshInfo>,
+    pub rdp: Vec<RdpInfo>,
+    pub ldap: Vec<LdapInfo>,
+    pub databases: Vec<DatabaseInfo>,
+    pub os_guesses: Vec<OsGuess>,
+    pub snmp: Vec<SnmpInfo>,
+    pub classifications: Vec<Classification>,
+}
+
+//DESCRIPTION: Fills in the run details once the run is over.
+//TAKES: When the run started, the stages that were on, the TCP ports scanned and whether it was
+//       stopped early.
+//RETURNS: The run details, finished now.
+pub fn run_info(started: SystemTime, stages: Vec<String>, ports: Vec<u32>, incomplete: bool) -> RunInfo {
+    let finished = SystemTime::now();
+    let epoch_secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0);
+    RunInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: std::env::args().collect(),
+        started: format_timestamp(epoch_secs(started)),
+        finished: format_timestamp(epoch_secs(finished)),
+        duration_secs: finished.duration_since(started).map(|took| took.as_secs()).unwrap_or(0),
+        stages,
+        ports,
+        incomplete,
+    }
+}
+
+//DESCRIPTION: Puts every host together with its ports and whatever the stages found on it.
+//TAKES: The run details, the targets, the exclusions, the subnets with hosts, the final host list,
+//       the host list as it was before portscanning (to tell how each host was found) and the
+//       stage results.
+//RETURNS: The report, hosts sorted by address.
+pub fn build_report(run: RunInfo, targets: Vec<String>, exclusions: Exclusions, subnets: &[String], hostnames: &HashMap<String, String>, discovered: &HashMap<String, String>, stages: StageResults) -> ScanReport {
+    let mut hosts: HashMap<IpAddr, HostReport> = HashMap::new();
+    for (ip, hostname) in hostnames {
+        let Ok(address) = ip.parse::<IpAddr>() else { continue };
+        let discovery = match discovered.get(ip).map(|hostname| hostname.as_str()) {
+            Some("no_hostname") => "ping",
+            Some(_) => "rdns",
+            None => "portscan",
+        };
+        let hostname = Some(hostname.clone()).filter(|hostname| hostname != "no_hostname");
+        hosts.insert(address, HostReport { address, hostname, discovery: discovery.to_string(), ports: Vec::new(), enrichment: HostEnrichment::default() });
+    }
+
+    for result in stages.port_results.iter().filter(|result| result.state == PortState::Open) {
+        let Some(host) = hosts.get_mut(&result.host) else { continue };
+        let fingerprinted = stages.services.iter().find(|service| service.host == result.host && service.port == result.port && result.proto == "tcp");
+        let (service, version) = match fingerprinted {
+            Some(service) => (service.service.clone(), service.summary()),
+            None if result.proto == "udp" => {
+                let name = UDP_SERVICES.iter().find(|service| service.port == result.port).map(|service| service.name.to_string());
+                (name.unwrap_or_default(), String::new())
+            },
+            None => (String::new(), String::new()),
+        };
+        host.ports.push(PortReport {
+            port: result.port,
+            protocol: result.proto.to_string(),
+            state: result.state.name().to_string(),
+            service,
+            version,
+            latency_ms: result.latency.as_millis() as u64,
+        });
+    }
+
+    //Each stage's results go to the host they came from.
+    for result in stages.classifications {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.category = Some(result);
+        }
+    }
+    for result in stages.os_guesses {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.os = Some(result);
+        }
+    }
+    for result in stages.banners {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.banners.push(result);
+        }
+    }
+    for result in stages.services {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.services.push(result);
+        }
+    }
+    for result in stages.certificates {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.tls.push(result);
+        }
+    }
+    for result in stages.pages {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.http.push(result);
+        }
+    }
+    for result in stages.smb {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.smb = Some(result);
+        }
+    }
+    for result in stages.ssh {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.ssh.push(result);
+        }
+    }
+    for result in stages.rdp {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.rdp = Some(result);
+        }
+    }
+    for result in stages.ldap {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.ldap.push(result);
+        }
+    }
+    for result in stages.databases {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.databases.push(result);
+        }
+    }
+    for result in stages.snmp {
+        if let Some(host) = hosts.get_mut(&result.host) {
+            host.enrichment.snmp = Some(result);
+        }
+    }
+
+    let mut hosts: Vec<HostReport> = hosts.into_values().collect();
+    hosts.sort_by_key(|host| host.address);
+    for host in hosts.iter_mut() {
+        host.ports.sort_by(|a, b| (a.protocol.as_str(), a.port).cmp(&(b.protocol.as_str(), b.port)));
+    }
+    ScanReport { run, targets, exclusions, subnets: subnets.to_vec(), hosts }
+}
+
+//DESCRIPTION: Reads a report back from a results.json. Enrichment isn't read back, just the run,
+//             hosts and ports.
+//TAKES: Path to the file.
+//RETURNS: The report. Panics if the file can't be read or isn't a valk2 report.
+pub fn load_json(path: &str) -> ScanReport {
+    let contents = match fs::read_to_string(path) {
+        Err(e) => panic!("Couldn't Read {}: {}", path, e),
+        Ok(contents) => contents,
+    };
+    match serde_json::from_str(&contents) {
+        Err(e) => panic!("{} isn't a valk2 results.json: {}", path, e),
+        Ok(report) => report,
+    }
+}
+
+//DESCRIPTION: Writes the report to results.json.
+//TAKES: The report and the run folder to write to.
+//RETURNS: Nothing.
+pub fn write_json(report: &ScanReport, out_dir: &Path) {
+    let results_rfp = out_dir.join("results.json");
+    let results_rf = match File::create(&results_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", results_rfp.display(), e),
+        Ok(file) => file,
+    };
+    serde_json::to_writer_pretty(BufWriter::new(results_rf), report).expect("Unable to write to results.json");
+}
+
+//DESCRIPTION: Writes results.csv, one row per open port with the host's name, and one row
+//             with the port columns empty for hosts with nothing open.
+//TAKES: The report and the run folder to write to.
+//RETURNS: Nothing.
+pub fn write_csv(report: &ScanReport, out_dir: &Path) {
+    let csv_rfp = out_dir.join("results.csv");
+    let csv_rf = match File::create(&csv_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", csv_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut csv_buff = BufWriter::new(csv_rf);
+    csv_buff.write_all(b"ip,hostname,discovery,protocol,port,state,service,version\n").expect("Unable to write to results.csv");
+    for host in report.hosts.iter() {
+        let hostname = host.hostname.as_deref().unwrap_or("");
+        let mut rows: Vec<[String; 5]> = host.ports.iter()
+            .map(|port| [port.protocol.clone(), port.port.to_string(), port.state.clone(), port.service.clone(), port.version.clone()])
+            .collect();
+        if rows.is_empty() {
+            rows.push(Default::default());
+        }
+        for row in rows {
+            let fields: Vec<String> = [host.address.to_string(), hostname.to_string(), host.discovery.clone()].into_iter().chain(row)
+                .map(|field| csv_field(&field))
+                .collect();
+            csv_buff.write_all(format!("{}\n", fields.join(",")).as_bytes()).expect("Unable to write to results.csv");
+        }
+    }
+}
+
+//DESCRIPTION: Writes results.gnmap, one line per host laid out like nmap -oG so it can be
+//             grepped and cut the same way:
+//             Host: 10.0.0.5 (fs01.corp.local)\tStatus: Up\tPorts: 445/open/tcp//smb//Samba 4.x/
+//TAKES: The report and the run folder to write to.
+//RETURNS: Nothing.
+pub fn write_grepable(report: &ScanReport, out_dir: &Path) {
+    let grep_rfp = out_dir.join("results.gnmap");
+    let grep_rf = match File::create(&grep_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", grep_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut grep_buff = BufWriter::new(grep_rf);
+    grep_buff.write_all(format!("# valk2 {} scan initiated {} as: {}\n", report.run.version, report.run.started, report.run.command_line.join(" ")).as_bytes()).expect("Unable to write to results.gnmap");
+    for host in report.hosts.iter() {
+        //Slashes and commas split the port fields, so they can't be in a value.
+        let clean = |value: &str| value.replace('/', "|").replace(',', ";");
+        let ports: Vec<String> = host.ports.iter()
+            .map(|port| format!("{}/{}/{}//{}//{}/", port.port, port.state, port.protocol, clean(&port.service), clean(&port.version)))
+            .collect();
+        let mut line = format!("Host: {} ({})\tStatus: Up\tFound: {}", host.address, host.hostname.as_deref().unwrap_or(""), host.discovery);
+        if !ports.is_empty() {
+            line.push_str(&format!("\tPorts: {}", ports.join(", ")));
+        }
+        if let Some(guess) = &host.enrichment.os {
+            line.push_str(&format!("\tOS: {}", guess.family));
+        }
+        if let Some(classification) = &host.enrichment.category {
+            line.push_str(&format!("\tCategory: {}", classification.category));
+        }
+        grep_buff.write_all(format!("{}\n", line).as_bytes()).expect("Unable to write to results.gnmap");
+    }
+    let done = if report.run.incomplete { "interrupted" } else { "done" };
+    grep_buff.write_all(format!("# valk2 {} at {} -- {} hosts up, scanned in {} seconds\n", done, report.run.finished, report.hosts.len(), report.run.duration_secs).as_bytes()).expect("Unable to write to results.gnmap");
+}
+
+//DESCRIPTION: Quotes a CSV field if it needs it.
+//TAKES: The field.
+//RETURNS: The field, in double quotes with its own quotes doubled if it has a comma, quote or newline.
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    }
+    else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::ntlm::{self, NtlmInfo};
+use crate::tls::fill_random;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+//Negotiate and session setup replies are a few hundred bytes, anything past this is not SMB.
+const MAX_SMB_MESSAGE: usize = 65536;
+const MAX_SMB_PROBES: usize = 64;
+
+pub const SMB_PORT: u32 = 445;
+
+//Every SMB2/3 dialect we ask about, lowest first.
+const SMB2_DIALECTS: &[(u16, &str)] = &[
+    (0x0202, "SMB 2.0.2"),
+    (0x0210, "SMB 2.1"),
+    (0x0300, "SMB 3.0"),
+    (0x0302, "SMB 3.0.2"),
+    (0x0311, "SMB 3.1.1"),
+];
+
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_MORE_PROCESSING_REQUIRED: u32 = 0xC000_0016;
+
+//What an SMB server told us before we ever logged in.
+#[derive(Clone, Debug, Serialize)]
+pub struct SmbInfo {
+    pub host: IpAddr,
+    pub dialects: Vec<&'static str>,
+    pub smb1: bool,
+    pub signing_required: bool,
+    pub ntlm: NtlmInfo,
+}
+
+//DESCRIPTION: Negotiates with every host that has 445 open and writes smb.txt. Hosts that
+//             don't require signing also go in smb_signing_not_required.txt, one IP a line,
+//             ready to be handed to a relay tool.
+//TAKES: The hosts with 445 open, the run folder to write to and the stop flag.
+//RETURNS: What every SMB server said.
+pub async fn smb_enrich(hosts: &[IpAddr], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<SmbInfo> {
+    let mut probes = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let host = *host;
+        probes.push(async move {
+            smb_probe(host).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_SMB_PROBES, stop).await;
+
+    let smb_rfp = out_dir.join("smb.txt");
+    let smb_rf = match File::create(&smb_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", smb_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut smb_buff = BufWriter::new(smb_rf);
+    let unsigned_rfp = out_dir.join("smb_signing_not_required.txt");
+    let unsigned_rf = match File::create(&unsigned_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", unsigned_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut unsigned_buff = BufWriter::new(unsigned_rf);
+
+    let mut results = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        let signing = if info.signing_required {"required"} else {"NOT required"};
+        let name = info.ntlm.computer_name();
+        eprintln!("{}:{} {} signing {} {}", info.host, SMB_PORT, info.dialects.last().unwrap_or(&"SMB 1"), signing, name);
+        let block = format!(
+            "{}:{} {}\n    Dialects: {}\n    SMBv1: {}\n    Signing: {}\n    NetBIOS Name: {}\n    NetBIOS Domain: {}\n    DNS Name: {}\n    DNS Domain: {}\n    DNS Forest: {}\n    OS Build: {}\n",
+            info.host, SMB_PORT, name, info.dialects.join(", "), if info.smb1 {"enabled"} else {"disabled"}, signing,
+            info.ntlm.netbios_computer, info.ntlm.netbios_domain, info.ntlm.dns_computer, info.ntlm.dns_domain, info.ntlm.dns_forest, info.ntlm.os_build,
+        );
+        smb_buff.write_all(block.as_bytes()).expect("Unable to write to smb.txt");
+        if !info.signing_required {
+            writeln!(unsigned_buff, "{}", info.host).expect("Unable to write to smb_signing_not_required.txt");
+        }
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: Works out the dialects, signing, SMBv1 and NTLM names for one host. One connection
+//             negotiates everything we know and starts an NTLM login to get the CHALLENGE, then one
+//             connection per dialect finds out which of them the server will actually take.
+//TAKES: The host.
+//RETURNS: What we found, or None if nothing on 445 spoke SMB.
+pub async fn smb_probe(host: IpAddr) -> Option<SmbInfo> {
+    let smb1_signing = smb1_negotiate(host).await;
+    let all_dialects: Vec<u16> = SMB2_DIALECTS.iter().map(|(dialect, _)| *dialect).collect();
+    let mut stream = smb_connect(host).await?;
+    let (smb2, signing_required, ntlm) = match smb2_negotiate(&mut stream, &all_dialects).await {
+        Some((_, security_mode)) => (true, security_mode & 0x02 != 0, ntlm_challenge(&mut stream).await.unwrap_or_default()),
+        //SMB1 only box, the signing answer comes from the SMB1 negotiate instead.
+        None => (false, smb1_signing?, NtlmInfo::default()),
+    };
+    let mut info = SmbInfo {
+        host,
+        dialects: Vec::new(),
+        smb1: smb1_signing.is_some(),
+        signing_required,
+        ntlm,
+    };
+
+    for (dialect, name) in SMB2_DIALECTS.iter().filter(|_| smb2) {
+        let Some(mut stream) = smb_connect(host).await else { continue };
+        if let Some((picked, _)) = smb2_negotiate(&mut stream, &[*dialect]).await {
+            if picked == *dialect {
+                info.dialects.push(name);
+            }
+        }
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Opens a connection to 445.
+//TAKES: The host.
+//RETURNS: The stream, or None if it didn't connect in time.
+async fn smb_connect(host: IpAddr) -> Option<TcpStream> {
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(SocketAddr::new(host, SMB_PORT as u16))).await {
+        Ok(Ok(stream)) => Some(stream),
+        _ => None,
+    }
+}
+
+//DESCRIPTION: Sends one SMB message with its NetBIOS session header and reads back the reply.
+//TAKES: The stream and the SMB message without the 4 byte session header.
+//RETURNS: The reply without its session header, or None on timeout or a hang up.
+async fn smb_exchange(stream: &mut TcpStream, message: &[u8]) -> Option<Vec<u8>> {
+    let mut framed = Vec::with_capacity(message.len() + 4);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    let exchange = async {
+        stream.write_all(&framed).await.ok()?;
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await.ok()?;
+        let length = u32::from_be_bytes(header) as usize & 0x00FF_FFFF;
+        if header[0] != 0 || length > MAX_SMB_MESSAGE {
+            return None;
+        }
+        let mut reply = vec![0u8; length];
+        stream.read_exact(&mut reply).await.ok()?;
+        Some(reply)
+    };
+    timeout(READ_TIMEOUT, exchange).await.ok().flatten()
+}
+
+//DESCRIPTION: Asks for SMB1 with nothing but the NT LM 0.12 dialect on offer, so a server with SMBv1
+//             turned off has nothing it can pick.
+//TAKES: The host.
+//RETURNS: Whether signing is required if SMBv1 is enabled, None if it is not.
+async fn smb1_negotiate(host: IpAddr) -> Option<bool> {
+    let mut stream = smb_connect(host).await?;
+    let mut request = vec![0xFF, b'S', b'M', b'B', 0x72];
+    request.extend_from_slice(&[0; 4]); //Status
+    request.push(0x18); //Flags: case insensitive, canonical paths
+    request.extend_from_slice(&0xC801u16.to_le_bytes()); //Flags2: unicode, NT status, extended security, long names
+    request.extend_from_slice(&[0; 12]); //PID high, security features, reserved
+    request.extend_from_slice(&[0, 0, 0xFF, 0xFE, 0, 0, 0, 0]); //TID, PID, UID, MID
+    let dialects = b"\x02NT LM 0.12\x00";
+    request.push(0); //Word count
+    request.extend_from_slice(&(dialects.len() as u16).to_le_bytes());
+    request.extend_from_slice(dialects);
+
+    let reply = smb_exchange(&mut stream, &request).await?;
+    if reply.len() < 35 || reply[0..4] != [0xFF, b'S', b'M', b'B'] || reply[4] != 0x72 {
+        return None;
+    }
+    let status = u32::from_le_bytes(reply[5..9].try_into().unwrap());
+    let dialect_index = u16::from_le_bytes([reply[33], reply[34]]);
+    if status != STATUS_SUCCESS || dialect_index == 0xFFFF {
+        return None;
+    }
+    //Security mode bit 3 is signatures required.
+    Some(reply.get(35).map(|mode| mode & 0x08 != 0).unwrap_or(false))
+}
+
+//DESCRIPTION: Sends an SMB2 NEGOTIATE offering the given dialects. 3.1.1 needs negotiate contexts
+//             so those get tacked on the end when it is on offer.
+//TAKES: The stream and the dialects to offer.
+//RETURNS: The dialect the server picked and its security mode, or None if it refused them all.
+async fn smb2_negotiate(stream: &mut TcpStream, dialects: &[u16]) -> Option<(u16, u16)> {
+    let with_contexts = dialects.contains(&0x0311);
+    let mut request = smb2_header(0x0000, 0, 0);
+    request.extend_from_slice(&36u16.to_le_bytes()); //Structure size
+    request.extend_from_slice(&(dialects.len() as u16).to_le_bytes());
+    request.extend_from_slice(&0x0001u16.to_le_bytes()); //Security mode: signing enabled
+    request.extend_from_slice(&[0, 0]);
+    request.extend_from_slice(&0x0000_007Fu32.to_le_bytes()); //Capabilities
+    let mut client_guid = [0u8; 16];
+    fill_random(&mut client_guid);
+    request.extend_from_slice(&client_guid);
+    //Context offset/count for 3.1.1, client start time for everything else. Offset is filled in below.
+    let context_offset_at = request.len();
+    request.extend_from_slice(&[0; 8]);
+    for dialect in dialects {
+        request.extend_from_slice(&dialect.to_le_bytes());
+    }
+
+    if with_contexts {
+        pad_to_8(&mut request);
+        let context_offset = request.len() as u32;
+        request[context_offset_at..context_offset_at + 4].copy_from_slice(&context_offset.to_le_bytes());
+        request[context_offset_at + 4..context_offset_at + 6].copy_from_slice(&2u16.to_le_bytes());
+        //Preauth integrity: one hash (SHA-512) and a 32 byte salt.
+        let mut preauth = vec![0x01, 0x00, 0x20, 0x00, 0x01, 0x00];
+        let mut salt = [0u8; 32];
+        fill_random(&mut salt);
+        preauth.extend_from_slice(&salt);
+        push_negotiate_context(&mut request, 0x0001, &preauth);
+        pad_to_8(&mut request);
+        //Encryption: AES-128-GCM and AES-128-CCM.
+        push_negotiate_context(&mut request, 0x0002, &[0x02, 0x00, 0x02, 0x00, 0x01, 0x00]);
+    }
+
+    let reply = smb_exchange(stream, &request).await?;
+    let (status, body) = smb2_reply(&reply)?;
+    if status != STATUS_SUCCESS || body.len() < 6 {
+        return None;
+    }
+    let security_mode = u16::from_le_bytes([body[2], body[3]]);
+    let dialect = u16::from_le_bytes([body[4], body[5]]);
+    Some((dialect, security_mode))
+}
+
+//DESCRIPTION: Starts an NTLM login with a SESSION_SETUP carrying an NTLM NEGOTIATE wrapped in SPNEGO.
+//             The server answers with its CHALLENGE, which is full of names, and we hang up there.
+//TAKES: A stream that has already been through NEGOTIATE.
+//RETURNS: The names and build from the CHALLENGE, or None if the server didn't send one.
+async fn ntlm_challenge(stream: &mut TcpStream) -> Option<NtlmInfo> {
+    let security_blob = ntlm::spnego_ntlm_negotiate();
+
+    let mut request = smb2_header(0x0001, 1, 0);
+    request.extend_from_slice(&25u16.to_le_bytes()); //Structure size
+    request.push(0); //Flags
+    request.push(0x01); //Security mode: signing enabled
+    request.extend_from_slice(&[0; 8]); //Capabilities, channel
+    request.extend_from_slice(&((request.len() + 12) as u16).to_le_bytes()); //Security buffer offset
+    request.extend_from_slice(&(security_blob.len() as u16).to_le_bytes());
+    request.extend_from_slice(&[0; 8]); //Previous session id
+    request.extend_from_slice(&security_blob);
+
+    let reply = smb_exchange(stream, &request).await?;
+    let (status, _) = smb2_reply(&reply)?;
+    if status != STATUS_MORE_PROCESSING_REQUIRED {
+        return None;
+    }
+    ntlm::find_ntlm_challenge(&reply)
+}
+
+//DESCRIPTION: Builds a 64 byte SMB2 header.
+//TAKES: The command, message id and session id.
+//RETURNS: The header, ready for the body to be appended.
+fn smb2_header(command: u16, message_id: u64, session_id: u64) -> Vec<u8> {
+    let mut header = vec![0xFE, b'S', b'M', b'B'];
+    header.extend_from_slice(&64u16.to_le_bytes()); //Structure size
+    header.extend_from_slice(&[0; 2]); //Credit charge
+    header.extend_from_slice(&[0; 4]); //Status
+    header.extend_from_slice(&command.to_le_bytes());
+    header.extend_from_slice(&31u16.to_le_bytes()); //Credits requested
+    header.extend_from_slice(&[0; 8]); //Flags, next command
+    header.extend_from_slice(&message_id.to_le_bytes());
+    header.extend_from_slice(&[0; 8]); //Reserved, tree id
+    header.extend_from_slice(&session_id.to_le_bytes());
+    header.extend_from_slice(&[0; 16]); //Signature
+    header
+}
+
+//DESCRIPTION: Checks a reply is SMB2 and splits off the header.
+//TAKES: The reply without its session header.
+//RETURNS: The NT status and the body after the header, or None if it isn't SMB2.
+fn smb2_reply(reply: &[u8]) -> Option<(u32, &[u8])> {
+    if reply.len() < 64 || reply[0..4] != [0xFE, b'S', b'M', b'B'] {
+        return None;
+    }
+    let status = u32::from_le_bytes(reply[8..12].try_into().unwrap());
+    Some((status, &reply[64..]))
+}
+
+//DESCRIPTION: Appends an SMB 3.1.1 negotiate context.
+//TAKES: The message, the context type and its data.
+//RETURNS: Nothing.
+fn push_negotiate_context(message: &mut Vec<u8>, context_type: u16, data: &[u8]) {
+    message.extend_from_slice(&context_type.to_le_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    message.extend_from_slice(&[0; 4]);
+    message.extend_from_slice(data);
+}
+
+//DESCRIPTION: Pads with zeros to the next 8 byte boundary, which negotiate contexts have to sit on.
+//TAKES: The message.
+//RETURNS: Nothing.
+fn pad_to_8(message: &mut Vec<u8>) {
+    while !message.len().is_multiple_of(8) {
+        message.push(0);
+    }
+}
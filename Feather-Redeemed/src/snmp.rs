@@ -0,0 +1,275 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use serde::Serialize;
+
+use crate::banner::sanitize_banner;
+use crate::ber::{decode_integer, der, encode_integer, read_tlv};
+use crate::udpscan::udp_exchange;
+
+pub const SNMP_PORT: u32 = 161;
+const MAX_SNMP_PROBES: usize = 256;
+
+//Communities tried when no wordlist is given.
+pub const DEFAULT_SNMP_COMMUNITIES: &[&str] = &["public", "private"];
+
+//Versions we ask with. The number is what goes in the message.
+const SNMP_VERSIONS: &[(u8, &str)] = &[(0, "v1"), (1, "v2c")];
+
+//The system group OIDs we GET. Every agent has these.
+pub const SYS_DESCR: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 1, 0];
+const SYS_OBJECT_ID: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 2, 0];
+const SYS_CONTACT: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 4, 0];
+const SYS_NAME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+//A community and version the agent answered to.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnmpCommunity {
+    pub community: String,
+    pub version: &'static str,
+}
+
+//What an SNMP agent gave up.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnmpInfo {
+    pub host: IpAddr,
+    pub communities: Vec<SnmpCommunity>,
+    pub sys_descr: String,
+    pub sys_object_id: String,
+    pub sys_contact: String,
+    pub sys_name: String,
+}
+
+//DESCRIPTION: Loads the community wordlist from path, one per line, or the defaults if path is None.
+//TAKES: Optional path to the wordlist.
+//RETURNS: The communities in file order. Panics if the file can't be read like the exclusions file does.
+pub fn load_snmp_communities(path: Option<&str>) -> Vec<String> {
+    match path {
+        Some(path) => {
+            let contents = match fs::read_to_string(path) {
+                Err(e) => panic!("Couldn't Read {}: {}", path, e),
+                Ok(contents) => contents,
+            };
+            contents.lines().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| line.to_string()).collect()
+        },
+        None => DEFAULT_SNMP_COMMUNITIES.iter().map(|community| community.to_string()).collect(),
+    }
+}
+
+//DESCRIPTION: Tries every community with SNMPv1 and v2c against every host and writes
+//             snmp.txt for the hosts that answered.
+//TAKES: The hosts to try and the communities to try on each, the run folder to write to and the
+//       stop flag.
+//RETURNS: What every agent that answered said.
+pub async fn snmp_enrich(hosts: &[IpAddr], communities: Arc<Vec<String>>, out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<SnmpInfo> {
+    //One probe per host, community and version so a silent host doesn't hold things up for long.
+    let probes_per_host = communities.len() * SNMP_VERSIONS.len();
+    let mut probes = Vec::with_capacity(hosts.len() * probes_per_host);
+    for host in hosts {
+        for (index, community) in communities.iter().enumerate() {
+            for (version, version_name) in SNMP_VERSIONS {
+                let host = *host;
+                let community = community.clone();
+                let version = *version;
+                let version_name = *version_name;
+                //Different id per request so a late answer to one can't be mistaken for another.
+                let request_id = 0x564B_0000 | (index as u32) << 1 | version as u32;
+                probes.push(async move {
+                    snmp_get_system(host, version, &community, request_id).await.map(|values| (community, version_name, values))
+                });
+            }
+        }
+    }
+    let mut answers = crate::run_probes(probes, MAX_SNMP_PROBES, stop).await.into_iter();
+
+    let snmp_rfp = out_dir.join("snmp.txt");
+    let snmp_rf = match File::create(&snmp_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", snmp_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut snmp_buff = BufWriter::new(snmp_rf);
+
+    let mut results = Vec::new();
+    for host in hosts {
+        let mut info = SnmpInfo { host: *host, communities: Vec::new(), sys_descr: String::new(), sys_object_id: String::new(), sys_contact: String::new(), sys_name: String::new() };
+        for answer in answers.by_ref().take(probes_per_host) {
+            let Some((community, version, values)) = answer.flatten() else { continue };
+            //Every community gets the same system group back, keep the first set.
+            if info.communities.is_empty() {
+                [info.sys_descr, info.sys_object_id, info.sys_contact, info.sys_name] = values;
+            }
+            info.communities.push(SnmpCommunity { community, version });
+        }
+        if info.communities.is_empty() {
+            continue;
+        }
+        let communities: Vec<String> = info.communities.iter().map(|accepted| format!("{} ({})", accepted.community, accepted.version)).collect();
+        eprintln!("{}:{} {} {}", info.host, SNMP_PORT, communities.join(", "), info.sys_name);
+        let block = format!(
+            "{}:{} {}\n    Communities: {}\n    sysDescr: {}\n    sysObjectID: {}\n    sysContact: {}\n",
+            info.host, SNMP_PORT, info.sys_name, communities.join(", "), info.sys_descr, info.sys_object_id, info.sys_contact,
+        );
+        snmp_buff.write_all(block.as_bytes()).expect("Unable to write to snmp.txt");
+        results.push(info);
+    }
+    results
+}
+
+//DESCRIPTION: GETs sysDescr, sysObjectID, sysContact and sysName with one community and version.
+//TAKES: Host, SNMP version number, community and the request id to use.
+//RETURNS: The four values in that order, or None if the agent didn't answer (wrong community
+//         gets silence) or answered with an error.
+async fn snmp_get_system(host: IpAddr, version: u8, community: &str, request_id: u32) -> Option<[String; 4]> {
+    let request = get_request(version, community, request_id, &[SYS_DESCR, SYS_OBJECT_ID, SYS_CONTACT, SYS_NAME]);
+    let reply = udp_exchange(host, SNMP_PORT, &request).await.ok()??;
+    let values = parse_get_response(&reply, request_id)?;
+    let value = |index: usize| values.get(index).cloned().unwrap_or_default();
+    Some([value(0), value(1), value(2), value(3)])
+}
+
+//DESCRIPTION: Encodes an SNMP GetRequest.
+//TAKES: Version number (0 for v1, 1 for v2c), community, request id and the OIDs to GET.
+//RETURNS: The message.
+pub fn get_request(version: u8, community: &str, request_id: u32, oids: &[&[u32]]) -> Vec<u8> {
+    let varbinds: Vec<u8> = oids.iter().flat_map(|oid| der(0x30, &[der(0x06, &encode_oid(oid)), der(0x05, &[])].concat())).collect();
+    let pdu = der(0xA0, &[
+        der(0x02, &encode_integer(request_id)),
+        der(0x02, &[0]), //error-status
+        der(0x02, &[0]), //error-index
+        der(0x30, &varbinds),
+    ].concat());
+    der(0x30, &[der(0x02, &[version]), der(0x04, community.as_bytes()), pdu].concat())
+}
+
+//DESCRIPTION: Decodes an SNMP GetResponse to our request.
+//TAKES: The reply and the request id we sent.
+//RETURNS: The value of each varbind in order, or None if it isn't our response or has an error.
+fn parse_get_response(reply: &[u8], request_id: u32) -> Option<Vec<String>> {
+    let (_, message) = read_tlv(reply, &mut 0).filter(|(tag, _)| *tag == 0x30)?;
+    let mut at = 0;
+    read_tlv(message, &mut at)?; //version
+    read_tlv(message, &mut at)?; //community
+    let (_, pdu) = read_tlv(message, &mut at).filter(|(tag, _)| *tag == 0xA2)?;
+    let mut at = 0;
+    let (_, id) = read_tlv(pdu, &mut at)?;
+    let (_, error_status) = read_tlv(pdu, &mut at)?;
+    read_tlv(pdu, &mut at)?; //error-index
+    if decode_integer(id) != request_id as i64 || decode_integer(error_status) != 0 {
+        return None;
+    }
+    let (_, varbinds) = read_tlv(pdu, &mut at)?;
+    let mut values = Vec::new();
+    let mut at = 0;
+    while at < varbinds.len() {
+        let (_, varbind) = read_tlv(varbinds, &mut at)?;
+        let mut inner = 0;
+        read_tlv(varbind, &mut inner)?; //name
+        let (tag, value) = read_tlv(varbind, &mut inner)?;
+        values.push(match tag {
+            0x04 => sanitize_banner(value),
+            0x06 => decode_oid(value),
+            0x02 | 0x41 | 0x42 | 0x43 => decode_integer(value).to_string(),
+            _ => String::new(), //NULL, noSuchObject, noSuchInstance, endOfMibView
+        });
+    }
+    Some(values)
+}
+
+//DESCRIPTION: Encodes an OID. The first two arcs share a byte and every arc is base 128.
+//TAKES: The arcs.
+//RETURNS: The content bytes.
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![(oid[0] * 40 + oid[1]) as u8];
+    for arc in &oid[2..] {
+        let mut chunk = vec![(*arc & 0x7F) as u8];
+        let mut rest = *arc >> 7;
+        while rest > 0 {
+            chunk.insert(0, (rest & 0x7F) as u8 | 0x80);
+            rest >>= 7;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    bytes
+}
+
+//DESCRIPTION: Decodes OID content to dotted form.
+//TAKES: The content bytes.
+//RETURNS: "1.3.6.1..." style text.
+fn decode_oid(value: &[u8]) -> String {
+    let Some(first) = value.first() else { return String::new() };
+    let mut arcs = vec![(first / 40).min(2) as u64, (*first as u64).saturating_sub(40 * (first / 40).min(2) as u64)];
+    let mut arc = 0u64;
+    for byte in &value[1..] {
+        arc = arc << 7 | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    arcs.iter().map(|arc| arc.to_string()).collect::<Vec<String>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //net-snmp on Ubuntu answering a v2c GET for the system group with community public.
+    const SYSTEM_RESPONSE: &[u8] = &[
+    0x30, 0x81, 0xc0, 0x02, 0x01, 0x01, 0x04, 0x06, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0xa2, 0x81,
+    0xb2, 0x02, 0x04, 0x56, 0x4b, 0x00, 0x03, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x81, 0xa3,
+    0x30, 0x5a, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, 0x04, 0x4e, 0x4c, 0x69,
+    0x6e, 0x75, 0x78, 0x20, 0x67, 0x77, 0x20, 0x35, 0x2e, 0x31, 0x35, 0x2e, 0x30, 0x2d, 0x39, 0x31,
+    0x2d, 0x67, 0x65, 0x6e, 0x65, 0x72, 0x69, 0x63, 0x20, 0x23, 0x31, 0x30, 0x31, 0x2d, 0x55, 0x62,
+    0x75, 0x6e, 0x74, 0x75, 0x20, 0x53, 0x4d, 0x50, 0x20, 0x54, 0x75, 0x65, 0x20, 0x4e, 0x6f, 0x76,
+    0x20, 0x31, 0x34, 0x20, 0x31, 0x33, 0x3a, 0x33, 0x30, 0x3a, 0x30, 0x38, 0x20, 0x55, 0x54, 0x43,
+    0x20, 0x32, 0x30, 0x32, 0x33, 0x20, 0x78, 0x38, 0x36, 0x5f, 0x36, 0x34, 0x30, 0x16, 0x06, 0x08,
+    0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x02, 0x00, 0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xbf,
+    0x08, 0x03, 0x02, 0x0a, 0x30, 0x1d, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x04, 0x00,
+    0x04, 0x11, 0x61, 0x64, 0x6d, 0x69, 0x6e, 0x40, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+    0x63, 0x6f, 0x6d, 0x30, 0x0e, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x05, 0x00, 0x04,
+    0x02, 0x67, 0x77,
+    ];
+    const REQUEST_ID: u32 = 0x564B_0003;
+
+    #[test]
+    fn parses_a_system_group_response() {
+        let values = parse_get_response(SYSTEM_RESPONSE, REQUEST_ID).expect("Response didn't parse");
+        assert_eq!(values, vec![
+            "Linux gw 5.15.0-91-generic #101-Ubuntu SMP Tue Nov 14 13:30:08 UTC 2023 x86_64",
+            "1.3.6.1.4.1.8072.3.2.10",
+            "admin@example.com",
+            "gw",
+        ]);
+    }
+
+    #[test]
+    fn ignores_other_requests_and_errors() {
+        assert_eq!(parse_get_response(SYSTEM_RESPONSE, REQUEST_ID + 1), None, "Answer to a different request");
+        let mut error = SYSTEM_RESPONSE.to_vec();
+        error[25] = 2; //error-status noSuchName
+        assert_eq!(parse_get_response(&error, REQUEST_ID), None);
+        //Our own request is a GetRequest, not a GetResponse.
+        let request = get_request(1, "public", REQUEST_ID, &[SYS_DESCR, SYS_OBJECT_ID, SYS_CONTACT, SYS_NAME]);
+        assert_eq!(parse_get_response(&request, REQUEST_ID), None);
+    }
+
+    #[test]
+    fn truncated_responses_are_rejected() {
+        for len in [0, 1, 2, 20, 100, SYSTEM_RESPONSE.len() - 1] {
+            assert_eq!(parse_get_response(&SYSTEM_RESPONSE[..len], REQUEST_ID), None, "Cut off at {}", len);
+        }
+    }
+
+    #[test]
+    fn oids_round_trip() {
+        for oid in [SYS_DESCR, SYS_NAME, &[1, 3, 6, 1, 4, 1, 8072, 3, 2, 10], &[1, 3, 6, 1, 4, 1, 311, 1, 1, 3, 1, 2], &[2, 100, 3]] {
+            let dotted: Vec<String> = oid.iter().map(|part| part.to_string()).collect();
+            assert_eq!(decode_oid(&encode_oid(oid)), dotted.join("."));
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use ring::digest;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::http::base64;
+use crate::tls::fill_random;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+//Covers the whole exchange on one connection, DH with a big group can take the server a moment.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(8);
+//RFC 4253 says every implementation has to cope with 35000 byte packets, nothing we want is bigger.
+const MAX_SSH_PACKET: usize = 35000;
+const MAX_SSH_PROBES: usize = 64;
+const CLIENT_IDENT: &str = "SSH-2.0-OpenSSH_9.6";
+
+pub const SSH_PORTS: &[u32] = &[22, 2222];
+
+const SSH_MSG_DISCONNECT: u8 = 1;
+const SSH_MSG_KEXINIT: u8 = 20;
+const SSH_MSG_KEX_INIT: u8 = 30;
+const SSH_MSG_KEX_REPLY: u8 = 31;
+
+//Key exchanges we can start without doing any real maths, with the size of the public value we
+//send. Servers never check it is a real key before sending their host key back.
+const KEX_METHODS: &[(&str, usize)] = &[
+    ("curve25519-sha256", 32),
+    ("curve25519-sha256@libssh.org", 32),
+    ("diffie-hellman-group14-sha256", 256),
+    ("diffie-hellman-group16-sha512", 512),
+    ("diffie-hellman-group18-sha512", 1024),
+    ("diffie-hellman-group14-sha1", 256),
+    ("diffie-hellman-group1-sha1", 128),
+];
+
+//Algorithms we call out as weak when a server offers them.
+const WEAK_SSH_ALGORITHMS: &[&str] = &[
+    "diffie-hellman-group1-sha1", "diffie-hellman-group14-sha1", "diffie-hellman-group-exchange-sha1",
+    "ssh-dss", "ssh-rsa",
+    "3des-cbc", "aes128-cbc", "aes192-cbc", "aes256-cbc", "blowfish-cbc", "cast128-cbc", "rijndael-cbc@lysator.liu.se",
+    "arcfour", "arcfour128", "arcfour256", "none",
+    "hmac-md5", "hmac-md5-96", "hmac-sha1-96", "hmac-md5-etm@openssh.com", "hmac-md5-96-etm@openssh.com",
+    "hmac-sha1-96-etm@openssh.com", "umac-64@openssh.com", "umac-64-etm@openssh.com",
+];
+
+//One host key the server proved it has.
+#[derive(Clone, Debug, Serialize)]
+pub struct SshHostKey {
+    pub key_type: String,
+    pub fingerprint: String,
+}
+
+//What an SSH server offered before we ever logged in.
+#[derive(Clone, Debug, Serialize)]
+pub struct SshInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub banner: String,
+    pub kex: Vec<String>,
+    pub host_key_algorithms: Vec<String>,
+    pub ciphers: Vec<String>,
+    pub macs: Vec<String>,
+    pub compression: Vec<String>,
+    pub host_keys: Vec<SshHostKey>,
+}
+
+impl SshInfo {
+    //DESCRIPTION: Picks out every offered algorithm that is on the weak list.
+    //TAKES: Nothing.
+    //RETURNS: The weak algorithms, in the order they were offered.
+    pub fn weak_algorithms(&self) -> Vec<String> {
+        [&self.kex, &self.host_key_algorithms, &self.ciphers, &self.macs].into_iter()
+            .flatten()
+            .filter(|algorithm| WEAK_SSH_ALGORITHMS.contains(&algorithm.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+//The name lists out of a KEXINIT. We only keep the server to client side of each pair, servers
+//offer the same thing both ways.
+struct KexInit {
+    kex: Vec<String>,
+    host_key_algorithms: Vec<String>,
+    ciphers: Vec<String>,
+    macs: Vec<String>,
+    compression: Vec<String>,
+}
+
+//A TCP stream with whatever we have read past the last line or packet.
+struct SshStream {
+    stream: TcpStream,
+    buffered: Vec<u8>,
+}
+
+//DESCRIPTION: Does the version exchange and key exchange with every open SSH port and writes
+//             ssh.txt. Host keys seen on more than one IP go in ssh_shared_keys.txt.
+//TAKES: The open (ip, port) pairs to check, the run folder to write to and the stop flag.
+//RETURNS: What every SSH server offered.
+pub async fn ssh_inventory(open_ports: &[(IpAddr, u32)], out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<SshInfo> {
+    let mut probes = Vec::with_capacity(open_ports.len());
+    for (host, port) in open_ports {
+        let host = *host;
+        let port = *port;
+        probes.push(async move {
+            ssh_probe(host, port).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_SSH_PROBES, stop).await;
+
+    let ssh_rfp = out_dir.join("ssh.txt");
+    let ssh_rf = match File::create(&ssh_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", ssh_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut ssh_buff = BufWriter::new(ssh_rf);
+
+    let mut results = Vec::new();
+    let mut key_owners: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        let host_keys: Vec<String> = info.host_keys.iter().map(|key| format!("{} {}", key.key_type, key.fingerprint)).collect();
+        eprintln!("{}:{} {} {}", info.host, info.port, info.banner, host_keys.join(", "));
+        let block = format!(
+            "{}:{} {}\n    KEX: {}\n    Host Key Algorithms: {}\n    Ciphers: {}\n    MACs: {}\n    Compression: {}\n    Host Keys: {}\n    Weak: {}\n",
+            info.host, info.port, info.banner, info.kex.join(", "), info.host_key_algorithms.join(", "), info.ciphers.join(", "),
+            info.macs.join(", "), info.compression.join(", "), host_keys.join(", "), info.weak_algorithms().join(", "),
+        );
+        ssh_buff.write_all(block.as_bytes()).expect("Unable to write to ssh.txt");
+        for key in info.host_keys.iter() {
+            let owners = key_owners.entry(key.fingerprint.clone()).or_default();
+            if !owners.contains(&info.host) {
+                owners.push(info.host);
+            }
+        }
+        results.push(info);
+    }
+
+    //Same key on more than one IP is almost always a cloned VM or image that never regenerated its keys.
+    let shared_rfp = out_dir.join("ssh_shared_keys.txt");
+    let shared_rf = match File::create(&shared_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", shared_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut shared_buff = BufWriter::new(shared_rf);
+    let mut shared: Vec<(&String, &Vec<IpAddr>)> = key_owners.iter().filter(|(_, owners)| owners.len() > 1).collect();
+    shared.sort();
+    for (fingerprint, owners) in shared {
+        let owners: Vec<String> = owners.iter().map(|owner| owner.to_string()).collect();
+        eprintln!("Host key {} is shared by {} hosts", fingerprint, owners.len());
+        writeln!(shared_buff, "{} {}", fingerprint, owners.join(", ")).expect("Unable to write to ssh_shared_keys.txt");
+    }
+    results
+}
+
+//DESCRIPTION: Reads the banner and KEXINIT from one SSH port, then runs a key exchange for each kind
+//             of host key it offers to get the keys themselves. We hang up as soon as the host key
+//             comes back, long before anything is authenticated.
+//TAKES: Host and port.
+//RETURNS: What we found, or None if it doesn't speak SSH.
+pub async fn ssh_probe(host: IpAddr, port: u32) -> Option<SshInfo> {
+    let mut stream = SshStream::connect(host, port).await?;
+    let (banner, server_kexinit) = timeout(EXCHANGE_TIMEOUT, stream.version_exchange()).await.ok()??;
+    let kexinit = parse_kexinit(&server_kexinit)?;
+    let mut info = SshInfo {
+        host,
+        port,
+        banner,
+        kex: kexinit.kex.clone(),
+        host_key_algorithms: kexinit.host_key_algorithms.clone(),
+        ciphers: kexinit.ciphers.clone(),
+        macs: kexinit.macs.clone(),
+        compression: kexinit.compression.clone(),
+        host_keys: Vec::new(),
+    };
+    let Some((kex, public_len)) = KEX_METHODS.iter().find(|(name, _)| kexinit.kex.iter().any(|offered| offered == name)) else {
+        return Some(info);
+    };
+
+    //rsa-sha2-512, rsa-sha2-256 and ssh-rsa all sign with the same key, only ask for it once.
+    let mut wanted: Vec<&String> = Vec::new();
+    let mut seen_rsa = false;
+    for algorithm in kexinit.host_key_algorithms.iter().filter(|algorithm| !algorithm.contains("-cert-")) {
+        let is_rsa = algorithm == "ssh-rsa" || algorithm.starts_with("rsa-sha2-");
+        if is_rsa && seen_rsa {
+            continue;
+        }
+        seen_rsa |= is_rsa;
+        wanted.push(algorithm);
+    }
+
+    let mut first_stream = Some(stream);
+    for algorithm in wanted {
+        //The connection we already have is halfway there, use it for the first key.
+        let key_blob = match first_stream.take() {
+            Some(mut stream) => timeout(EXCHANGE_TIMEOUT, stream.fetch_host_key(&kexinit, kex, *public_len, algorithm)).await.ok().flatten(),
+            None => {
+                let Some(mut stream) = SshStream::connect(host, port).await else { continue };
+                timeout(EXCHANGE_TIMEOUT, async {
+                    stream.version_exchange().await?;
+                    stream.fetch_host_key(&kexinit, kex, *public_len, algorithm).await
+                }).await.ok().flatten()
+            },
+        };
+        let Some(key_blob) = key_blob else { continue };
+        let key_type = read_string(&key_blob, &mut 0).map(|name| String::from_utf8_lossy(name).to_string()).unwrap_or_default();
+        info.host_keys.push(SshHostKey { key_type, fingerprint: ssh_fingerprint(&key_blob) });
+    }
+    Some(info)
+}
+
+impl SshStream {
+    //DESCRIPTION: Opens a connection to an SSH port.
+    //TAKES: Host and port.
+    //RETURNS: The stream, or None if it didn't connect in time.
+    async fn connect(host: IpAddr, port: u32) -> Option<SshStream> {
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(SocketAddr::new(host, port as u16))).await {
+            Ok(Ok(stream)) => Some(SshStream { stream, buffered: Vec::new() }),
+            _ => None,
+        }
+    }
+
+    //DESCRIPTION: Swaps identification strings and reads the server's KEXINIT, which it sends
+    //             without waiting for ours.
+    //TAKES: Nothing.
+    //RETURNS: The server's identification string and its KEXINIT payload.
+    async fn version_exchange(&mut self) -> Option<(String, Vec<u8>)> {
+        self.stream.write_all(format!("{}\r\n", CLIENT_IDENT).as_bytes()).await.ok()?;
+        //Servers are allowed to send other lines before the identification string.
+        let banner = loop {
+            let line = self.read_line().await?;
+            if line.starts_with("SSH-") {
+                break line;
+            }
+        };
+        loop {
+            let payload = self.read_packet().await?;
+            match payload.first() {
+                Some(&SSH_MSG_KEXINIT) => return Some((banner, payload)),
+                Some(&SSH_MSG_DISCONNECT) | None => return None,
+                _ => continue,
+            }
+        }
+    }
+
+    //DESCRIPTION: Sends a KEXINIT that only allows the one kex and host key algorithm we want,
+    //             echoing the server's own ciphers and MACs so it can't refuse, then the client half
+    //             of the key exchange with a random public value.
+    //TAKES: The server's KEXINIT, the kex method and its public value size, and the host key algorithm.
+    //RETURNS: The host key blob from the server's reply.
+    async fn fetch_host_key(&mut self, server: &KexInit, kex: &str, public_len: usize, host_key_algorithm: &str) -> Option<Vec<u8>> {
+        let mut kexinit = vec![SSH_MSG_KEXINIT];
+        let mut cookie = [0u8; 16];
+        fill_random(&mut cookie);
+        kexinit.extend_from_slice(&cookie);
+        push_name_list(&mut kexinit, &[kex]);
+        push_name_list(&mut kexinit, &[host_key_algorithm]);
+        for list in [&server.ciphers, &server.ciphers, &server.macs, &server.macs, &server.compression, &server.compression] {
+            let names: Vec<&str> = list.iter().map(|name| name.as_str()).collect();
+            push_name_list(&mut kexinit, &names);
+        }
+        push_name_list(&mut kexinit, &[]);
+        push_name_list(&mut kexinit, &[]);
+        kexinit.push(0); //First kex packet follows
+        kexinit.extend_from_slice(&[0; 4]);
+        self.write_packet(&kexinit).await?;
+
+        let mut public = vec![0u8; public_len];
+        fill_random(&mut public);
+        if !kex.starts_with("curve25519") {
+            //DH e has to be under the group prime, which starts 0xFFFF. Clearing the top bit also
+            //means the mpint doesn't need a leading zero.
+            public[0] = 0x7F;
+        }
+        let mut kex_init = vec![SSH_MSG_KEX_INIT];
+        push_string(&mut kex_init, &public);
+        self.write_packet(&kex_init).await?;
+
+        loop {
+            let payload = self.read_packet().await?;
+            match payload.first() {
+                Some(&SSH_MSG_KEX_REPLY) => return read_string(&payload, &mut 1).map(|blob| blob.to_vec()),
+                Some(&SSH_MSG_DISCONNECT) | None => return None,
+                _ => continue,
+            }
+        }
+    }
+
+    //DESCRIPTION: Reads one line of the version exchange.
+    //TAKES: Nothing.
+    //RETURNS: The line without its line ending, or None if it runs past 255 bytes or the server hangs up.
+    async fn read_line(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.buffered.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffered.drain(..=end).collect();
+                return Some(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            if self.buffered.len() > 255 {
+                return None;
+            }
+            self.fill().await?;
+        }
+    }
+
+    //DESCRIPTION: Reads one unencrypted binary packet.
+    //TAKES: Nothing.
+    //RETURNS: The payload without length and padding, or None if the packet is bad or the server hangs up.
+    async fn read_packet(&mut self) -> Option<Vec<u8>> {
+        while self.buffered.len() < 5 {
+            self.fill().await?;
+        }
+        let length = u32::from_be_bytes(self.buffered[0..4].try_into().unwrap()) as usize;
+        let padding = self.buffered[4] as usize;
+        if length > MAX_SSH_PACKET || padding + 1 > length {
+            return None;
+        }
+        while self.buffered.len() < length + 4 {
+            self.fill().await?;
+        }
+        let packet: Vec<u8> = self.buffered.drain(..length + 4).collect();
+        Some(packet[5..length + 4 - padding].to_vec())
+    }
+
+    //DESCRIPTION: Wraps a payload in an unencrypted binary packet and sends it.
+    //TAKES: The payload.
+    //RETURNS: Some(()) once it is written, None if the write failed.
+    async fn write_packet(&mut self, payload: &[u8]) -> Option<()> {
+        //Length, padding length, payload and padding together have to be a multiple of 8, with at least 4 bytes of padding.
+        let mut padding = 8 - (payload.len() + 5) % 8;
+        if padding < 4 {
+            padding += 8;
+        }
+        let mut packet = Vec::with_capacity(payload.len() + padding + 5);
+        packet.extend_from_slice(&((payload.len() + padding + 1) as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        packet.resize(packet.len() + padding, 0);
+        self.stream.write_all(&packet).await.ok()
+    }
+
+    //DESCRIPTION: Reads whatever the server has sent next onto the buffer.
+    //TAKES: Nothing.
+    //RETURNS: Some(()) if anything came in, None on a hang up or error.
+    async fn fill(&mut self) -> Option<()> {
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => None,
+            Ok(read) => {
+                self.buffered.extend_from_slice(&chunk[..read]);
+                Some(())
+            },
+        }
+    }
+}
+
+//DESCRIPTION: Pulls the name lists out of a KEXINIT payload.
+//TAKES: The payload, starting at its message number.
+//RETURNS: The lists, or None if it is cut short.
+fn parse_kexinit(payload: &[u8]) -> Option<KexInit> {
+    //Message number and 16 byte cookie come before the lists.
+    let mut at = 17;
+    let mut lists = Vec::with_capacity(10);
+    for _ in 0..10 {
+        let list = String::from_utf8_lossy(read_string(payload, &mut at)?).to_string();
+        lists.push(list.split(',').filter(|name| !name.is_empty()).map(|name| name.to_string()).collect::<Vec<String>>());
+    }
+    Some(KexInit {
+        kex: lists[0].clone(),
+        host_key_algorithms: lists[1].clone(),
+        ciphers: lists[3].clone(),
+        macs: lists[5].clone(),
+        compression: lists[7].clone(),
+    })
+}
+
+//DESCRIPTION: Reads an SSH string (u32 length then that many bytes) and moves past it.
+//TAKES: The buffer and the offset to read from, which gets moved along.
+//RETURNS: The string's bytes, or None if it runs off the end.
+fn read_string<'a>(buf: &'a [u8], at: &mut usize) -> Option<&'a [u8]> {
+    let length = u32::from_be_bytes(buf.get(*at..*at + 4)?.try_into().unwrap()) as usize;
+    let value = buf.get(*at + 4..*at + 4 + length)?;
+    *at += 4 + length;
+    Some(value)
+}
+
+//DESCRIPTION: Appends an SSH string.
+//TAKES: The message and the bytes.
+//RETURNS: Nothing.
+fn push_string(message: &mut Vec<u8>, value: &[u8]) {
+    message.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    message.extend_from_slice(value);
+}
+
+//DESCRIPTION: Appends an SSH name-list.
+//TAKES: The message and the names.
+//RETURNS: Nothing.
+fn push_name_list(message: &mut Vec<u8>, names: &[&str]) {
+    push_string(message, names.join(",").as_bytes());
+}
+
+//DESCRIPTION: Fingerprints a host key the way ssh-keygen -l does.
+//TAKES: The host key blob.
+//RETURNS: "SHA256:..." with the base64 padding dropped.
+fn ssh_fingerprint(key_blob: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, key_blob);
+    format!("SHA256:{}", base64(hash.as_ref()).trim_end_matches('='))
+}
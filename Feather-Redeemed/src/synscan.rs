@@ -0,0 +1,203 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::{ipv4_checksum, MutableTcpPacket, TcpFlags, TcpOption, TcpPacket};
+use pnet::packet::Packet;
+use pnet::transport::{ipv4_packet_iter, transport_channel, TransportChannelType::{Layer3, Layer4}, TransportProtocol::Ipv4};
+
+use crate::osfingerprint::{self, OsObservations};
+use crate::{Db, PortResult, PortState};
+
+//How long to keep listening for stragglers once every SYN is out the door.
+const SYN_WAIT: Duration = Duration::from_millis(1500);
+//Send this many SYNs before taking a short breather so we don't flood the socket buffer.
+const SYN_BURST: usize = 256;
+
+type SentTimes = Arc<Mutex<HashMap<(Ipv4Addr, u16), Instant>>>;
+type Answers = Arc<Mutex<HashMap<(Ipv4Addr, u16), PortResult>>>;
+
+//DESCRIPTION: Half-open scan. Fires a SYN at every host/port and lets a listener thread match up
+//             the SYN/ACKs and RSTs as they come back. The kernel tears down the SYN/ACKs for us
+//             with a RST since it never opened a socket for them. Every SYN/ACK also gets kept
+//             for OS fingerprinting.
+//TAKES: Hosts to scan, the ports to hit on each one, where to keep the SYN/ACK observations, the
+//       host list to add hosts that answered to and the stop flag. Once a stop comes in no more SYNs go out.
+//RETURNS: A PortResult for every host/port pair a SYN went to, or the error from opening the raw
+//         socket (usually PermissionDenied when we don't have CAP_NET_RAW).
+pub fn syn_scan(hosts: &[IpAddr], ports: &[u32], observations: OsObservations, host_list: Db, stop: Arc<AtomicBool>) -> io::Result<Vec<PortResult>> {
+    let (mut tx, _) = transport_channel(1 << 16, Layer4(Ipv4(IpNextHeaderProtocols::Tcp)))?;
+    //A layer 4 channel cuts the IP header off what it receives, the listener needs it for the
+    //source address and TTL so it gets a layer 3 one.
+    let (_, mut rx) = transport_channel(1 << 16, Layer3(IpNextHeaderProtocols::Tcp))?;
+    let src_port = syn_source_port();
+    let sent_times: SentTimes = Arc::new(Mutex::new(HashMap::new()));
+    let answers: Answers = Arc::new(Mutex::new(HashMap::new()));
+    let done = Arc::new(AtomicBool::new(false));
+
+    //Route lookups are per /24 since that is how the sweep hands us hosts. Done before the listener
+    //starts so there's nothing to clean up, and a /24 with no route is skipped rather than the whole scan.
+    let mut source_ips: HashMap<[u8; 3], Option<Ipv4Addr>> = HashMap::new();
+    let mut targets: Vec<(Ipv4Addr, Ipv4Addr)> = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let IpAddr::V4(dst) = host else { continue };
+        let octets = dst.octets();
+        let src = source_ips.entry([octets[0], octets[1], octets[2]]).or_insert_with(|| match source_ip_for(*dst) {
+            Ok(src) => Some(src),
+            Err(e) => {
+                eprintln!("No route to {}.{}.{}.0/24 ({}), skipping it in the SYN scan.", octets[0], octets[1], octets[2], e);
+                None
+            }
+        });
+        if let Some(src) = src {
+            targets.push((*dst, *src));
+        }
+    }
+
+    //Listener runs the whole time we are sending so early answers are not dropped.
+    let listener = {
+        let sent_times = sent_times.clone();
+        let answers = answers.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut iter = ipv4_packet_iter(&mut rx);
+            while !done.load(Ordering::Relaxed) {
+                match iter.next_with_timeout(Duration::from_millis(100)) {
+                    Ok(Some((ip_packet, _))) => match_syn_reply(&ip_packet, src_port, &sent_times, &answers, &observations, &host_list),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("SYN listener errored: {}", e);
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    let mut sent = 0;
+    for (dst, src) in targets {
+        if stop.load(Ordering::Relaxed) {
+            eprintln!("Stopping the SYN scan, {} SYNs were sent.", sent);
+            break;
+        }
+        for port in ports {
+            let port = *port as u16;
+            let mut buf = [0u8; 40];
+            build_syn(&mut buf, src, dst, src_port, port);
+            sent_times.lock().unwrap().insert((dst, port), Instant::now());
+            if let Err(e) = tx.send_to(TcpPacket::new(&buf).unwrap(), IpAddr::V4(dst)) {
+                eprintln!("Failed to send SYN to {}:{}: {}", dst, port, e);
+            }
+            sent += 1;
+            if sent % SYN_BURST == 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    thread::sleep(SYN_WAIT);
+    done.store(true, Ordering::Relaxed);
+    listener.join().expect("SYN listener thread panicked");
+
+    //Anything that never answered is filtered.
+    let answers = answers.lock().unwrap();
+    let sent_times = sent_times.lock().unwrap();
+    let mut results = Vec::with_capacity(sent_times.len());
+    for (dst, port) in sent_times.keys() {
+        match answers.get(&(*dst, *port)) {
+            Some(result) => results.push(*result),
+            None => results.push(PortResult { host: IpAddr::V4(*dst), port: *port as u32, proto: "tcp", state: PortState::Filtered, latency: SYN_WAIT }),
+        }
+    }
+    //Same order as the connect scan gives, for the port files.
+    results.sort_by_key(|result| (result.host, result.port));
+    Ok(results)
+}
+
+//DESCRIPTION: Checks a packet off the raw socket against the SYNs we sent and records the answer.
+//TAKES: The IPv4 packet, our source port, when each SYN went out, the answers map to fill, the
+//       OS observations to add SYN/ACKs to and the host list.
+//RETURNS: Nothing. Packets that are not for us are ignored.
+fn match_syn_reply(ip_packet: &Ipv4Packet, src_port: u16, sent_times: &SentTimes, answers: &Answers, observations: &OsObservations, host_list: &Db) {
+    let Some(tcp) = TcpPacket::new(ip_packet.payload()) else { return };
+    if tcp.get_destination() != src_port {
+        return;
+    }
+    let dst = ip_packet.get_source();
+    let port = tcp.get_source();
+    //Make sure this is an answer to our SYN and not some other traffic to the same port.
+    if tcp.get_acknowledgement() != syn_cookie(dst, port).wrapping_add(1) {
+        return;
+    }
+    let flags = tcp.get_flags();
+    let state = if flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK {
+        osfingerprint::record_observation(observations, IpAddr::V4(dst), osfingerprint::tcp_observation(ip_packet, &tcp));
+        PortState::Open
+    } else if flags & TcpFlags::RST != 0 {
+        PortState::Closed
+    } else {
+        return;
+    };
+    let latency = match sent_times.lock().unwrap().get(&(dst, port)) {
+        Some(sent_at) => sent_at.elapsed(),
+        None => return,
+    };
+    //Only the first answer counts, a retransmitted SYN/ACK isn't reported twice.
+    if let Entry::Vacant(entry) = answers.lock().unwrap().entry((dst, port)) {
+        let result = entry.insert(PortResult { host: IpAddr::V4(dst), port: port as u32, proto: "tcp", state, latency });
+        crate::port_answered(result, host_list);
+    }
+}
+
+//DESCRIPTION: Fills buf with a SYN carrying the same options as a Linux connect so it looks like one.
+//             Servers only send back the options we offer, so offering them all is also what
+//             lets the SYN/ACK's option layout tell one OS from another.
+//TAKES: A 40 byte buffer, the source and destination addresses, and the two ports.
+//RETURNS: Nothing, the packet is written into buf.
+fn build_syn(buf: &mut [u8; 40], src: Ipv4Addr, dst: Ipv4Addr, src_port: u16, dst_port: u16) {
+    let mut syn = MutableTcpPacket::new(buf).unwrap();
+    syn.set_source(src_port);
+    syn.set_destination(dst_port);
+    syn.set_sequence(syn_cookie(dst, dst_port));
+    syn.set_acknowledgement(0);
+    syn.set_data_offset(10);
+    syn.set_flags(TcpFlags::SYN);
+    syn.set_window(1024);
+    syn.set_options(&[TcpOption::mss(1460), TcpOption::sack_perm(), TcpOption::timestamp(syn_cookie(dst, dst_port), 0), TcpOption::nop(), TcpOption::wscale(7)]);
+    let checksum = ipv4_checksum(&syn.to_immutable(), &src, &dst);
+    syn.set_checksum(checksum);
+}
+
+//DESCRIPTION: Sequence number for a SYN so replies can be checked without keeping state per packet.
+//TAKES: Destination address and port.
+//RETURNS: The sequence number to use.
+fn syn_cookie(dst: Ipv4Addr, port: u16) -> u32 {
+    (u32::from(dst) ^ ((port as u32) << 16 | port as u32)).wrapping_mul(0x9E37_79B1) ^ 0x5641_4C4B
+}
+
+//DESCRIPTION: Picks the source port for this run out of the ephemeral range.
+//TAKES: Nothing.
+//RETURNS: A port number between 40000 and 59999.
+fn syn_source_port() -> u16 {
+    40000 + (std::process::id() % 20000) as u16
+}
+
+//DESCRIPTION: Asks the kernel which local address it would use to reach dst. Connecting a UDP
+//             socket does the route lookup without sending anything.
+//TAKES: The destination address.
+//RETURNS: The local IPv4 address the kernel picked.
+fn source_ip_for(dst: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((dst, 9))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(src) => Ok(src),
+        IpAddr::V6(_) => Err(io::Error::other("No IPv4 route to target")),
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use x509_parser::extensions::GeneralName;
+use x509_parser::public_key::PublicKey;
+
+use crate::format_timestamp;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+//Certificate chains are rarely past 16k, stop reading well before anything silly.
+const MAX_HANDSHAKE_LEN: usize = 65536;
+const MAX_TLS_PROBES: usize = 128;
+
+//Ports we try a TLS handshake on when they are open.
+pub const TLS_PORTS: &[u32] = &[261, 443, 448, 465, 563, 585, 614, 636, 989, 990, 992, 993, 994, 995, 2083, 2087, 2096, 2376, 3269, 4443, 5061, 5986, 6443, 7443, 8443, 8834, 9443, 10443];
+
+//Everything we pulled out of one TLS handshake.
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsInfo {
+    pub host: IpAddr,
+    pub port: u32,
+    pub protocol: String,
+    pub cipher: String,
+    pub subject: String,
+    pub subject_cn: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    pub key_type: String,
+}
+
+impl TlsInfo {
+    //DESCRIPTION: Picks the name to give a host that has no PTR record. First DNS SAN that is not a
+    //             wildcard, then the subject CN if it looks like a hostname.
+    //TAKES: Nothing.
+    //RETURNS: The hostname, or None if the certificate doesn't name anything useful.
+    pub fn best_hostname(&self) -> Option<String> {
+        let looks_like_host = |name: &str| !name.is_empty() && !name.starts_with('*') && !name.contains(' ') && name.parse::<IpAddr>().is_err();
+        self.sans.iter()
+            .find(|san| looks_like_host(san))
+            .cloned()
+            .or_else(|| Some(self.subject_cn.clone()).filter(|cn| looks_like_host(cn)))
+    }
+}
+
+//What came back from a ClientHello.
+struct ServerHelloInfo {
+    version: u16,
+    cipher: u16,
+    certificates: Vec<Vec<u8>>,
+}
+
+//DESCRIPTION: Handshakes with every open TLS port and writes tls.txt.
+//TAKES: The open (ip, port) pairs to check and the ip -> hostname list so we can send SNI,
+//       the run folder to write to and the stop flag.
+//RETURNS: The certificate details for every port that finished a handshake.
+pub async fn harvest_certificates(open_ports: &[(IpAddr, u32)], hostnames: &std::collections::HashMap<String, String>, out_dir: &Path, stop: &Arc<AtomicBool>) -> Vec<TlsInfo> {
+    let mut probes = Vec::with_capacity(open_ports.len());
+    for (host, port) in open_ports {
+        let host = *host;
+        let port = *port;
+        let sni = hostnames.get(&host.to_string()).filter(|name| name.as_str() != "no_hostname").cloned();
+        probes.push(async move {
+            tls_probe(host, port, sni.as_deref()).await
+        });
+    }
+    let answers = crate::run_probes(probes, MAX_TLS_PROBES, stop).await;
+
+    let tls_rfp = out_dir.join("tls.txt");
+    let tls_rf = match File::create(&tls_rfp) {
+        Err(e) => panic!("Couldn't Create {}: {}", tls_rfp.display(), e),
+        Ok(file) => file,
+    };
+    let mut tls_buff = BufWriter::new(tls_rf);
+
+    let mut harvested = Vec::new();
+    for answer in answers {
+        let Some(info) = answer.flatten() else { continue };
+        eprintln!("{}:{} {} {} {}", info.host, info.port, info.protocol, info.cipher, info.subject);
+        let block = format!(
+            "{}:{} {} {}\n    Subject: {}\n    Issuer: {}\n    SANs: {}\n    Valid: {} to {}\n    Key: {}\n",
+            info.host, info.port, info.protocol, info.cipher, info.subject, info.issuer, info.sans.join(", "), info.not_before, info.not_after, info.key_type,
+        );
+        tls_buff.write_all(block.as_bytes()).expect("Unable to write certificate to tls.txt");
+        harvested.push(info);
+    }
+    harvested
+}
+
+//DESCRIPTION: Works out the protocol, cipher and certificate for one port. Offers TLS 1.3 first to
+//             see what the server really picks, then if it did pick 1.3 (where the certificate is
+//             encrypted) asks again capped at 1.2 to get the certificate in the clear.
+//TAKES: Host, port and an optional name for SNI.
+//RETURNS: What we found, or None if it doesn't speak TLS.
+pub async fn tls_probe(host: IpAddr, port: u32, sni: Option<&str>) -> Option<TlsInfo> {
+    let first = tls_handshake(host, port, &client_hello(sni, true)).await;
+    let (version, cipher, certificates) = match first {
+        Some(hello) if hello.version == 0x0304 => {
+            let certificates = tls_handshake(host, port, &client_hello(sni, false)).await.map(|hello| hello.certificates).unwrap_or_default();
+            (hello.version, hello.cipher, certificates)
+        },
+        Some(hello) => (hello.version, hello.cipher, hello.certificates),
+        //Some old stacks hang up on a hello with TLS 1.3 bits in it. Try again without.
+        None => {
+            let hello = tls_handshake(host, port, &client_hello(sni, false)).await?;
+            (hello.version, hello.cipher, hello.certificates)
+        },
+    };
+
+    let mut info = TlsInfo {
+        host,
+        port,
+        protocol: tls_version_name(version),
+        cipher: cipher_suite_name(cipher),
+        subject: String::new(),
+        subject_cn: String::new(),
+        issuer: String::new(),
+        sans: Vec::new(),
+        not_before: String::new(),
+        not_after: String::new(),
+        key_type: String::new(),
+    };
+    //The first certificate is the server's own, the rest are the chain.
+    if let Some(der) = certificates.first() {
+        fill_certificate(&mut info, der);
+    }
+    Some(info)
+}
+
+//DESCRIPTION: Decodes a DER certificate into the subject, issuer, SAN, validity and key fields of info.
+//TAKES: The TlsInfo to fill and the server's certificate.
+//RETURNS: Nothing. Fields are left empty if the certificate doesn't parse.
+pub fn fill_certificate(info: &mut TlsInfo, der: &[u8]) {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(der) else { return };
+    info.subject = cert.subject().to_string();
+    info.subject_cn = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).unwrap_or("").to_string();
+    info.issuer = cert.issuer().to_string();
+    info.not_before = format_timestamp(cert.validity().not_before.timestamp());
+    info.not_after = format_timestamp(cert.validity().not_after.timestamp());
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            match name {
+                GeneralName::DNSName(dns) => info.sans.push(dns.to_string()),
+                GeneralName::IPAddress(ip) if ip.len() == 4 => info.sans.push(IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap()).to_string()),
+                GeneralName::IPAddress(ip) if ip.len() == 16 => info.sans.push(IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap()).to_string()),
+                _ => {},
+            }
+        }
+    }
+    info.key_type = match cert.public_key().parsed() {
+        Ok(PublicKey::RSA(rsa)) => format!("RSA {}", rsa.key_size()),
+        Ok(PublicKey::EC(point)) => format!("EC {}", point.key_size()),
+        Ok(PublicKey::DSA(_)) => "DSA".to_string(),
+        _ => match cert.public_key().algorithm.algorithm.to_id_string().as_str() {
+            "1.3.101.112" => "Ed25519".to_string(),
+            "1.3.101.113" => "Ed448".to_string(),
+            other => other.to_string(),
+        },
+    };
+}
+
+//DESCRIPTION: Sends a ClientHello and reads handshake records until we have the ServerHello and,
+//             for TLS 1.2 and older, the Certificate message.
+//TAKES: Host, port and the ClientHello record to send.
+//RETURNS: The ServerHello details, or None on an alert, a hang up, or something that isn't TLS.
+async fn tls_handshake(host: IpAddr, port: u32, hello: &[u8]) -> Option<ServerHelloInfo> {
+    let socket = SocketAddr::new(host, port as u16);
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(socket)).await.ok()?.ok()?;
+    stream.write_all(hello).await.ok()?;
+
+    let mut records: Vec<u8> = Vec::new();
+    let mut handshake: Vec<u8> = Vec::new();
+    let mut server_hello: Option<ServerHelloInfo> = None;
+    let mut buf = [0u8; 4096];
+    while records.len() < MAX_HANDSHAKE_LEN {
+        let len = timeout(READ_TIMEOUT, stream.read(&mut buf)).await.ok()?.ok()?;
+        if len == 0 {
+            break;
+        }
+        records.extend_from_slice(&buf[..len]);
+
+        //Peel off every full record we have. Handshake messages can span records so glue them together.
+        while records.len() >= 5 && records.len() >= 5 + u16::from_be_bytes([records[3], records[4]]) as usize {
+            let record_len = u16::from_be_bytes([records[3], records[4]]) as usize;
+            let content_type = records[0];
+            let body: Vec<u8> = records.drain(..5 + record_len).skip(5).collect();
+            match content_type {
+                0x16 => handshake.extend_from_slice(&body),
+                0x15 => return server_hello.filter(|hello| !hello.certificates.is_empty() || hello.version == 0x0304), //Alert
+                0x14 | 0x17 => return server_hello, //TLS 1.3 goes encrypted straight after the ServerHello.
+                _ => return None,
+            }
+
+            //Walk the handshake messages we have so far.
+            while handshake.len() >= 4 {
+                let msg_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+                if handshake.len() < 4 + msg_len {
+                    break;
+                }
+                let msg_type = handshake[0];
+                let msg: Vec<u8> = handshake.drain(..4 + msg_len).skip(4).collect();
+                match msg_type {
+                    2 => {
+                        let hello = parse_server_hello(&msg)?;
+                        if hello.version == 0x0304 {
+                            return Some(hello);
+                        }
+                        server_hello = Some(hello);
+                    },
+                    11 => {
+                        let mut hello = server_hello?;
+                        hello.certificates = parse_certificate_list(&msg);
+                        return Some(hello);
+                    },
+                    14 => return server_hello, //ServerHelloDone with no certificate (anonymous suites).
+                    _ => {},
+                }
+            }
+        }
+    }
+    server_hello
+}
+
+//DESCRIPTION: Pulls the version and cipher out of a ServerHello body. If there is a
+//             supported_versions extension that is the real version (TLS 1.3 says 1.2 up front).
+//TAKES: The ServerHello body without the handshake header.
+//RETURNS: The parsed hello with no certificates yet, or None if it is too short.
+fn parse_server_hello(msg: &[u8]) -> Option<ServerHelloInfo> {
+    let mut version = u16::from_be_bytes([*msg.first()?, *msg.get(1)?]);
+    let session_len = *msg.get(34)? as usize;
+    let mut at = 35 + session_len;
+    let cipher = u16::from_be_bytes([*msg.get(at)?, *msg.get(at + 1)?]);
+    at += 3; //cipher + compression method
+    if msg.len() >= at + 2 {
+        let ext_end = (at + 2 + u16::from_be_bytes([msg[at], msg[at + 1]]) as usize).min(msg.len());
+        at += 2;
+        while at + 4 <= ext_end {
+            let ext_type = u16::from_be_bytes([msg[at], msg[at + 1]]);
+            let ext_len = u16::from_be_bytes([msg[at + 2], msg[at + 3]]) as usize;
+            if ext_type == 0x002b && ext_len == 2 && at + 6 <= ext_end {
+                version = u16::from_be_bytes([msg[at + 4], msg[at + 5]]);
+            }
+            at += 4 + ext_len;
+        }
+    }
+    Some(ServerHelloInfo { version, cipher, certificates: Vec::new() })
+}
+
+//DESCRIPTION: Splits a TLS 1.2 Certificate message into the DER certificates in it.
+//TAKES: The Certificate body without the handshake header.
+//RETURNS: The certificates in the order the server sent them.
+fn parse_certificate_list(msg: &[u8]) -> Vec<Vec<u8>> {
+    let mut certificates = Vec::new();
+    if msg.len() < 3 {
+        return certificates;
+    }
+    let list_end = (3 + u32::from_be_bytes([0, msg[0], msg[1], msg[2]]) as usize).min(msg.len());
+    let mut at = 3;
+    while at + 3 <= list_end {
+        let cert_len = u32::from_be_bytes([0, msg[at], msg[at + 1], msg[at + 2]]) as usize;
+        at += 3;
+        if at + cert_len > list_end {
+            break;
+        }
+        certificates.push(msg[at..at + cert_len].to_vec());
+        at += cert_len;
+    }
+    certificates
+}
+
+//Cipher suites offered, strongest first. The old junk is at the end so ancient devices still answer.
+const TLS13_CIPHERS: &[u16] = &[0x1301, 0x1302, 0x1303];
+const TLS12_CIPHERS: &[u16] = &[
+    0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc009, 0xc013, 0xc00a, 0xc014,
+    0x009e, 0x009f, 0x0033, 0x0039, 0x009c, 0x009d, 0x002f, 0x0035, 0x003c, 0x003d,
+    0x000a, 0x0005, 0x0004,
+];
+
+//DESCRIPTION: Builds a ClientHello record. With tls13 it also offers TLS 1.3 through
+//             supported_versions and a throwaway x25519 key share.
+//TAKES: Optional SNI name and whether to offer TLS 1.3.
+//RETURNS: The full record ready to write to the socket.
+fn client_hello(sni: Option<&str>, tls13: bool) -> Vec<u8> {
+    let mut random = [0u8; 32];
+    fill_random(&mut random);
+
+    let mut body: Vec<u8> = Vec::new();
+    body.extend_from_slice(&[0x03, 0x03]); //TLS 1.2 in the legacy version field
+    body.extend_from_slice(&random);
+    body.push(32); //Session id, TLS 1.3 middleboxes like seeing one
+    let mut session_id = [0u8; 32];
+    fill_random(&mut session_id);
+    body.extend_from_slice(&session_id);
+
+    let mut ciphers: Vec<u16> = Vec::new();
+    if tls13 {
+        ciphers.extend_from_slice(TLS13_CIPHERS);
+    }
+    ciphers.extend_from_slice(TLS12_CIPHERS);
+    body.extend_from_slice(&((ciphers.len() * 2) as u16).to_be_bytes());
+    for cipher in ciphers {
+        body.extend_from_slice(&cipher.to_be_bytes());
+    }
+    body.extend_from_slice(&[0x01, 0x00]); //No compression
+
+    let mut extensions: Vec<u8> = Vec::new();
+    if let Some(name) = sni {
+        let name = name.as_bytes();
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        ext.push(0x00); //host_name
+        ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        ext.extend_from_slice(name);
+        push_extension(&mut extensions, 0x0000, &ext);
+    }
+    push_extension(&mut extensions, 0x000a, &[0x00, 0x06, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18]); //x25519, P-256, P-384
+    push_extension(&mut extensions, 0x000b, &[0x01, 0x00]); //uncompressed points
+    push_extension(&mut extensions, 0x000d, &[
+        0x00, 0x16, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x04, 0x08, 0x05,
+        0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x01, 0x02, 0x03,
+    ]);
+    push_extension(&mut extensions, 0xff01, &[0x00]); //renegotiation_info
+    if tls13 {
+        push_extension(&mut extensions, 0x002b, &[0x08, 0x03, 0x04, 0x03, 0x03, 0x03, 0x02, 0x03, 0x01]);
+        let mut key_share = vec![0x00, 0x24, 0x00, 0x1d, 0x00, 0x20];
+        let mut key = [0u8; 32];
+        fill_random(&mut key);
+        key_share.extend_from_slice(&key);
+        push_extension(&mut extensions, 0x0033, &key_share);
+    }
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+//DESCRIPTION: Appends a type/length/value extension.
+//TAKES: Extension buffer, extension type and its data.
+//RETURNS: Nothing.
+fn push_extension(extensions: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    extensions.extend_from_slice(&ext_type.to_be_bytes());
+    extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(data);
+}
+
+//DESCRIPTION: Fills buf from the system's random number generator. Nothing we send with these is
+//             ever used to protect anything, the handshakes are dropped once we have what we came for.
+//TAKES: Buffer to fill.
+//RETURNS: Nothing.
+pub fn fill_random(buf: &mut [u8]) {
+    SystemRandom::new().fill(buf).expect("The system random number generator failed");
+}
+
+//DESCRIPTION: Wire version to name.
+//TAKES: The version number from the ServerHello.
+//RETURNS: "TLSv1.2" style name.
+pub fn tls_version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_string(),
+        0x0301 => "TLSv1.0".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0304 => "TLSv1.3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+//DESCRIPTION: Cipher suite number to IANA name for everything we offer.
+//TAKES: The cipher suite the server picked.
+//RETURNS: The name, or the hex number if it is one we don't know.
+pub fn cipher_suite_name(cipher: u16) -> String {
+    let name = match cipher {
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xc009 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc00a => "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0x009e => "TLS_DHE_RSA_WITH_AES_128_GCM_SHA256",
+        0x009f => "TLS_DHE_RSA_WITH_AES_256_GCM_SHA384",
+        0x0033 => "TLS_DHE_RSA_WITH_AES_128_CBC_SHA",
+        0x0039 => "TLS_DHE_RSA_WITH_AES_256_CBC_SHA",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        0x003c => "TLS_RSA_WITH_AES_128_CBC_SHA256",
+        0x003d => "TLS_RSA_WITH_AES_256_CBC_SHA256",
+        0x000a => "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+        0x0005 => "TLS_RSA_WITH_RC4_128_SHA",
+        0x0004 => "TLS_RSA_WITH_RC4_128_MD5",
+        other => return format!("0x{:04x}", other),
+    };
+    name.to_string()
+}
+
+//Certificate "verifier" for talking to internal hosts. Self signed and expired certificates are
+//what we expect to find, so everything is accepted. Signatures are still checked so the
+//handshake itself is sane.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+//DESCRIPTION: Builds a TLS client that will talk to anything, for probes that need a real session
+//             (HTTPS and friends) rather than just the handshake. TLS 1.2 and 1.3 only.
+//TAKES: Nothing.
+//RETURNS: The connector.
+pub fn insecure_tls_connector() -> TlsConnector {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default TLS versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+//DESCRIPTION: Runs a TLS session over an already connected stream.
+//TAKES: The connector, the stream, the host and an optional name for SNI.
+//RETURNS: The TLS stream, or None if the handshake failed or timed out.
+pub async fn tls_wrap(connector: &TlsConnector, stream: TcpStream, host: IpAddr, sni: Option<&str>) -> Option<TlsStream<TcpStream>> {
+    let server_name = match sni.and_then(|name| ServerName::try_from(name.to_string()).ok()) {
+        Some(name) => name,
+        None => ServerName::IpAddress(host.into()),
+    };
+    timeout(CONNECT_TIMEOUT, connector.connect(server_name, stream)).await.ok()?.ok()
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::snmp;
+use crate::{Db, PortResult, PortState};
+
+//How long to wait for an answer to each UDP probe.
+const UDP_TIMEOUT: Duration = Duration::from_millis(1500);
+//UDP gets dropped a lot so every probe is sent this many times before giving up.
+const UDP_TRIES: usize = 2;
+//Each probe holds a socket open so cap how many run at once.
+const MAX_UDP_PROBES: usize = 512;
+
+//A UDP port we know how to talk to and the payload it gets.
+pub struct UdpService {
+    pub port: u32,
+    pub name: &'static str,
+    pub probe: fn() -> Vec<u8>,
+}
+
+//An empty datagram gets ignored by pretty much every one of these services.
+pub const UDP_SERVICES: &[UdpService] = &[
+    UdpService { port: 53, name: "dns", probe: dns_probe },
+    UdpService { port: 69, name: "tftp", probe: tftp_probe },
+    UdpService { port: 123, name: "ntp", probe: ntp_probe },
+    UdpService { port: 137, name: "netbios-ns", probe: netbios_ns_probe },
+    UdpService { port: 161, name: "snmp", probe: snmp_probe },
+    UdpService { port: 500, name: "ike", probe: ike_probe },
+    UdpService { port: 1900, name: "ssdp", probe: ssdp_probe },
+    UdpService { port: 5353, name: "mdns", probe: mdns_probe },
+];
+
+//DESCRIPTION: Sends every UDP service probe to every host and sorts out the answers.
+//TAKES: Hosts to scan, the host list to add hosts that answered to and the stop flag.
+//RETURNS: A PortResult for each host and UDP service port. Probes cut short by a stop are left out.
+pub async fn udp_scan(hosts: &[IpAddr], host_list: &Db, stop: &Arc<AtomicBool>) -> Vec<PortResult> {
+    let mut probes = Vec::with_capacity(hosts.len() * UDP_SERVICES.len());
+    for host in hosts {
+        for service in UDP_SERVICES {
+            let host = *host;
+            let port = service.port;
+            let payload = (service.probe)();
+            let host_list = host_list.clone();
+            probes.push(async move {
+                let result = addr_udpscan(host, port, &payload).await;
+                crate::port_answered(&result, &host_list);
+                result
+            });
+        }
+    }
+    crate::run_probes(probes, MAX_UDP_PROBES, stop).await.into_iter().flatten().collect()
+}
+
+//DESCRIPTION: Probes a single UDP port. A connected UDP socket hands ICMP port unreachable back
+//             to us as ConnectionRefused so no raw socket is needed to spot closed ports.
+//TAKES: Host, port and the payload to send.
+//RETURNS: Open if anything answered, Closed on port unreachable, OpenFiltered on silence.
+pub async fn addr_udpscan(host: IpAddr, port: u32, payload: &[u8]) -> PortResult {
+    let probe_time = Instant::now();
+    let state = match udp_exchange(host, port, payload).await {
+        Ok(Some(_)) => PortState::Open,
+        Ok(None) => PortState::OpenFiltered,
+        Err(e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused => PortState::Closed,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => PortState::Unreachable,
+            _ => PortState::Error,
+        },
+    };
+    PortResult { host, port, proto: "udp", state, latency: probe_time.elapsed() }
+}
+
+//DESCRIPTION: Sends payload to host:port and waits for a datagram back, resending on silence.
+//TAKES: Host, port and payload.
+//RETURNS: The reply if one came back, None if we heard nothing, or the socket error (ICMP errors land here).
+pub async fn udp_exchange(host: IpAddr, port: u32, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let bind_addr: SocketAddr = match host {
+        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(SocketAddr::new(host, port as u16)).await?;
+    let mut buf = vec![0u8; 4096];
+    for _ in 0..UDP_TRIES {
+        socket.send(payload).await?;
+        match timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                buf.truncate(len);
+                return Ok(Some(buf));
+            },
+            Ok(Err(e)) => return Err(e),
+            Err(_) => continue, //Timed out, try again.
+        }
+    }
+    Ok(None)
+}
+
+//DESCRIPTION: Appends a DNS name in wire format (length prefixed labels ending in a zero).
+//TAKES: Buffer to write into and a dotted name.
+//RETURNS: Nothing.
+fn push_dns_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+//DNS: version.bind TXT CH. Anything that speaks DNS answers, even if it is just REFUSED.
+fn dns_probe() -> Vec<u8> {
+    let mut probe = vec![0x56, 0x4b, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    push_dns_name(&mut probe, "version.bind");
+    probe.extend_from_slice(&[0x00, 0x10, 0x00, 0x03]); //TXT, CHAOS
+    probe
+}
+
+//mDNS: legacy unicast query for the DNS-SD service list. Responders answer us directly
+//since the source port is not 5353.
+fn mdns_probe() -> Vec<u8> {
+    let mut probe = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    push_dns_name(&mut probe, "_services._dns-sd._udp.local");
+    probe.extend_from_slice(&[0x00, 0x0c, 0x00, 0x01]); //PTR, IN
+    probe
+}
+
+//TFTP: read request for a file that should not exist. Servers come back with an ERROR packet.
+fn tftp_probe() -> Vec<u8> {
+    let mut probe = vec![0x00, 0x01];
+    probe.extend_from_slice(b"valk2probe\0octet\0");
+    probe
+}
+
+//NTP: v4 client mode request, everything else zeroed.
+fn ntp_probe() -> Vec<u8> {
+    let mut probe = vec![0u8; 48];
+    probe[0] = 0xe3; //LI 3, VN 4, Mode 3
+    probe
+}
+
+//NetBIOS-NS: node status (NBSTAT) request for the wildcard name "*".
+fn netbios_ns_probe() -> Vec<u8> {
+    let mut probe = vec![0x56, 0x4b, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    probe.push(0x20);
+    //First level encoding of "*" padded out to 16 bytes with nulls.
+    probe.extend_from_slice(b"CK");
+    probe.extend_from_slice(&[b'A'; 30]);
+    probe.extend_from_slice(&[0x00, 0x00, 0x21, 0x00, 0x01]); //NBSTAT, IN
+    probe
+}
+
+//SNMP: v2c GetRequest for sysDescr.0 with the community "public".
+fn snmp_probe() -> Vec<u8> {
+    snmp::get_request(1, "public", 0x564B_3200, &[snmp::SYS_DESCR])
+}
+
+//SSDP: M-SEARCH for everything sent straight at the host.
+fn ssdp_probe() -> Vec<u8> {
+    b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n".to_vec()
+}
+
+//IKE: IKEv1 main mode with a single 3DES/SHA1/PSK/MODP1024 proposal. Anything running IKE
+//answers with either its own SA or a NOTIFY.
+fn ike_probe() -> Vec<u8> {
+    let mut probe = Vec::with_capacity(80);
+    //ISAKMP header
+    probe.extend_from_slice(b"VALK2IKE"); //initiator cookie
+    probe.extend_from_slice(&[0u8; 8]); //responder cookie
+    probe.extend_from_slice(&[0x01, 0x10, 0x02, 0x00]); //next payload SA, v1.0, main mode, no flags
+    probe.extend_from_slice(&[0u8; 4]); //message id
+    probe.extend_from_slice(&80u32.to_be_bytes()); //total length
+    //SA payload
+    probe.extend_from_slice(&[0x00, 0x00, 0x00, 52]);
+    probe.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); //DOI IPsec
+    probe.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); //situation identity only
+    //Proposal payload
+    probe.extend_from_slice(&[0x00, 0x00, 0x00, 40, 0x01, 0x01, 0x00, 0x01]);
+    //Transform payload
+    probe.extend_from_slice(&[0x00, 0x00, 0x00, 32, 0x01, 0x01, 0x00, 0x00]);
+    probe.extend_from_slice(&[
+        0x80, 0x01, 0x00, 0x05, //encryption 3DES
+        0x80, 0x02, 0x00, 0x02, //hash SHA1
+        0x80, 0x03, 0x00, 0x01, //auth pre-shared key
+        0x80, 0x04, 0x00, 0x02, //group MODP1024
+        0x80, 0x0b, 0x00, 0x01, //life type seconds
+        0x80, 0x0c, 0x70, 0x80, //life duration 28800
+    ]);
+    probe
+}